@@ -0,0 +1,272 @@
+pub mod oidc;
+pub mod policy;
+pub mod signed_url;
+
+use std::{future::{ready, Future, Ready}, pin::Pin, rc::Rc};
+
+use actix_web::{body::EitherBody, dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform}, http::header, web::Data, Error, FromRequest, HttpMessage, HttpRequest};
+use derive_more::Display;
+use getset::Getters;
+use log::{debug, warn};
+use paperclip::actix::{Apiv2Schema, Apiv2Security};
+use rand::{distributions::Alphanumeric, Rng, thread_rng};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{errors::Errors, settings::{AuthSettings, Settings}, store::Store};
+use oidc::JwksCache;
+use policy::Grant;
+
+const KEY_PREFIX: &str = "kms_";
+const KEY_LENGTH: usize = 40;
+
+pub fn generate_key() -> String {
+    let random: String = thread_rng().sample_iter(&Alphanumeric).take(KEY_LENGTH).map(char::from).collect();
+    format!("{}{}", KEY_PREFIX, random)
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+/// What a principal may do, each role including the ones before it. Viewers list and read,
+/// operators register and change devices, admins also delete them and manage clusters, pools,
+/// bootstrap tokens and API keys.
+#[derive(Clone, Copy, Debug, Default, Display, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    #[display(fmt = "viewer")]
+    Viewer,
+    #[display(fmt = "operator")]
+    Operator,
+    #[display(fmt = "admin")]
+    Admin
+}
+
+/// Who a request was made by.
+#[derive(Clone, Debug, Getters)]
+#[get = "pub with_prefix"]
+pub struct Principal {
+    name: String,
+    grants: Vec<Grant>
+}
+
+impl Principal {
+    // Stands in for every caller when authentication is disabled
+    fn anonymous() -> Principal {
+        Principal { name: "anonymous".to_owned(), grants: vec![Grant::unrestricted(Role::Admin)] }
+    }
+
+    /// The operator of the command line, who has access to the configuration and store anyway.
+    pub fn local() -> Principal {
+        Principal { name: "local".to_owned(), grants: vec![Grant::unrestricted(Role::Admin)] }
+    }
+
+    fn api_key(name: &str, grant: Grant) -> Principal {
+        Principal { name: name.to_owned(), grants: vec![grant] }
+    }
+}
+
+/// Extracts the principal the middleware authenticated. Taking it as a handler argument also
+/// documents the security scheme of the route in the OpenAPI spec.
+#[derive(Apiv2Security)]
+#[openapi(apiKey, in = "header", name = "Authorization", description = "API key or OIDC token, sent as `Bearer <token>`. API keys may also be sent as `ApiKey <key>`")]
+pub struct Authenticated(pub Principal);
+
+impl FromRequest for Authenticated {
+    type Error = Errors;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(request.extensions().get::<Principal>().cloned().map(Authenticated).ok_or(Errors::UnauthorizedError))
+    }
+}
+
+fn is_anonymous(settings: &AuthSettings, path: &str) -> bool {
+    settings.get_anonymous_paths().iter().any(|prefix| {
+        let prefix = prefix.trim_end_matches('/');
+        path == prefix || path.starts_with(&format!("{}/", prefix))
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum Credentials {
+    // An OIDC token or an API key
+    Bearer(String),
+    ApiKey(String)
+}
+
+/// The credentials of an `Authorization: Bearer <token>` or `Authorization: ApiKey <key>` header,
+/// the schemes the challenges of a 401 offer. Any other scheme is rejected rather than ignored.
+fn credentials(request: &ServiceRequest) -> Result<Option<Credentials>, Errors> {
+    let value = match request.headers().get(header::AUTHORIZATION) {
+        Some(value) => value.to_str().map_err(|_| Errors::UnauthorizedError)?,
+        None => return Ok(None)
+    };
+    match value.split_once(' ').map(|(scheme, token)| (scheme, token.trim())) {
+        Some((_, "")) => Err(Errors::UnauthorizedError),
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => Ok(Some(Credentials::Bearer(token.to_owned()))),
+        Some((scheme, key)) if scheme.eq_ignore_ascii_case("apikey") => Ok(Some(Credentials::ApiKey(key.to_owned()))),
+        _ => Err(Errors::UnauthorizedError)
+    }
+}
+
+/// Looks the key up in the settings first and the store second.
+fn find_key(settings: &Settings, store: Option<&Data<Store>>, key: &str) -> Option<Principal> {
+    let hash = hash_key(key);
+    let configured = settings.get_auth().get_api_keys().iter()
+        .find(|configured| constant_time_eq(configured.get_sha256().to_lowercase().as_bytes(), hash.as_bytes()))
+        .map(|configured| Principal::api_key(configured.get_name(), Grant::new(*configured.get_role(), configured.get_clusters(), configured.get_sites())));
+    configured.or_else(|| store?.read(|tables| tables.api_keys.values()
+        .find(|stored| constant_time_eq(stored.get_sha256().as_bytes(), hash.as_bytes()))
+        .map(|stored| Principal::api_key(stored.get_name(), Grant::new(*stored.get_role(), stored.get_clusters(), stored.get_sites())))))
+}
+
+/// JWTs consist of three dot separated parts, API keys never contain a dot.
+fn is_jwt(token: &str) -> bool {
+    token.matches('.').count() == 2
+}
+
+async fn verify_token(settings: &Settings, request: &ServiceRequest, token: &str) -> Result<Principal, Errors> {
+    let oidc = settings.get_auth().get_oidc().as_ref().ok_or(Errors::UnauthorizedError)?;
+    let cache = request.app_data::<Data<JwksCache>>().ok_or(Errors::InternalServerError)?;
+    let identity = match oidc::verify(oidc, cache, token).await {
+        Ok(identity) => identity,
+        Err(error) => {
+            warn!("Rejected token for {} {}: {}", request.method(), request.path(), error);
+            return Err(Errors::UnauthorizedError)
+        }
+    };
+    let grants = identity.grants(oidc);
+    if grants.is_empty() {
+        warn!("User {} has none of the groups mapped to a role", identity.username);
+        return Err(Errors::ForbiddenError { reason: format!("none of the groups of {} is mapped to a role", identity.username) })
+    }
+    Ok(Principal { name: identity.username, grants })
+}
+
+async fn authenticate(request: &ServiceRequest) -> Result<(), Errors> {
+    let settings = request.app_data::<Data<Settings>>().ok_or(Errors::InternalServerError)?.clone();
+    if !settings.get_auth().get_enabled() {
+        request.extensions_mut().insert(Principal::anonymous());
+        return Ok(())
+    }
+
+    let credentials = match credentials(request)? {
+        Some(credentials) => credentials,
+        None if is_anonymous(settings.get_auth(), request.path()) => return Ok(()),
+        None => return Err(Errors::UnauthorizedError)
+    };
+    let principal = match credentials {
+        Credentials::Bearer(token) if is_jwt(&token) => verify_token(&settings, request, &token).await?,
+        Credentials::Bearer(key) | Credentials::ApiKey(key) => match find_key(&settings, request.app_data::<Data<Store>>(), &key) {
+            Some(principal) => principal,
+            None => {
+                warn!("Rejected unknown API key for {} {}", request.method(), request.path());
+                return Err(Errors::UnauthorizedError)
+            }
+        }
+    };
+    debug!("Authenticated {} for {} {}", principal.get_name(), request.method(), request.path());
+    request.extensions_mut().insert(principal);
+    Ok(())
+}
+
+/// Rejects requests without a valid API key or OIDC token, except on the anonymous paths of the auth settings.
+pub struct Authentication;
+
+impl<S, B> Transform<S, ServiceRequest> for Authentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<S>
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            // Rejections become responses here instead of errors, so the error handlers wrapped
+            // around the app turn them into problems like every other error
+            if let Err(error) = authenticate(&request).await {
+                return Ok(request.error_response(error).map_into_right_body())
+            }
+            service.call(request).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn credentials_of(authorization: &str) -> Result<Option<Credentials>, Errors> {
+        credentials(&TestRequest::default().insert_header((header::AUTHORIZATION, authorization)).to_srv_request())
+    }
+
+    #[test]
+    fn reads_bearer_and_api_key_credentials() {
+        assert_eq!(credentials(&TestRequest::default().to_srv_request()).unwrap(), None);
+        assert_eq!(credentials_of("Bearer kms_key").unwrap(), Some(Credentials::Bearer("kms_key".to_owned())));
+        assert_eq!(credentials_of("bearer  kms_key ").unwrap(), Some(Credentials::Bearer("kms_key".to_owned())));
+        assert_eq!(credentials_of("ApiKey kms_key").unwrap(), Some(Credentials::ApiKey("kms_key".to_owned())));
+        assert!(matches!(credentials_of("Basic a21zOmtleQ=="), Err(Errors::UnauthorizedError)));
+        assert!(matches!(credentials_of("Bearer "), Err(Errors::UnauthorizedError)));
+        assert!(matches!(credentials_of("kms_key"), Err(Errors::UnauthorizedError)));
+    }
+
+    #[test]
+    fn hashes_keys_as_hex_sha256() {
+        assert_eq!(hash_key("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_ne!(hash_key("kms_a"), hash_key("kms_b"));
+    }
+
+    #[test]
+    fn generates_prefixed_keys() {
+        let key = generate_key();
+        assert!(key.starts_with(KEY_PREFIX));
+        assert_eq!(key.len(), KEY_PREFIX.len() + KEY_LENGTH);
+        assert!(key[KEY_PREFIX.len()..].chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(key, generate_key());
+    }
+
+    #[test]
+    fn compares_in_constant_time() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(hash_key("key").as_bytes(), hash_key("key").as_bytes()));
+        assert!(!constant_time_eq(hash_key("key").as_bytes(), hash_key("other").as_bytes()));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+        assert!(!constant_time_eq(b"abc", b""));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,130 @@
+use std::fmt;
+
+use getset::Getters;
+
+use crate::{errors::Errors, v1::devices::models::device::Device};
+use super::{Principal, Role};
+
+/// What an operation touches. Sites are the IPAM networks devices are placed in.
+#[derive(Clone, Copy, Default)]
+pub struct Scope<'a> {
+    cluster: Option<&'a str>,
+    site: Option<&'a str>
+}
+
+impl<'a> Scope<'a> {
+    /// Operations that aren't tied to a cluster or site, only unrestricted grants cover them.
+    pub fn global() -> Scope<'a> {
+        Scope::default()
+    }
+
+    pub fn cluster(name: &'a str) -> Scope<'a> {
+        Scope { cluster: Some(name), site: None }
+    }
+
+    pub fn site(name: &'a str) -> Scope<'a> {
+        Scope { cluster: None, site: Some(name) }
+    }
+
+    pub fn new(cluster: Option<&'a str>, site: Option<&'a str>) -> Scope<'a> {
+        Scope { cluster, site }
+    }
+
+    pub fn device(device: &'a Device) -> Scope<'a> {
+        Scope::new(device.get_cluster().as_deref(), device.get_network().as_deref())
+    }
+}
+
+impl fmt::Display for Scope<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.cluster, self.site) {
+            (Some(cluster), Some(site)) => write!(f, "cluster {} at site {}", cluster, site),
+            (Some(cluster), None) => write!(f, "cluster {}", cluster),
+            (None, Some(site)) => write!(f, "site {}", site),
+            (None, None) => write!(f, "all clusters and sites")
+        }
+    }
+}
+
+/// A role, limited to some clusters and sites. Empty lists don't restrict the grant.
+#[derive(Clone, Debug, Getters)]
+#[get = "pub with_prefix"]
+pub struct Grant {
+    role: Role,
+    clusters: Vec<String>,
+    sites: Vec<String>
+}
+
+impl Grant {
+    pub fn new(role: Role, clusters: &[String], sites: &[String]) -> Grant {
+        Grant { role, clusters: clusters.to_vec(), sites: sites.to_vec() }
+    }
+
+    pub fn unrestricted(role: Role) -> Grant {
+        Grant { role, clusters: Vec::new(), sites: Vec::new() }
+    }
+
+    fn covers(&self, scope: &Scope) -> bool {
+        let within = |allowed: &[String], name: Option<&str>| allowed.is_empty() || name.is_some_and(|name| allowed.iter().any(|allowed| allowed == name));
+        within(&self.clusters, scope.cluster) && within(&self.sites, scope.site)
+    }
+}
+
+impl Principal {
+    pub fn can(&self, role: Role, scope: Scope) -> bool {
+        self.grants.iter().any(|grant| *grant.get_role() >= role && grant.covers(&scope))
+    }
+
+    /// Checks that the principal holds at least `role` for `scope`. `action` completes the reason
+    /// given to the caller, e.g. "delete devices".
+    pub fn authorize(&self, role: Role, action: &str, scope: Scope) -> Result<(), Errors> {
+        if self.can(role, scope) {
+            Ok(())
+        } else {
+            Err(Errors::ForbiddenError { reason: format!("{} needs the {} role for {} to {}", self.name, role, scope, action) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn unrestricted_grants_cover_everything() {
+        let grant = Grant::unrestricted(Role::Viewer);
+        assert!(grant.covers(&Scope::global()));
+        assert!(grant.covers(&Scope::cluster("prod")));
+        assert!(grant.covers(&Scope::new(Some("prod"), Some("fra1"))));
+    }
+
+    #[test]
+    fn cluster_grants_cover_only_their_clusters() {
+        let grant = Grant::new(Role::Admin, &names(&["prod", "staging"]), &[]);
+        assert!(grant.covers(&Scope::cluster("prod")));
+        assert!(grant.covers(&Scope::new(Some("staging"), Some("fra1"))));
+        assert!(!grant.covers(&Scope::cluster("lab")));
+        assert!(!grant.covers(&Scope::site("fra1")));
+        assert!(!grant.covers(&Scope::global()));
+    }
+
+    #[test]
+    fn restricted_grants_need_every_restriction_met() {
+        let grant = Grant::new(Role::Operator, &names(&["prod"]), &names(&["fra1"]));
+        assert!(grant.covers(&Scope::new(Some("prod"), Some("fra1"))));
+        assert!(!grant.covers(&Scope::new(Some("prod"), Some("ams1"))));
+        assert!(!grant.covers(&Scope::new(Some("lab"), Some("fra1"))));
+        assert!(!grant.covers(&Scope::cluster("prod")));
+    }
+
+    #[test]
+    fn names_match_exactly() {
+        let grant = Grant::new(Role::Viewer, &names(&["prod"]), &[]);
+        assert!(!grant.covers(&Scope::cluster("production")));
+        assert!(!grant.covers(&Scope::cluster("Prod")));
+    }
+}
\ No newline at end of file
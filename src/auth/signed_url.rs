@@ -0,0 +1,123 @@
+use eui48::MacAddress;
+use hmac::{Hmac, Mac};
+use log::{debug, error};
+use sha2::Sha256;
+
+use crate::{settings::{ProvisioningSettings, SigningKeySettings}, v1::tokens::models::token::now};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The signature covers the device and the expiry, so one signed query string is good for every
+/// provisioning URL of that device until it expires. `None` when the key's file can't be read.
+fn hmac(key: &SigningKeySettings, mac: &MacAddress, expires: u64) -> Option<HmacSha256> {
+    let secret = match key.get_secret().expose() {
+        Ok(secret) => secret,
+        Err(error) => {
+            error!("Unable to read signing key {}: {}", key.get_id(), error);
+            return None
+        }
+    };
+    let mut hmac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    hmac.update(format!("{}:{}", mac.to_hex_string(), expires).as_bytes());
+    Some(hmac)
+}
+
+/// Checks the signature against every key of the ring, so URLs signed before a rotation keep
+/// working until they expire or their key is removed.
+pub fn verify(settings: &ProvisioningSettings, mac: &MacAddress, expires: u64, signature: &str) -> bool {
+    if expires < now() {
+        return false
+    }
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false
+    };
+    match settings.get_signing_keys().iter().find(|key| hmac(key, mac, expires).is_some_and(|hmac| hmac.verify_slice(&signature).is_ok())) {
+        Some(key) => {
+            debug!("Signed URL of {} verified with key {}", mac, key.get_id());
+            true
+        }
+        None => false
+    }
+}
+
+/// The `exp` and `sig` query parameters that authorize fetching provisioning data of `mac` for
+/// the configured time, together with the expiry. `None` when there are no signing keys.
+pub fn query(settings: &ProvisioningSettings, mac: &MacAddress) -> Option<(String, u64)> {
+    let key = settings.get_signing_keys().first()?;
+    let expires = now() + settings.get_signed_url_seconds();
+    let signature = hex::encode(hmac(key, mac, expires)?.finalize().into_bytes());
+    Some((format!("exp={}&sig={}", expires, signature), expires))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(keys: &[(&str, &str)]) -> ProvisioningSettings {
+        let keys: Vec<_> = keys.iter().map(|(id, secret)| json!({ "id": id, "secret": secret })).collect();
+        serde_json::from_value(json!({ "signing_keys": keys })).unwrap()
+    }
+
+    fn mac() -> MacAddress {
+        MacAddress::parse_str("00:11:22:33:44:55").unwrap()
+    }
+
+    fn signature(settings: &ProvisioningSettings, key: usize, mac: &MacAddress, expires: u64) -> String {
+        hex::encode(hmac(&settings.get_signing_keys()[key], mac, expires).unwrap().finalize().into_bytes())
+    }
+
+    #[test]
+    fn verifies_the_query_it_issued() {
+        let settings = settings(&[("current", "secret")]);
+        let (query, expires) = query(&settings, &mac()).unwrap();
+        let signature = query.split("sig=").nth(1).unwrap();
+
+        assert!(query.starts_with(&format!("exp={}&", expires)));
+        assert!(verify(&settings, &mac(), expires, signature));
+    }
+
+    #[test]
+    fn rejects_other_devices_and_expiries() {
+        let settings = settings(&[("current", "secret")]);
+        let expires = now() + 60;
+        let signature = signature(&settings, 0, &mac(), expires);
+
+        assert!(!verify(&settings, &MacAddress::parse_str("00:11:22:33:44:56").unwrap(), expires, &signature));
+        assert!(!verify(&settings, &mac(), expires + 1, &signature));
+    }
+
+    #[test]
+    fn rejects_expired_signatures() {
+        let settings = settings(&[("current", "secret")]);
+        let expires = now() - 1;
+        assert!(!verify(&settings, &mac(), expires, &signature(&settings, 0, &mac(), expires)));
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        let settings = settings(&[("current", "secret")]);
+        let expires = now() + 60;
+        assert!(!verify(&settings, &mac(), expires, "not hex"));
+        assert!(!verify(&settings, &mac(), expires, ""));
+        assert!(!verify(&settings, &mac(), expires, &signature(&settings, 0, &mac(), expires)[..62]));
+    }
+
+    #[test]
+    fn accepts_every_key_of_the_ring() {
+        let previous = settings(&[("previous", "old")]);
+        let rotated = settings(&[("current", "new"), ("previous", "old")]);
+        let expires = now() + 60;
+
+        assert!(verify(&rotated, &mac(), expires, &signature(&previous, 0, &mac(), expires)));
+        assert!(!verify(&settings(&[("current", "new")]), &mac(), expires, &signature(&previous, 0, &mac(), expires)));
+    }
+
+    #[test]
+    fn signs_nothing_without_keys() {
+        assert!(query(&settings(&[]), &mac()).is_none());
+        assert!(!verify(&settings(&[]), &mac(), now() + 60, "00"));
+    }
+}
\ No newline at end of file
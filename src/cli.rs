@@ -0,0 +1,305 @@
+use std::{fs, io, net::{IpAddr, Ipv4Addr, Ipv6Addr}, path::PathBuf, process, sync::Arc};
+
+use clap::{Args, Parser, Subcommand};
+use log::error;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{auth::Principal, errors::Errors, reload::LiveSettings, server, settings::{self, LogFormat, Settings}, store::{Store, StoreError}, telemetry, tls, v1::{clusters::models::cluster::NodeRole, devices::{models::{device::Ipv6Mode, requests::RegisterDevice}, service}}};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct Cli {
+    /// Directory with default.toml and the optional file of the `STAGE`
+    #[arg(long = "config", value_name = "DIRECTORY", env = "KMS_CONFIG_DIR", default_value = "./config", global = true)]
+    pub config_dir: PathBuf,
+    #[command(flatten)]
+    pub overrides: Overrides,
+    // Serves when left out, like before there were subcommands
+    #[command(subcommand)]
+    pub command: Option<Command>
+}
+
+/// Flags that take precedence over the configuration files and `KMS_` environment variables.
+#[derive(Args)]
+pub struct Overrides {
+    /// Address to listen on, overrides `server.address`
+    #[arg(short, long, global = true)]
+    address: Option<IpAddr>,
+    /// Port to listen on, overrides `server.port`
+    #[arg(short, long, global = true, value_parser = settings::validate_port)]
+    port: Option<u16>,
+    /// Store file, overrides `storage.path`
+    #[arg(long, global = true)]
+    storage_path: Option<String>,
+    /// `text` or `json`, overrides `logging.format`
+    #[arg(long, global = true, value_parser = parse_name::<LogFormat>)]
+    log_format: Option<LogFormat>
+}
+
+impl Overrides {
+    pub fn pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = Vec::new();
+        if let Some(address) = self.address {
+            pairs.push(("server.address", address.to_string()));
+        }
+        if let Some(port) = self.port {
+            pairs.push(("server.port", port.to_string()));
+        }
+        if let Some(path) = &self.storage_path {
+            pairs.push(("storage.path", path.to_owned()));
+        }
+        if let Some(format) = self.log_format {
+            pairs.push(("logging.format", if format == LogFormat::Json { "json" } else { "text" }.to_owned()));
+        }
+        pairs
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Runs the HTTP API along with the TFTP and ProxyDHCP servers that are enabled
+    Serve,
+    /// Works with the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Manages devices in the store, the same way the API does
+    #[command(subcommand)]
+    Device(DeviceCommand),
+    /// Works with the OpenAPI spec
+    #[command(subcommand)]
+    Openapi(OpenapiCommand),
+    /// Lists registered devices whose address differs from what the UniFi controller sees
+    Reconcile
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Loads the configuration and checks that the store and TLS files can be used
+    Check
+}
+
+#[derive(Subcommand)]
+pub enum DeviceCommand {
+    /// Registers a device
+    Add(AddDevice),
+    /// Prints a device
+    Get { mac_address: String },
+    /// Prints every device
+    List,
+    /// Deletes a device and releases its addresses
+    Delete { mac_address: String }
+}
+
+#[derive(Args)]
+pub struct AddDevice {
+    #[arg(long)]
+    hostname: String,
+    #[arg(long)]
+    mac_address: String,
+    /// Allocated from the pool of `--network` when left out
+    #[arg(long)]
+    ip_address: Option<Ipv4Addr>,
+    #[arg(long)]
+    network: Option<String>,
+    #[arg(long)]
+    ipv6_address: Option<Ipv6Addr>,
+    /// `static` or `slaac`
+    #[arg(long, value_parser = parse_name::<Ipv6Mode>)]
+    ipv6_mode: Option<Ipv6Mode>,
+    #[arg(long)]
+    cluster: Option<String>,
+    /// `control-plane`, `worker` or `etcd`
+    #[arg(long, value_parser = parse_name::<NodeRole>)]
+    role: Option<NodeRole>
+}
+
+impl From<AddDevice> for RegisterDevice {
+    fn from(device: AddDevice) -> Self {
+        let mut request = RegisterDevice::default();
+        request.set_hostname(device.hostname).set_mac_address(device.mac_address)
+            .set_ip_address(device.ip_address).set_network(device.network)
+            .set_ipv6_address(device.ipv6_address).set_ipv6_mode(device.ipv6_mode)
+            .set_cluster(device.cluster).set_role(device.role);
+        request
+    }
+}
+
+#[derive(Subcommand)]
+pub enum OpenapiCommand {
+    /// Writes the spec the server serves at /openapi.json
+    Export {
+        /// File to write to instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>
+    }
+}
+
+// Enums of the API are taken by the names they have in JSON, so both accept the same values
+fn parse_name<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    serde_json::from_value(Value::String(value.to_owned())).map_err(|_| format!("`{}` isn't a valid value", value))
+}
+
+fn print<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).expect("values of the API serialize to JSON"));
+}
+
+// Errors are printed as the problem the API would answer with
+fn fail(error: Errors) -> ! {
+    eprintln!("{}", serde_json::to_string_pretty(&error.problem()).expect("problems serialize to JSON"));
+    process::exit(1)
+}
+
+// Commands work on the store file, an in-memory store would forget their changes on exit. While
+// the server runs it holds the file, changes have to go through its API then. Commands that only
+// read open it without the lock, so they work alongside the server.
+fn open_store(settings: &Settings, read_only: bool) -> Store {
+    if settings.get_storage().get_path().is_none() {
+        error!("Storage Error: storage.path is not set, the command would only see an in-memory store");
+        process::exit(1)
+    }
+    let store = if read_only { Store::open_read_only(settings.get_storage()) } else { Store::open(settings.get_storage()) };
+    store.unwrap_or_else(|error| {
+        match error {
+            StoreError::Locked { .. } => error!("Storage Error: {}, use the HTTP API while the server runs", error),
+            error => error!("Storage Error: {}", error)
+        }
+        process::exit(1)
+    })
+}
+
+/// Exports the spec without loading the configuration, so it works in a bare build environment.
+pub fn export_openapi(output: Option<PathBuf>) -> io::Result<()> {
+    let spec = serde_json::to_string_pretty(&server::openapi_spec())?;
+    match output {
+        Some(path) => fs::write(path, spec),
+        None => {
+            println!("{}", spec);
+            Ok(())
+        }
+    }
+}
+
+/// Runs `command` with the configuration loaded from `config_dir`. `serve` loads it again from there,
+/// with the same `overrides`, whenever it changes.
+pub async fn run(command: Command, settings: Settings, config_dir: PathBuf, overrides: Vec<(&'static str, String)>) -> io::Result<()> {
+    let principal = Principal::local();
+    match command {
+        Command::Serve => {
+            if let Err(error) = telemetry::init(settings.get_tracing()) {
+                error!("Tracing Error: {}", error);
+                process::exit(1)
+            }
+            let result = server::serve(Arc::new(LiveSettings::new(settings, config_dir, overrides))).await;
+            telemetry::shutdown();
+            result
+        }
+        Command::Config(ConfigCommand::Check) => {
+            // Without the lock, checking leaves nothing behind and works while a server runs
+            match Store::open_read_only(settings.get_storage()).and_then(|store| store.check()) {
+                Ok(()) => {}
+                Err(error) => {
+                    error!("Storage Error: {}", error);
+                    process::exit(1)
+                }
+            }
+            if let Some(tls) = settings.get_server().get_tls() {
+                tls::acceptor(tls)?;
+            }
+            println!("Configuration is valid");
+            Ok(())
+        }
+        Command::Device(command) => {
+            let store = open_store(&settings, matches!(command, DeviceCommand::Get { .. } | DeviceCommand::List));
+            match command {
+                DeviceCommand::Add(device) => print(&service::register(&principal, device.into(), &settings, &store).await.unwrap_or_else(|error| fail(error))),
+                DeviceCommand::Get { mac_address } => print(&service::get(&principal, &store, &mac_address).unwrap_or_else(|error| fail(error))),
+                DeviceCommand::List => print(&service::list(&principal, &store)),
+                DeviceCommand::Delete { mac_address } => print(&service::delete(&principal, &store, &mac_address).unwrap_or_else(|error| fail(error)))
+            }
+            Ok(())
+        }
+        Command::Openapi(OpenapiCommand::Export { output }) => export_openapi(output),
+        Command::Reconcile => {
+            let store = open_store(&settings, true);
+            print(&service::reconcile(&principal, &settings, &store).await.unwrap_or_else(|error| fail(error)));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // A fresh directory holding a store with one device, tests run in parallel
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("kms-cli-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let tables = json!({ "devices": { "00:00:5e:00:53:01": { "hostname": "node-1", "macAddress": "00:00:5e:00:53:01", "ipAddress": "10.0.0.11", "network": null } } });
+        fs::write(directory.join("store.json"), tables.to_string()).unwrap();
+        directory
+    }
+
+    fn settings(directory: &std::path::Path) -> Settings {
+        serde_json::from_value(json!({
+            "server": { "address": "127.0.0.1", "port": 8080 },
+            "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" },
+            "storage": { "path": directory.join("store.json") }
+        })).unwrap()
+    }
+
+    fn files(directory: &std::path::Path) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(directory).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn applies_global_flags_to_subcommands() {
+        let cli = Cli::try_parse_from(["kms", "device", "list", "--storage-path", "/var/lib/kms/store.json", "--port", "8443"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Device(DeviceCommand::List))));
+        assert_eq!(cli.overrides.pairs(), vec![("server.port", "8443".to_owned()), ("storage.path", "/var/lib/kms/store.json".to_owned())]);
+        assert!(Cli::try_parse_from(["kms", "--port", "80"]).is_err());
+    }
+
+    #[actix_web::test]
+    async fn checks_the_configuration_without_leaving_files_behind() {
+        let directory = directory("check");
+        run(Command::Config(ConfigCommand::Check), settings(&directory), directory.clone(), Vec::new()).await.unwrap();
+        assert_eq!(files(&directory), vec!["store.json"]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn reads_devices_while_the_server_holds_the_store() {
+        let directory = directory("read");
+        let settings = settings(&directory);
+        let server = Store::open(settings.get_storage()).unwrap();
+        assert!(matches!(Store::open(settings.get_storage()), Err(StoreError::Locked { .. })));
+
+        run(Command::Device(DeviceCommand::List), settings.clone(), directory.clone(), Vec::new()).await.unwrap();
+        run(Command::Device(DeviceCommand::Get { mac_address: "00-00-5E-00-53-01".to_owned() }), settings.clone(), directory.clone(), Vec::new()).await.unwrap();
+        run(Command::Config(ConfigCommand::Check), settings, directory.clone(), Vec::new()).await.unwrap();
+        drop(server);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn refuses_changes_to_a_read_only_store() {
+        let directory = directory("read-only");
+        let store = Store::open_read_only(settings(&directory).get_storage()).unwrap();
+        assert_eq!(store.read(|tables| tables.devices.len()), 1);
+        let result = store.transaction(|tables| {
+            tables.devices.clear();
+            Ok::<_, StoreError>(())
+        });
+        assert!(matches!(result, Err(StoreError::ReadOnly)));
+        assert_eq!(store.read(|tables| tables.devices.len()), 1);
+        assert_eq!(files(&directory), vec!["store.json"]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
\ No newline at end of file
@@ -1,2 +1,3 @@
 pub mod dynamodb;
+pub mod kubernetes;
 pub mod unifi;
\ No newline at end of file
@@ -1,3 +1,4 @@
+#[allow(unused)]
 pub struct DynamoDBClient {
     pub client: &'static str
 }
\ No newline at end of file
@@ -0,0 +1,150 @@
+use std::time::Instant;
+
+use log::{error, info, warn};
+use opentelemetry::{trace::{Span, SpanKind, Status}, KeyValue};
+use reqwest::{Certificate, Client, Method, StatusCode};
+use serde_json::{json, Value};
+
+use crate::{telemetry, v1::{clusters::models::access::KubernetesAccess, tokens::models::token::BootstrapToken}};
+
+const SECRETS: &str = "/api/v1/namespaces/kube-system/secrets";
+
+// The group `kubeadm token create` puts tokens in, which kubeadm's RBAC lets join nodes
+const NODE_TOKEN_GROUP: &str = "system:bootstrappers:kubeadm:default-node-token";
+
+fn secret_name(id: &str) -> String {
+    format!("bootstrap-token-{}", id)
+}
+
+/// Formats Unix time as RFC 3339 in UTC, the format of a bootstrap token's `expiration`.
+fn rfc3339(seconds: u64) -> String {
+    // Days to civil date, after Howard Hinnant's `civil_from_days`
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    let time = seconds % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+/// The Secret `kubeadm token create` would create for `token`.
+fn bootstrap_token_secret(token: &BootstrapToken) -> Value {
+    let mut data = json!({
+        "token-id": token.get_id(),
+        "token-secret": token.get_secret(),
+        "expiration": rfc3339(*token.get_expires_at()),
+        "usage-bootstrap-authentication": "true",
+        "usage-bootstrap-signing": "true",
+        "auth-extra-groups": NODE_TOKEN_GROUP
+    });
+    if let Some(description) = token.get_description() {
+        data["description"] = Value::String(description.to_owned());
+    }
+    json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": { "name": secret_name(token.get_id()), "namespace": "kube-system" },
+        "type": "bootstrap.kubernetes.io/token",
+        "stringData": data
+    })
+}
+
+/// Talks to the API server of a running cluster, as far as KMS needs to.
+pub struct KubernetesClient {
+    client: Client,
+    server: String,
+    token: String
+}
+
+impl KubernetesClient {
+    pub fn from_access(access: &KubernetesAccess) -> Result<KubernetesClient, reqwest::Error> {
+        let client = Client::builder()
+            .add_root_certificate(Certificate::from_pem(access.get_ca_certificate().as_bytes())?)
+            .build()?;
+        Ok(KubernetesClient { client, server: access.get_server().trim_end_matches('/').to_owned(), token: access.get_token().to_owned() })
+    }
+
+    async fn call(&self, method: Method, path: &str, body: Option<&Value>) -> Result<reqwest::Response, reqwest::Error> {
+        let mut span = telemetry::span(format!("Kubernetes {} {}", method, path), SpanKind::Client);
+        span.set_attribute(KeyValue::new("http.method", method.to_string()));
+        span.set_attribute(KeyValue::new("http.target", path.to_owned()));
+
+        let mut request = self.client.request(method.clone(), format!("{}{}", self.server, path)).bearer_auth(&self.token);
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let started = Instant::now();
+        let response = request.send().await;
+        let elapsed = started.elapsed().as_millis();
+
+        match &response {
+            Ok(response) => {
+                span.set_attribute(KeyValue::new("http.status_code", i64::from(response.status().as_u16())));
+                if response.status().is_success() {
+                    info!("Kubernetes {} {} returned {} in {}ms", method, path, response.status(), elapsed);
+                } else {
+                    warn!("Kubernetes {} {} returned {} in {}ms", method, path, response.status(), elapsed);
+                    span.set_status(Status::error(response.status().to_string()));
+                }
+            }
+            Err(error) => {
+                error!("Kubernetes {} {} failed after {}ms: {}", method, path, elapsed, error);
+                span.set_status(Status::error(error.to_string()));
+            }
+        }
+        span.end();
+        response
+    }
+
+    /// Creates the Secret of `token` in `kube-system`, or replaces it when it exists already.
+    pub async fn apply_bootstrap_token(&self, token: &BootstrapToken) -> Result<(), reqwest::Error> {
+        let secret = bootstrap_token_secret(token);
+        let response = self.call(Method::POST, SECRETS, Some(&secret)).await?;
+        if response.status() == StatusCode::CONFLICT {
+            let path = format!("{}/{}", SECRETS, secret_name(token.get_id()));
+            self.call(Method::PUT, &path, Some(&secret)).await?.error_for_status()?;
+            return Ok(())
+        }
+        response.error_for_status().map(|_| ())
+    }
+
+    /// Deletes the Secret of the token `id`. The API server deletes expired tokens by itself, so
+    /// one that is gone already is fine.
+    pub async fn delete_bootstrap_token(&self, id: &str) -> Result<(), reqwest::Error> {
+        let response = self.call(Method::DELETE, &format!("{}/{}", SECRETS, secret_name(id)), None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(())
+        }
+        response.error_for_status().map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_unix_time_as_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(rfc3339(4_102_444_799), "2099-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn describes_tokens_as_kubeadm_does() {
+        let token = BootstrapToken::generate("prod", None, Some("worker".to_owned()), 60);
+        let secret = bootstrap_token_secret(&token);
+        assert_eq!(secret["metadata"]["name"], format!("bootstrap-token-{}", token.get_id()));
+        assert_eq!(secret["type"], "bootstrap.kubernetes.io/token");
+        assert_eq!(secret["stringData"]["token-secret"], token.get_secret().as_str());
+        assert_eq!(secret["stringData"]["expiration"], rfc3339(*token.get_expires_at()));
+        assert_eq!(secret["stringData"]["description"], "worker");
+    }
+}
\ No newline at end of file
@@ -1,3 +1,7 @@
+use std::{io, time::Instant};
+
+use log::{error, info, warn};
+use opentelemetry::{global::BoxedSpan, trace::{Span, SpanKind, Status}, KeyValue};
 use reqwest::{header::{HeaderMap, HeaderValue}, Method, Client};
 use serde::{Serialize};
 
@@ -8,6 +12,7 @@ pub mod models {
     use getset::{Getters};
 
     use serde::{Serialize, Deserialize};
+    #[allow(unused)]
     #[derive(Serialize, Deserialize)]
     struct UnifiMetaResponse {
         rc: String
@@ -144,10 +149,44 @@ pub struct UnifiApiClient {
     password: String
 }
 
-use self::models::{LoginBody};
+use self::models::{LoginBody, ListClientsResponse};
+use crate::{metrics, settings::UnifiSettings, telemetry};
+
+fn call_span(method: &Method, path: &str) -> BoxedSpan {
+    let mut span = telemetry::span(format!("UniFi {} {}", method, path), SpanKind::Client);
+    span.set_attribute(KeyValue::new("http.method", method.to_string()));
+    span.set_attribute(KeyValue::new("http.target", path.to_owned()));
+    span
+}
+
+/// Logs, counts and traces every call to the controller. The logger adds the ID of the request
+/// the call is made for, which ties a failed provisioning to the controller's answers.
+fn log_call(method: &Method, path: &str, started: Instant, mut span: BoxedSpan, response: Result<reqwest::Response, reqwest::Error>) -> Result<reqwest::Response, reqwest::Error> {
+    let duration = started.elapsed();
+    metrics::unifi_call(method.as_str(), path, response.as_ref().ok().map(|response| response.status().as_u16()), duration.as_secs_f64());
+    let elapsed = duration.as_millis();
+    match &response {
+        Ok(response) if response.status().is_success() => info!("UniFi {} {} returned {} in {}ms", method, path, response.status(), elapsed),
+        Ok(response) => warn!("UniFi {} {} returned {} in {}ms", method, path, response.status(), elapsed),
+        Err(error) => error!("UniFi {} {} failed after {}ms: {}", method, path, elapsed, error)
+    }
+    match &response {
+        Ok(response) => {
+            span.set_attribute(KeyValue::new("http.status_code", i64::from(response.status().as_u16())));
+            if !response.status().is_success() {
+                span.set_status(Status::error(response.status().to_string()));
+            }
+        },
+        Err(error) => span.set_status(Status::error(error.to_string()))
+    }
+    span.end();
+    response
+}
 
 impl UnifiApiClient {
-    async fn authenticate(&mut self) {
+    async fn authenticate(&mut self) -> Result<(), reqwest::Error> {
+        let span = call_span(&Method::POST, "/api/auth/login");
+        let started = Instant::now();
         let response = self.client.post(format!("{}/api/auth/login", self.base_url))
             .json(&LoginBody {
                 username: self.username.to_owned(),
@@ -155,31 +194,64 @@ impl UnifiApiClient {
                 remember_me: false
             })
             .send().await;
+        let response = log_call(&Method::POST, "/api/auth/login", started, span, response)
+            .and_then(reqwest::Response::error_for_status);
+        metrics::unifi_login(response.is_ok());
+        let response = response?;
 
-        self.headers.append("X-CSRF-Token", response.unwrap().headers().get("X-CSRF-Token").unwrap().into());
+        if let Some(token) = response.headers().get("X-CSRF-Token") {
+            self.headers.insert("X-CSRF-Token", token.to_owned());
+        }
+        Ok(())
     }
 
     async fn request(&mut self, method: Method, path: &str, body: Option<impl Serialize>) -> Result<reqwest::Response, reqwest::Error> {
-        self.authenticate().await;
-        match &body {
+        self.authenticate().await?;
+        let span = call_span(&method, path);
+        let started = Instant::now();
+        let response = match &body {
             Some(request_body) => {
-                self.client.request(method, format!("{}{}", self.base_url, path))
+                self.client.request(method.clone(), format!("{}{}", self.base_url, path))
                     .json(&request_body)
                     .headers(self.headers.clone())
                     .send()
                     .await
             },
             None => {
-                self.client.request(method, format!("{}{}", self.base_url, path))
+                self.client.request(method.clone(), format!("{}{}", self.base_url, path))
                 .headers(self.headers.clone())
                 .send()
                 .await
             }
-        }
+        };
+        log_call(&method, path, started, span, response)
+    }
+
+    /// Logs in without calling anything else, which tells whether the controller is reachable and
+    /// accepts the credentials.
+    pub async fn login(&mut self) -> Result<(), reqwest::Error> {
+        self.authenticate().await
     }
 
     pub async fn list_clients(&mut self) -> Result<reqwest::Response, reqwest::Error> {
-        self.request(Method::GET, "/proxy/network/api/s/default/stat/sta", None::<&str>).await
+        let response = self.request(Method::GET, "/proxy/network/api/s/default/stat/sta", None::<&str>).await?;
+        if response.status().is_success() {
+            metrics::unifi_synced();
+        }
+        Ok(response)
+    }
+
+    pub async fn list_client_devices(&mut self) -> Result<ListClientsResponse, reqwest::Error> {
+        self.list_clients().await?.error_for_status()?.json().await
+    }
+
+    /// Fails when the credentials are kept in files that can't be read.
+    pub fn from_settings(settings: &UnifiSettings) -> io::Result<UnifiApiClient> {
+        Ok(UnifiApiClient::new(
+            settings.get_base_url().clone(),
+            settings.get_username().expose()?,
+            settings.get_password().expose()?
+        ))
     }
 
     pub fn new(base_url: String, username: String, password: String) -> UnifiApiClient {
@@ -187,11 +259,11 @@ impl UnifiApiClient {
         headers.append("Content-Type", HeaderValue::from_static("application/json"));
         let client = Client::builder().danger_accept_invalid_certs(true).cookie_store(true).build().unwrap();
         UnifiApiClient {
-            client: client,
-            base_url: base_url,
-            headers: headers,
-            username: username,
-            password: password,
+            client,
+            base_url,
+            headers,
+            username,
+            password,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,358 @@
+use std::{collections::HashMap, io, panic};
+
+use actix_web::{dev::ServiceResponse, error::{self, BlockingError}, http::{header, StatusCode}, middleware::ErrorHandlerResponse, HttpMessage, HttpResponse};
+use convert_case::{Case, Casing};
+use derive_more::{Display, Error};
+use eui48::{MacAddress, ParseError};
+use log::{debug, error, warn};
+use paperclip::actix::{api_v2_errors, Apiv2Schema};
+use serde_derive::Serialize;
+use serde_json::Value;
+use validator::{ValidationError, ValidationErrors};
+
+use crate::{request_id::RequestId, store::StoreError, templates::TemplateError};
+
+const PROBLEM_JSON: &str = "application/problem+json";
+// Sent with every 401, the schemes the authentication middleware accepts
+const CHALLENGES: &str = "Bearer realm=\"kms\", ApiKey realm=\"kms\"";
+
+/// One problem with a request field. `params` carries the limits of the failed check, such as
+/// `min` and `max` of a length.
+#[derive(Clone, Debug, Serialize, Apiv2Schema)]
+pub struct FieldError {
+    code: String,
+    message: String,
+    params: HashMap<String, Value>
+}
+
+impl FieldError {
+    fn new(error: &ValidationError) -> FieldError {
+        let params: HashMap<String, Value> = error.params.iter()
+            // The rejected value is left out, it may well be a secret
+            .filter(|(name, _)| *name != "value")
+            .map(|(name, value)| (name.to_string(), value.to_owned()))
+            .collect();
+        FieldError { code: error.code.to_string(), message: describe(error), params }
+    }
+}
+
+/// The message of a validation error, for API responses as well as configuration problems.
+pub fn describe(error: &ValidationError) -> String {
+    match &error.message {
+        Some(message) => message.to_string(),
+        None => {
+            let params: HashMap<String, Value> = error.params.iter().map(|(name, value)| (name.to_string(), value.to_owned())).collect();
+            default_message(&error.code, &params)
+        }
+    }
+}
+
+// Range limits come as floats, `1024.0` reads odd for a port
+fn limit(value: &Value) -> Value {
+    match value.as_f64() {
+        Some(number) if number.fract() == 0.0 && number.abs() < i64::MAX as f64 => Value::from(number as i64),
+        _ => value.to_owned()
+    }
+}
+
+fn default_message(code: &str, params: &HashMap<String, Value>) -> String {
+    if let ("length", Some(length)) = (code, params.get("equal")) {
+        return format!("must be exactly {} characters long", length)
+    }
+    match (code, params.get("min").map(limit), params.get("max").map(limit)) {
+        ("length", Some(min), Some(max)) => format!("must be between {} and {} characters long", min, max),
+        ("length", Some(min), None) => format!("must be at least {} characters long", min),
+        ("length", None, Some(max)) => format!("must be at most {} characters long", max),
+        ("length", None, None) => "has an invalid length".to_owned(),
+        ("range", Some(min), Some(max)) => format!("must be between {} and {}", min, max),
+        ("range", Some(min), None) => format!("must be at least {}", min),
+        ("range", None, Some(max)) => format!("must be at most {}", max),
+        ("range", None, None) => "is out of range".to_owned(),
+        ("regex", _, _) => "has an invalid format".to_owned(),
+        ("url", _, _) => "must be a URL, e.g. https://example.com".to_owned(),
+        ("required", _, _) => "is required".to_owned(),
+        ("unknown", _, _) => "does not refer to an existing resource".to_owned(),
+        ("immutable", _, _) => "cannot be changed".to_owned(),
+        ("cidr", _, _) => "must be a network in CIDR notation, e.g. 10.0.0.0/24".to_owned(),
+        ("bytes", _, _) => "must be a MAC address, e.g. 00:11:22:33:44:55".to_owned(),
+        ("slaac", _, _) => "cannot be set when the address is derived with SLAAC".to_owned(),
+        ("prefix", _, _) => "requires a pool with an IPv6 prefix".to_owned(),
+        ("prefix_length", _, _) => "must be a /64 prefix, SLAAC addresses need one".to_owned(),
+        _ => "is invalid".to_owned()
+    }
+}
+
+pub type FieldErrors = HashMap<String, Vec<FieldError>>;
+
+/// An RFC 7807 problem details object, the body of every error response.
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    // Also in the X-Request-Id header and every log line written while handling the request
+    #[serde(rename = "requestId", skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    // Only present on validation problems, keyed by the camel cased field name
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    errors: FieldErrors
+}
+
+impl Problem {
+    fn new(problem_type: &str, status: StatusCode, detail: Option<String>) -> Problem {
+        Problem {
+            problem_type: problem_type.to_owned(),
+            title: status.canonical_reason().unwrap_or_default().to_owned(),
+            status: status.as_u16(),
+            detail,
+            instance: None,
+            request_id: None,
+            errors: HashMap::new()
+        }
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut response = HttpResponse::build(status);
+        response.insert_header((header::CONTENT_TYPE, PROBLEM_JSON));
+        if status == StatusCode::UNAUTHORIZED {
+            response.insert_header((header::WWW_AUTHENTICATE, CHALLENGES));
+        }
+        response.json(self)
+    }
+}
+
+#[api_v2_errors(
+    code = 400, description = "The request is invalid, `errors` lists the problems per field",
+    code = 401, description = "Credentials are missing or invalid",
+    code = 403, description = "The credentials do not allow the operation, `detail` says why",
+    code = 404, description = "The resource does not exist",
+    code = 409, description = "The request conflicts with the current state",
+    code = 500, description = "KMS failed to handle the request",
+    default_schema = "Problem"
+)]
+#[derive(Debug, Display, Error)]
+// Named after the problems they turn into, `NotFoundError` reads better than `NotFound` at call sites
+#[allow(clippy::enum_variant_names)]
+pub enum Errors {
+    #[display(fmt = "Validation Error")]
+    ValidationError { field_errors: FieldErrors },
+    #[display(fmt = "Internal Server Exception")]
+    InternalServerError,
+    #[display(fmt = "Not Found")]
+    NotFoundError,
+    #[display(fmt = "Unauthorized")]
+    UnauthorizedError,
+    #[display(fmt = "Forbidden: {}", reason)]
+    ForbiddenError { reason: String },
+    #[display(fmt = "Conflict: {}", message)]
+    ConflictError { message: String }
+}
+
+impl Errors {
+    pub fn problem(&self) -> Problem {
+        match self {
+            Errors::ValidationError { field_errors } => Problem {
+                errors: field_errors.to_owned(),
+                ..Problem::new("urn:kms:problem:validation", StatusCode::BAD_REQUEST, Some("The request has invalid fields".to_owned()))
+            },
+            Errors::InternalServerError => Problem::new("urn:kms:problem:internal", StatusCode::INTERNAL_SERVER_ERROR, None),
+            Errors::NotFoundError => Problem::new("urn:kms:problem:not-found", StatusCode::NOT_FOUND, None),
+            Errors::UnauthorizedError => Problem::new("urn:kms:problem:unauthorized", StatusCode::UNAUTHORIZED, None),
+            Errors::ForbiddenError { reason } => Problem::new("urn:kms:problem:forbidden", StatusCode::FORBIDDEN, Some(reason.to_owned())),
+            Errors::ConflictError { message } => Problem::new("urn:kms:problem:conflict", StatusCode::CONFLICT, Some(message.to_owned()))
+        }
+    }
+}
+
+impl error::ResponseError for Errors {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.problem().status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.problem().response()
+    }
+}
+
+/// Error handler for every 4xx and 5xx response. Errors of KMS get the path they occurred at as
+/// `instance` along with the request ID, errors raised by actix itself, e.g. for malformed JSON, become problems as well.
+pub fn problem_response<B>(response: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let mut problem = match response.response().error() {
+        Some(error) => match error.as_error::<Errors>() {
+            Some(error) => error.problem(),
+            None if response.status().is_client_error() => Problem::new("about:blank", response.status(), Some(error.to_string())),
+            None => Problem::new("about:blank", response.status(), None)
+        },
+        // Responses handlers built themselves are left as they are
+        None => return Ok(ErrorHandlerResponse::Response(response.map_into_left_body()))
+    };
+    problem.instance = Some(response.request().path().to_owned());
+    problem.request_id = response.request().extensions().get::<RequestId>().map(|id| id.0.clone());
+
+    let (request, _) = response.into_parts();
+    Ok(ErrorHandlerResponse::Response(ServiceResponse::new(request, problem.response()).map_into_right_body()))
+}
+
+impl From<StoreError> for Errors {
+    fn from(error: StoreError) -> Self {
+        error!("Store error: {}", error);
+        Errors::InternalServerError
+    }
+}
+
+impl From<TemplateError> for Errors {
+    fn from(error: TemplateError) -> Self {
+        match error {
+            TemplateError::InvalidName { .. } => validation_error("template", "invalid"),
+            TemplateError::Unknown { .. } => Errors::NotFoundError,
+            // Broken site templates are a configuration problem, report it instead of hiding it
+            TemplateError::Render(error) => Errors::ConflictError { message: error.to_string() },
+            TemplateError::Io(error) => {
+                error!("Template error: {}", error);
+                Errors::InternalServerError
+            }
+        }
+    }
+}
+
+impl From<io::Error> for Errors {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::NotFound {
+            return Errors::NotFoundError
+        }
+        error!("I/O error: {}", error);
+        Errors::InternalServerError
+    }
+}
+
+impl From<BlockingError> for Errors {
+    fn from(error: BlockingError) -> Self {
+        error!("Blocking task failed: {}", error);
+        Errors::InternalServerError
+    }
+}
+
+pub fn parse_validation_errors(validation_errors: ValidationErrors) -> FieldErrors {
+    validation_errors.field_errors().into_iter()
+        .map(|(field_name, field_errors)| (field_name.to_case(Case::Camel), field_errors.iter().map(FieldError::new).collect()))
+        .collect()
+}
+
+pub fn create_error_response_for_mac_address(error: ParseError) -> FieldErrors {
+    let code  = if error.to_owned().to_string().contains("Invalid length") { "length" } else { "bytes" };
+    debug!("Invalid MAC address: {}", error);
+    let mut validation_errors = ValidationErrors::new();
+    validation_errors.add("macAddress", ValidationError::new(code));
+    parse_validation_errors(validation_errors)
+}
+
+pub fn validation_error(field: &'static str, code: &'static str) -> Errors {
+    let mut validation_errors = ValidationErrors::new();
+    validation_errors.add(field, ValidationError::new(code));
+    Errors::ValidationError { field_errors: parse_validation_errors(validation_errors) }
+}
+
+pub fn parse_mac_address(mac_address: &str) -> Result<MacAddress, Errors> {
+    // There's an odd error with some invalid MACs that the parser panics at
+    match panic::catch_unwind(|| MacAddress::parse_str(mac_address)) {
+        Ok(parsed) => parsed.map_err(|error| Errors::ValidationError { field_errors: create_error_response_for_mac_address(error) }),
+        Err(panic) => {
+            warn!("Error parsing MAC address: {:?}", panic);
+            Err(validation_error("macAddress", "length"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{middleware::ErrorHandlers, test::{self, TestRequest}, web, App, ResponseError};
+    use serde_json::json;
+
+    use crate::request_id;
+    use super::*;
+
+    async fn invalid_name() -> Result<HttpResponse, Errors> {
+        Err(validation_error("name", "length"))
+    }
+
+    async fn echo(body: web::Json<Value>) -> HttpResponse {
+        HttpResponse::Ok().json(body.into_inner())
+    }
+
+    #[test]
+    fn challenges_unauthorized_clients() {
+        let response = Errors::UnauthorizedError.error_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers().get(header::WWW_AUTHENTICATE).unwrap(), CHALLENGES);
+        assert!(Errors::ForbiddenError { reason: "role".to_owned() }.error_response().headers().get(header::WWW_AUTHENTICATE).is_none());
+    }
+
+    #[actix_web::test]
+    async fn turns_errors_into_problems() {
+        let app = test::init_service(App::new()
+            .wrap(ErrorHandlers::new().default_handler(problem_response))
+            .wrap(request_id::RequestTracing)
+            .route("/invalid", web::get().to(invalid_name))
+            .route("/echo", web::post().to(echo))).await;
+
+        let response = test::call_service(&app, TestRequest::get().uri("/invalid").insert_header(("X-Request-Id", "abc")).to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+        let problem: Value = test::read_body_json(response).await;
+        assert_eq!(problem["type"], "urn:kms:problem:validation");
+        assert_eq!(problem["title"], "Bad Request");
+        assert_eq!(problem["status"], 400);
+        assert_eq!(problem["detail"], "The request has invalid fields");
+        assert_eq!(problem["instance"], "/invalid");
+        assert_eq!(problem["requestId"], "abc");
+        assert_eq!(problem["errors"]["name"][0]["code"], "length");
+        assert_eq!(problem["errors"]["name"][0]["message"], "has an invalid length");
+
+        // Errors actix raises on its own, here for a body that is not JSON
+        let response = test::call_service(&app, TestRequest::post().uri("/echo").insert_header((header::CONTENT_TYPE, "application/json")).set_payload("{").to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+        let problem: Value = test::read_body_json(response).await;
+        assert_eq!(problem["type"], "about:blank");
+        assert!(problem["detail"].is_string());
+        assert!(problem.get("errors").is_none());
+
+        // Successful responses pass through untouched
+        let response = test::call_service(&app, TestRequest::post().uri("/echo").set_json(json!({ "a": 1 })).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+    }
+
+    #[test]
+    fn leaves_the_detail_out_of_internal_errors() {
+        let problem = serde_json::to_value(Errors::InternalServerError.problem()).unwrap();
+        assert_eq!(problem, json!({ "type": "urn:kms:problem:internal", "title": "Internal Server Error", "status": 500 }));
+        let problem = serde_json::to_value(Errors::ConflictError { message: "taken".to_owned() }.problem()).unwrap();
+        assert_eq!(problem["status"], 409);
+        assert_eq!(problem["detail"], "taken");
+    }
+
+    #[test]
+    fn parses_mac_addresses_in_every_notation() {
+        let expected = MacAddress::parse_str("00:00:5e:00:53:af").unwrap();
+        for notation in ["00:00:5e:00:53:af", "00-00-5e-00-53-af", "0000.5e00.53af", "00005e0053af", "00:00:5E:00:53:Af"] {
+            assert_eq!(parse_mac_address(notation).unwrap(), expected, "{}", notation);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_mac_addresses() {
+        for (input, code) in [("00:00:5e", "length"), ("", "length"), ("00:00:5e:00:53:zz", "bytes")] {
+            match parse_mac_address(input) {
+                Err(Errors::ValidationError { field_errors }) => assert_eq!(field_errors["macAddress"][0].code, code, "{}", input),
+                other => panic!("{} parsed as {:?}", input, other)
+            }
+        }
+    }
+}
\ No newline at end of file
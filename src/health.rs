@@ -0,0 +1,113 @@
+use std::{collections::{BTreeMap, HashMap}, future::Future, sync::Mutex, time::{Duration, Instant}};
+
+use actix_web::{rt::time::timeout, web::Data, HttpResponse};
+use log::warn;
+use paperclip::actix::{api_v2_operation, get, Apiv2Schema};
+use serde_derive::Serialize;
+
+use crate::{clients::unifi::UnifiApiClient, errors::Errors, settings::Settings, store::Store, v1::tokens::models::token::now};
+
+// Probes come every few seconds from every kubelet, the controller shouldn't see a login for each
+const CACHE_FOR: Duration = Duration::from_secs(10);
+// Below the default timeout of Kubernetes probes, so a hanging controller reports as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Apiv2Schema)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down
+}
+
+/// The outcome of checking one dependency.
+#[derive(Clone, Debug, Serialize, Apiv2Schema)]
+#[serde(rename_all = "camelCase")]
+pub struct Check {
+    status: Status,
+    // Why the dependency is down
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    // Unix time the check ran at, older than the request when the result came from the cache
+    checked_at: u64,
+    duration_ms: u64
+}
+
+#[derive(Serialize, Apiv2Schema)]
+pub struct Health {
+    status: Status,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    checks: BTreeMap<String, Check>
+}
+
+impl Health {
+    fn response(&self) -> HttpResponse {
+        match self.status {
+            Status::Up => HttpResponse::Ok().json(self),
+            Status::Down => HttpResponse::ServiceUnavailable().json(self)
+        }
+    }
+}
+
+/// The latest result of every dependency check.
+#[derive(Default)]
+pub struct HealthCache {
+    checks: Mutex<HashMap<&'static str, (Instant, Check)>>
+}
+
+impl HealthCache {
+    fn cached(&self, name: &str) -> Option<Check> {
+        let checks = self.checks.lock().unwrap_or_else(|error| error.into_inner());
+        checks.get(name).filter(|(at, _)| at.elapsed() < CACHE_FOR).map(|(_, check)| check.clone())
+    }
+
+    async fn check<F>(&self, name: &'static str, check: F) -> Check
+    where F: Future<Output = Result<(), String>> {
+        if let Some(check) = self.cached(name) {
+            return check
+        }
+
+        let started = Instant::now();
+        let result = match timeout(CHECK_TIMEOUT, check).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("no answer within {}s", CHECK_TIMEOUT.as_secs()))
+        };
+        if let Err(error) = &result {
+            warn!("Readiness check {} failed: {}", name, error);
+        }
+        let check = Check {
+            status: if result.is_ok() { Status::Up } else { Status::Down },
+            detail: result.err(),
+            checked_at: now(),
+            duration_ms: started.elapsed().as_millis() as u64
+        };
+        self.checks.lock().unwrap_or_else(|error| error.into_inner()).insert(name, (Instant::now(), check.clone()));
+        check
+    }
+}
+
+/// Liveness probe. Answers as long as the process serves requests, without looking at any
+/// dependency, so an unreachable controller never gets KMS restarted.
+#[api_v2_operation]
+#[get("/healthz")]
+pub async fn get_health() -> Result<HttpResponse, Errors> {
+    Ok(Health { status: Status::Up, checks: BTreeMap::new() }.response())
+}
+
+/// Readiness probe. Checks that the store can be written and that the UniFi controller accepts
+/// the configured credentials. Answers 503 when either is down, `checks` tells which one and why.
+/// Results are reused for 10 seconds.
+#[api_v2_operation]
+#[get("/readyz")]
+pub async fn get_readiness(settings: Data<Settings>, store: Data<Store>, cache: Data<HealthCache>) -> Result<HttpResponse, Errors> {
+    let mut checks = BTreeMap::new();
+    let storage = cache.check("storage", async { store.check().map_err(|error| error.to_string()) }).await;
+    checks.insert("storage".to_owned(), storage);
+    let unifi = cache.check("unifi", async {
+        let mut client = UnifiApiClient::from_settings(settings.get_unifi()).map_err(|error| format!("unable to read the credentials: {}", error))?;
+        client.login().await.map_err(|error| error.to_string())
+    }).await;
+    checks.insert("unifi".to_owned(), unifi);
+
+    let status = if checks.values().all(|check| check.status == Status::Up) { Status::Up } else { Status::Down };
+    Ok(Health { status, checks }.response())
+}
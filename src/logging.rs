@@ -0,0 +1,69 @@
+use std::{cell::RefCell, io::Write, sync::atomic::{AtomicBool, Ordering}};
+
+use env_logger::{fmt::Formatter, Builder, Target};
+use log::{info, Record};
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+
+use crate::{request_id, settings::LogFormat};
+
+const ACCESS_TARGET: &str = "kms::access";
+
+// The logger is installed before the settings are read, so the format is switched afterwards
+static JSON: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Fields of the access log line being written. env_logger formats on the thread that logs
+    static ACCESS: RefCell<Option<Access>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Access {
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: u128
+}
+
+pub fn init() {
+    Builder::from_default_env().target(Target::Stdout).format(format).init();
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// Writes one access log line. In JSON the request's fields are separate keys, so they can be
+/// filtered on without parsing the message.
+pub fn access(entry: &Access) {
+    ACCESS.with(|access| *access.borrow_mut() = Some(entry.clone()));
+    info!(target: ACCESS_TARGET, "{} {} {} {}ms", entry.method, entry.path, entry.status, entry.duration_ms);
+    ACCESS.with(|access| access.borrow_mut().take());
+}
+
+fn format(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let request_id = request_id::current();
+    if !JSON.load(Ordering::Relaxed) {
+        return match request_id {
+            Some(id) => writeln!(buf, "[{} {:<5} {}] [{}] {}", buf.timestamp(), record.level(), record.target(), id, record.args()),
+            None => writeln!(buf, "[{} {:<5} {}] {}", buf.timestamp(), record.level(), record.target(), record.args())
+        }
+    }
+
+    let mut line = json!({
+        "timestamp": buf.timestamp_millis().to_string(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string()
+    });
+    if let Some(id) = request_id {
+        line["requestId"] = Value::String(id);
+    }
+    if record.target() == ACCESS_TARGET {
+        if let Some(Value::Object(fields)) = ACCESS.with(|access| access.borrow().as_ref().and_then(|entry| serde_json::to_value(entry).ok())) {
+            line.as_object_mut().expect("log lines are objects").extend(fields);
+        }
+    }
+    writeln!(buf, "{}", line)
+}
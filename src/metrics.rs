@@ -0,0 +1,220 @@
+use std::{future::{ready, Future, Ready}, pin::Pin, rc::Rc, sync::atomic::{AtomicU64, Ordering}, time::Instant};
+
+use actix_web::{dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform}, web::Data, Error, HttpResponse};
+use lazy_static::lazy_static;
+use log::error;
+use paperclip::actix::{api_v2_operation, get};
+use prometheus::{register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder, GaugeVec, HistogramTimer, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder};
+
+use crate::{errors::Errors, store::Store, v1::{devices::models::device::ProvisioningState, tokens::models::token::now}};
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "kms_http_requests_total", "HTTP requests by route pattern and status", &["method", "route", "status"]).unwrap();
+    static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "kms_http_request_duration_seconds", "Time to answer HTTP requests", &["method", "route"]).unwrap();
+    static ref UNIFI_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "kms_unifi_requests_total", "Calls to the UniFi controller by status", &["method", "path", "status"]).unwrap();
+    static ref UNIFI_DURATION: HistogramVec = register_histogram_vec!(
+        "kms_unifi_request_duration_seconds", "Time the UniFi controller took to answer", &["method", "path"]).unwrap();
+    static ref UNIFI_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "kms_unifi_errors_total", "UniFi calls that failed or were answered with an error status", &["method", "path"]).unwrap();
+    static ref UNIFI_LOGINS: IntCounterVec = register_int_counter_vec!(
+        "kms_unifi_logins_total", "Logins at the UniFi controller", &["outcome"]).unwrap();
+    static ref STORE_DURATION: HistogramVec = register_histogram_vec!(
+        "kms_store_operation_duration_seconds", "Time spent in store operations, including waiting for the lock",
+        &["operation"], vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0]).unwrap();
+    static ref DEVICES: IntGaugeVec = register_int_gauge_vec!(
+        "kms_devices", "Registered devices by provisioning state", &["state"]).unwrap();
+    static ref CLUSTER_DEVICES: IntGaugeVec = register_int_gauge_vec!(
+        "kms_cluster_devices", "Devices assigned to each cluster", &["cluster"]).unwrap();
+    static ref SYNC_LAG: GaugeVec = register_gauge_vec!(
+        "kms_sync_lag_seconds", "Seconds since the last successful sync with an external source", &["source"]).unwrap();
+}
+
+// Unix time of the last client list fetched from the controller, 0 before the first one
+static UNIFI_SYNCED_AT: AtomicU64 = AtomicU64::new(0);
+
+pub fn unifi_call(method: &str, path: &str, status: Option<u16>, duration_seconds: f64) {
+    let status_label = status.map_or_else(|| "error".to_owned(), |status| status.to_string());
+    UNIFI_REQUESTS.with_label_values(&[method, path, &status_label]).inc();
+    UNIFI_DURATION.with_label_values(&[method, path]).observe(duration_seconds);
+    if status.is_none_or(|status| status >= 400) {
+        UNIFI_ERRORS.with_label_values(&[method, path]).inc();
+    }
+}
+
+pub fn unifi_login(success: bool) {
+    UNIFI_LOGINS.with_label_values(&[if success { "success" } else { "failure" }]).inc();
+}
+
+pub fn unifi_synced() {
+    UNIFI_SYNCED_AT.store(now(), Ordering::Relaxed);
+}
+
+/// Observes the duration of a store operation when dropped.
+pub fn store_timer(operation: &str) -> HistogramTimer {
+    STORE_DURATION.with_label_values(&[operation]).start_timer()
+}
+
+fn state_label(state: ProvisioningState) -> &'static str {
+    match state {
+        ProvisioningState::Provisioning => "provisioning",
+        ProvisioningState::Provisioned => "provisioned",
+        ProvisioningState::Failed => "failed"
+    }
+}
+
+// The device gauges are derived from the store on every scrape, so they can't drift from it
+fn update_gauges(store: &Store) {
+    store.read(|tables| {
+        DEVICES.reset();
+        CLUSTER_DEVICES.reset();
+        for state in [ProvisioningState::Provisioning, ProvisioningState::Provisioned, ProvisioningState::Failed] {
+            DEVICES.with_label_values(&[state_label(state)]).set(0);
+        }
+        for device in tables.devices.values() {
+            DEVICES.with_label_values(&[state_label(*device.get_state())]).inc();
+            if let Some(cluster) = device.get_cluster() {
+                CLUSTER_DEVICES.with_label_values(&[cluster]).inc();
+            }
+        }
+    });
+
+    let synced_at = UNIFI_SYNCED_AT.load(Ordering::Relaxed);
+    if synced_at > 0 {
+        SYNC_LAG.with_label_values(&["unifi"]).set(now().saturating_sub(synced_at) as f64);
+    }
+}
+
+/// Metrics in the Prometheus text format.
+#[api_v2_operation]
+#[get("/metrics")]
+pub async fn get_metrics(store: Data<Store>) -> Result<HttpResponse, Errors> {
+    update_gauges(&store);
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut buffer).map_err(|error| {
+        error!("Unable to encode metrics: {}", error);
+        Errors::InternalServerError
+    })?;
+    Ok(HttpResponse::Ok().content_type(encoder.format_type()).body(buffer))
+}
+
+/// Counts requests and measures their latency per route pattern, so device MACs in paths don't
+/// create a series each.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let method = request.method().to_string();
+        let route = request.match_pattern().unwrap_or_else(|| "unmatched".to_owned());
+        let started = Instant::now();
+
+        Box::pin(async move {
+            let result = service.call(request).await;
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(error) => error.as_response_error().status_code()
+            };
+            HTTP_REQUESTS.with_label_values(&[&method, &route, status.as_str()]).inc();
+            HTTP_DURATION.with_label_values(&[&method, &route]).observe(started.elapsed().as_secs_f64());
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::{self, TestRequest}, App};
+    use eui48::MacAddress;
+    use paperclip::actix::OpenApiExt;
+    use serde_json::json;
+
+    use crate::{server, settings::{Settings, StorageSettings}, v1::devices::models::device::Device};
+    use super::*;
+
+    fn settings() -> Settings {
+        serde_json::from_value(json!({
+            "server": { "address": "127.0.0.1", "port": 8080 },
+            "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" },
+            "auth": { "enabled": false }
+        })).unwrap()
+    }
+
+    fn store() -> Store {
+        let mut device = Device::new("node-1", MacAddress::parse_str("00:00:5e:00:53:01").unwrap(), "10.0.0.11".parse().unwrap(), None);
+        device.set_cluster(Some("metrics".to_owned())).set_state(ProvisioningState::Failed);
+        let store = Store::open(&StorageSettings::default()).unwrap();
+        store.transaction(|tables| {
+            tables.devices.insert(device.get_mac_address().to_owned(), device);
+            Ok::<_, Errors>(())
+        }).unwrap();
+        store
+    }
+
+    #[actix_web::test]
+    async fn labels_requests_by_route_pattern() {
+        let app = test::init_service(App::new()
+            .wrap(crate::auth::Authentication)
+            .wrap(RequestMetrics)
+            .wrap_api()
+            .app_data(Data::new(settings()))
+            .app_data(Data::new(store()))
+            .configure(server::routes)
+            .build()).await;
+
+        let response = test::call_service(&app, TestRequest::get().uri("/v1/devices/device/00:00:5e:00:53:99").to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        test::call_service(&app, TestRequest::get().uri("/no/such/route").to_request()).await;
+        unifi_call("GET", "/api/s/default/stat/sta", Some(502), 0.25);
+
+        let response = test::call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let metrics = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        for line in [
+            "kms_http_requests_total{method=\"GET\",route=\"/v1/devices/device/{mac_address}\",status=\"404\"}",
+            "kms_http_request_duration_seconds_count{method=\"GET\",route=\"/v1/devices/device/{mac_address}\"}",
+            "kms_http_requests_total{method=\"GET\",route=\"unmatched\",status=\"404\"}",
+            "kms_unifi_requests_total{method=\"GET\",path=\"/api/s/default/stat/sta\",status=\"502\"}",
+            "kms_unifi_errors_total{method=\"GET\",path=\"/api/s/default/stat/sta\"}",
+            "kms_devices{state=\"failed\"} 1",
+            "kms_cluster_devices{cluster=\"metrics\"} 1"
+        ] {
+            assert!(metrics.contains(line), "{} missing from\n{}", line, metrics);
+        }
+        // Paths with a MAC address must not turn into series of their own
+        assert!(!metrics.contains("00:00:5e:00:53:99"));
+    }
+}
\ No newline at end of file
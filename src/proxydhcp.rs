@@ -0,0 +1,311 @@
+use std::{collections::BTreeMap, io, net::{IpAddr, Ipv4Addr, SocketAddr}, rc::Rc, sync::Arc};
+
+use actix_web::{rt::net::UdpSocket, web::Data};
+use eui48::MacAddress;
+use log::{debug, info, error};
+
+use crate::{reload::LiveSettings, settings::Settings, store::Store, v1::boot::models::service};
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const ETHERNET: u8 = 1;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const PXE_PORT: u16 = 4011;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_VENDOR: u8 = 43;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_VENDOR_CLASS: u8 = 60;
+const OPTION_BOOTFILE: u8 = 67;
+const OPTION_USER_CLASS: u8 = 77;
+const OPTION_CLIENT_ARCH: u8 = 93;
+const OPTION_CLIENT_UUID: u8 = 97;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+
+// PXE_DISCOVERY_CONTROL with bit 3 set: boot the file named in this offer, skip boot server discovery
+const PXE_VENDOR_OPTIONS: [u8; 4] = [6, 1, 8, OPTION_END];
+// Client system architecture (RFC 4578) of legacy BIOS PXE ROMs
+const ARCH_BIOS: u16 = 0;
+
+struct Request {
+    header: [u8; 236],
+    options: BTreeMap<u8, Vec<u8>>
+}
+
+impl Request {
+    fn parse(packet: &[u8]) -> Option<Request> {
+        if packet.len() < 240 || packet[0] != BOOTREQUEST || packet[1] != ETHERNET || packet[2] != 6 || packet[236..240] != MAGIC_COOKIE {
+            return None
+        }
+
+        let mut options = BTreeMap::new();
+        let mut index = 240;
+        while index < packet.len() {
+            match packet[index] {
+                OPTION_PAD => index += 1,
+                OPTION_END => break,
+                code => {
+                    let length = *packet.get(index + 1)? as usize;
+                    let value = packet.get(index + 2..index + 2 + length)?;
+                    // Long options are split into several instances that belong together (RFC 3396)
+                    options.entry(code).or_insert_with(Vec::new).extend_from_slice(value);
+                    index += 2 + length;
+                }
+            }
+        }
+        Some(Request { header: packet[..236].try_into().ok()?, options })
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        let mut bytes = [0u8; 6];
+        bytes.copy_from_slice(&self.header[28..34]);
+        MacAddress::new(bytes)
+    }
+
+    fn relay(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.header[24], self.header[25], self.header[26], self.header[27])
+    }
+
+    fn message_type(&self) -> Option<u8> {
+        self.options.get(&OPTION_MESSAGE_TYPE)?.first().copied()
+    }
+
+    fn is_pxe_client(&self) -> bool {
+        self.options.get(&OPTION_VENDOR_CLASS).is_some_and(|class| class.starts_with(b"PXEClient"))
+    }
+
+    /// iPXE announces itself in the user class, it has to be sent on to HTTP instead of being
+    /// handed iPXE again.
+    fn is_ipxe(&self) -> bool {
+        self.options.get(&OPTION_USER_CLASS).is_some_and(|class| class.windows(4).any(|window| window == b"iPXE"))
+    }
+
+    fn architecture(&self) -> Option<u16> {
+        match self.options.get(&OPTION_CLIENT_ARCH)?.as_slice() {
+            [high, low, ..] => Some(u16::from_be_bytes([*high, *low])),
+            _ => None
+        }
+    }
+}
+
+fn push_option(packet: &mut Vec<u8>, code: u8, value: &[u8]) {
+    for chunk in value.chunks(255) {
+        packet.push(code);
+        packet.push(chunk.len() as u8);
+        packet.extend_from_slice(chunk);
+    }
+}
+
+/// A ProxyDHCP reply only carries boot information, addresses are left to the real DHCP server.
+fn reply(request: &Request, message_type: u8, next_server: Ipv4Addr, filename: &str) -> Vec<u8> {
+    let mut packet = request.header.to_vec();
+    packet[0] = BOOTREPLY;
+    packet[3] = 0;
+    // ciaddr, yiaddr
+    packet[12..20].fill(0);
+    packet[20..24].copy_from_slice(&next_server.octets());
+    // sname, file
+    packet[44..236].fill(0);
+    if filename.len() < 128 {
+        packet[108..108 + filename.len()].copy_from_slice(filename.as_bytes());
+    }
+    packet.extend_from_slice(&MAGIC_COOKIE);
+
+    push_option(&mut packet, OPTION_MESSAGE_TYPE, &[message_type]);
+    push_option(&mut packet, OPTION_SERVER_ID, &next_server.octets());
+    push_option(&mut packet, OPTION_VENDOR_CLASS, b"PXEClient");
+    push_option(&mut packet, OPTION_BOOTFILE, filename.as_bytes());
+    if let Some(uuid) = request.options.get(&OPTION_CLIENT_UUID) {
+        push_option(&mut packet, OPTION_CLIENT_UUID, uuid);
+    }
+    push_option(&mut packet, OPTION_VENDOR, &PXE_VENDOR_OPTIONS);
+    packet.push(OPTION_END);
+    packet
+}
+
+struct Responder {
+    // Answers use the settings current when a request arrives, the sockets and next server are fixed
+    live: Arc<LiveSettings>,
+    store: Data<Store>,
+    next_server: Ipv4Addr
+}
+
+impl Responder {
+    fn boot_filename(&self, settings: &Settings, request: &Request) -> String {
+        if request.is_ipxe() {
+            return service::ipxe_script_url(settings, &self.next_server.to_string(), &request.mac_address())
+        }
+        match request.architecture() {
+            Some(ARCH_BIOS) | None => settings.get_proxy_dhcp().get_bios_filename().to_owned(),
+            Some(_) => settings.get_proxy_dhcp().get_uefi_filename().to_owned()
+        }
+    }
+
+    /// Builds the answer to `packet` received on `port` and where to send it, if KMS should answer
+    /// at all: only PXE clients of devices waiting for provisioning are.
+    fn answer(&self, packet: &[u8], peer: SocketAddr, port: u16) -> Option<(Vec<u8>, SocketAddr)> {
+        let request = Request::parse(packet)?;
+        if !request.is_pxe_client() {
+            return None
+        }
+        let mac = request.mac_address();
+        if !service::awaits_provisioning(&self.store, &mac) {
+            debug!("Ignoring PXE request of {}, it is unknown or not waiting for provisioning", mac);
+            return None
+        }
+
+        let (message_type, destination) = match (port, request.message_type()?) {
+            (DHCP_SERVER_PORT, DISCOVER) if request.relay().is_unspecified() => (OFFER, SocketAddr::from((Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT))),
+            (DHCP_SERVER_PORT, DISCOVER) => (OFFER, SocketAddr::from((request.relay(), DHCP_SERVER_PORT))),
+            (PXE_PORT, REQUEST) => (ACK, peer),
+            _ => return None
+        };
+        let filename = self.boot_filename(&self.live.current(), &request);
+        info!("Answering PXE request of {} with {}", mac, filename);
+        Some((reply(&request, message_type, self.next_server, &filename), destination))
+    }
+
+    async fn listen(self: Rc<Self>, socket: UdpSocket, port: u16) {
+        let mut buffer = [0u8; 1500];
+        loop {
+            let (length, peer) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(error) => {
+                    error!("ProxyDHCP receive error on port {}: {}", port, error);
+                    continue
+                }
+            };
+            if let Some((reply, destination)) = self.answer(&buffer[..length], peer, port) {
+                if let Err(error) = socket.send_to(&reply, destination).await {
+                    error!("Unable to send ProxyDHCP reply to {}: {}", destination, error);
+                }
+            }
+        }
+    }
+}
+
+/// Answers PXE ROMs with the boot file for registered devices next to the site's DHCP server,
+/// which keeps handing out the addresses.
+pub struct ProxyDhcpServer {
+    dhcp: UdpSocket,
+    pxe: UdpSocket,
+    responder: Rc<Responder>
+}
+
+impl ProxyDhcpServer {
+    pub async fn bind(live: Arc<LiveSettings>, store: Data<Store>) -> io::Result<ProxyDhcpServer> {
+        let settings = live.current();
+        let proxy_dhcp = settings.get_proxy_dhcp();
+        let next_server = match (proxy_dhcp.get_next_server(), settings.get_server().get_address()) {
+            (Some(next_server), _) => *next_server,
+            (None, IpAddr::V4(address)) if !address.is_unspecified() => *address,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "proxy_dhcp.next_server is required unless the server binds to a specific IPv4 address"))
+        };
+
+        let dhcp = UdpSocket::bind((*proxy_dhcp.get_address(), DHCP_SERVER_PORT)).await?;
+        dhcp.set_broadcast(true)?;
+        let pxe = UdpSocket::bind((*proxy_dhcp.get_address(), PXE_PORT)).await?;
+        info!("ProxyDHCP listening on {} ports {} and {}, next server {}", proxy_dhcp.get_address(), DHCP_SERVER_PORT, PXE_PORT, next_server);
+
+        Ok(ProxyDhcpServer { dhcp, pxe, responder: Rc::new(Responder { live, store, next_server }) })
+    }
+
+    /// Listens on both ports in the same task, so stopping it stops both.
+    pub async fn run(self) {
+        tokio::join!(
+            self.responder.clone().listen(self.pxe, PXE_PORT),
+            self.responder.listen(self.dhcp, DHCP_SERVER_PORT)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A DHCPDISCOVER of a PXE ROM with `options` after the message type
+    fn discover(options: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 236];
+        packet[0] = BOOTREQUEST;
+        packet[1] = ETHERNET;
+        packet[2] = 6;
+        packet[24..28].copy_from_slice(&[10, 0, 0, 254]);
+        packet[28..34].copy_from_slice(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        packet.extend_from_slice(&MAGIC_COOKIE);
+        packet.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, DISCOVER]);
+        packet.extend_from_slice(options);
+        packet.push(OPTION_END);
+        packet
+    }
+
+    #[test]
+    fn parses_pxe_requests() {
+        let mut options = vec![OPTION_VENDOR_CLASS, 32];
+        options.extend_from_slice(b"PXEClient:Arch:00007:UNDI:003016");
+        options.extend_from_slice(&[OPTION_PAD, OPTION_CLIENT_ARCH, 2, 0, 7]);
+        let request = Request::parse(&discover(&options)).unwrap();
+
+        assert_eq!(request.mac_address(), MacAddress::parse_str("00:11:22:33:44:55").unwrap());
+        assert_eq!(request.relay(), Ipv4Addr::new(10, 0, 0, 254));
+        assert_eq!(request.message_type(), Some(DISCOVER));
+        assert!(request.is_pxe_client());
+        assert!(!request.is_ipxe());
+        assert_eq!(request.architecture(), Some(7));
+    }
+
+    #[test]
+    fn recognizes_ipxe() {
+        let mut options = vec![OPTION_VENDOR_CLASS, 9];
+        options.extend_from_slice(b"PXEClient");
+        options.extend_from_slice(&[OPTION_USER_CLASS, 4]);
+        options.extend_from_slice(b"iPXE");
+        let request = Request::parse(&discover(&options)).unwrap();
+        assert!(request.is_pxe_client());
+        assert!(request.is_ipxe());
+        assert_eq!(request.architecture(), None);
+    }
+
+    #[test]
+    fn joins_split_options() {
+        let mut options = vec![OPTION_VENDOR_CLASS, 3];
+        options.extend_from_slice(b"PXE");
+        options.extend_from_slice(&[OPTION_VENDOR_CLASS, 6]);
+        options.extend_from_slice(b"Client");
+        assert!(Request::parse(&discover(&options)).unwrap().is_pxe_client());
+    }
+
+    #[test]
+    fn ignores_other_clients() {
+        let mut options = vec![OPTION_VENDOR_CLASS, 8];
+        options.extend_from_slice(b"MSFT 5.0");
+        assert!(!Request::parse(&discover(&options)).unwrap().is_pxe_client());
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let packet = discover(&[]);
+        assert!(Request::parse(&packet[..239]).is_none());
+
+        let mut reply = packet.clone();
+        reply[0] = BOOTREPLY;
+        assert!(Request::parse(&reply).is_none());
+
+        let mut cookie = packet.clone();
+        cookie[236] = 0;
+        assert!(Request::parse(&cookie).is_none());
+
+        // An option running past the end of the packet
+        let mut truncated = packet[..packet.len() - 1].to_vec();
+        truncated.extend_from_slice(&[OPTION_VENDOR_CLASS, 9, b'P']);
+        assert!(Request::parse(&truncated).is_none());
+    }
+}
\ No newline at end of file
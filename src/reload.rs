@@ -0,0 +1,272 @@
+use std::{collections::BTreeMap, future::{ready, Ready}, path::PathBuf, rc::Rc, sync::{Arc, PoisonError, RwLock}, time::Duration};
+
+use actix_web::{dev::{forward_ready, Extensions, Service, ServiceRequest, ServiceResponse, Transform}, rt::{self, signal::unix::{signal, SignalKind}, time::sleep}, web::Data, Error};
+use log::{debug, error, info, warn};
+use notify::{recommended_watcher, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use serde_json::Value;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::{logging, settings::Settings};
+
+// Editors and Kubernetes write a file in several steps, the reload waits until they're done
+const SETTLE: Duration = Duration::from_millis(500);
+
+// Read once at startup: the sockets are bound and the store is opened by then. The TFTP and
+// ProxyDHCP servers read the rest of their settings for every request
+const RESTART_REQUIRED: [&str; 9] = [
+    "server", "storage", "tracing",
+    "tftp.enabled", "tftp.address", "tftp.port",
+    "proxy_dhcp.enabled", "proxy_dhcp.address", "proxy_dhcp.next_server"
+];
+
+#[derive(Clone, Copy, Debug)]
+enum Trigger {
+    FileChange,
+    Hangup
+}
+
+/// The configuration new requests are served with. A reload swaps it as a whole, requests that
+/// already started finish with the one they started with.
+pub struct LiveSettings {
+    directory: PathBuf,
+    overrides: Vec<(&'static str, String)>,
+    current: RwLock<Data<Settings>>
+}
+
+impl LiveSettings {
+    pub fn new(settings: Settings, directory: PathBuf, overrides: Vec<(&'static str, String)>) -> Self {
+        LiveSettings { directory, overrides, current: RwLock::new(Data::new(settings)) }
+    }
+
+    pub fn current(&self) -> Data<Settings> {
+        self.current.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Loads the configuration again the same way it was loaded at startup. An invalid one is
+    /// logged and rejected, the current one stays in place.
+    fn reload(&self, trigger: Trigger) {
+        let settings = match Settings::new(&self.directory, &self.overrides) {
+            Ok(settings) => settings,
+            Err(error) => {
+                error!("Rejected the configuration reloaded on {:?}, keeping the current one: {}", trigger, error);
+                return
+            }
+        };
+
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        let changes = diff(&current, &settings);
+        if changes.is_empty() {
+            // Secrets are redacted in the diff, a changed one has to be swapped in all the same
+            debug!("Configuration reloaded on {:?} has no visible changes", trigger);
+        } else {
+            info!("Reloaded the configuration on {:?}, {} setting(s) changed", trigger, changes.len());
+        }
+        for (key, (old, new)) in &changes {
+            info!("  {}: {} -> {}", key, old, new);
+        }
+        for setting in RESTART_REQUIRED {
+            if changes.keys().any(|key| key == setting || key.starts_with(&format!("{}.", setting))) {
+                warn!("Changes to `{}` only take effect after a restart", setting);
+            }
+        }
+        logging::set_format(*settings.get_logging().get_format());
+        *current = Data::new(settings);
+    }
+}
+
+// Turns the settings into `tftp.port`, `auth.api_keys[0].name` and the like, secrets stay redacted
+fn flatten(prefix: String, value: &Value, settings: &mut BTreeMap<String, String>) {
+    match value {
+        Value::Object(fields) if !fields.is_empty() => {
+            for (name, field) in fields {
+                let key = if prefix.is_empty() { name.to_owned() } else { format!("{}.{}", prefix, name) };
+                flatten(key, field, settings);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (index, item) in items.iter().enumerate() {
+                flatten(format!("{}[{}]", prefix, index), item, settings);
+            }
+        }
+        _ => {
+            settings.insert(prefix, value.to_string());
+        }
+    }
+}
+
+fn diff(old: &Settings, new: &Settings) -> BTreeMap<String, (String, String)> {
+    let (mut before, mut after) = (BTreeMap::new(), BTreeMap::new());
+    flatten(String::new(), &serde_json::to_value(old).unwrap_or_default(), &mut before);
+    flatten(String::new(), &serde_json::to_value(new).unwrap_or_default(), &mut after);
+
+    let mut changes = BTreeMap::new();
+    for key in before.keys().chain(after.keys()) {
+        let (old, new) = (before.get(key), after.get(key));
+        if old != new {
+            let unset = || "unset".to_owned();
+            changes.insert(key.to_owned(), (old.cloned().unwrap_or_else(unset), new.cloned().unwrap_or_else(unset)));
+        }
+    }
+    changes
+}
+
+async fn reload_on(settings: Arc<LiveSettings>, mut triggers: UnboundedReceiver<Trigger>) {
+    while let Some(trigger) = triggers.recv().await {
+        if let Trigger::FileChange = trigger {
+            sleep(SETTLE).await;
+            // Everything that changed meanwhile is picked up by this reload
+            while triggers.try_recv().is_ok() {}
+        }
+        settings.reload(trigger);
+    }
+}
+
+/// Reloads the configuration on `SIGHUP` and when a file in its directory changes. Changes to
+/// the files are no longer noticed once the returned watcher is dropped.
+pub fn watch(settings: Arc<LiveSettings>) -> notify::Result<RecommendedWatcher> {
+    let (sender, receiver) = unbounded_channel();
+    let directory = settings.directory.clone();
+    rt::spawn(reload_on(settings, receiver));
+
+    match signal(SignalKind::hangup()) {
+        Ok(mut hangups) => {
+            let sender = sender.clone();
+            rt::spawn(async move {
+                while hangups.recv().await.is_some() {
+                    if sender.send(Trigger::Hangup).is_err() {
+                        break
+                    }
+                }
+            });
+        }
+        Err(error) => warn!("Unable to listen for SIGHUP: {}", error)
+    }
+
+    let mut watcher = recommended_watcher(move |event: notify::Result<Event>| match event {
+        Ok(event) if !event.kind.is_access() => {
+            let _ = sender.send(Trigger::FileChange);
+        }
+        Ok(_) => {}
+        Err(error) => warn!("Unable to watch the configuration: {}", error)
+    })?;
+    // The directory rather than the files, Kubernetes updates a mounted ConfigMap by swapping a symlink
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+    info!("Watching {} for configuration changes", directory.display());
+    Ok(watcher)
+}
+
+/// Hands every request the settings that are current when it arrives. It has to wrap everything
+/// that reads `Data<Settings>`, the authentication included.
+pub struct CurrentSettings {
+    settings: Arc<LiveSettings>
+}
+
+impl CurrentSettings {
+    pub fn new(settings: Arc<LiveSettings>) -> Self {
+        CurrentSettings { settings }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CurrentSettings
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = CurrentSettingsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CurrentSettingsMiddleware { service: Rc::new(service), settings: self.settings.clone() }))
+    }
+}
+
+pub struct CurrentSettingsMiddleware<S> {
+    service: Rc<S>,
+    settings: Arc<LiveSettings>
+}
+
+impl<S, B> Service<ServiceRequest> for CurrentSettingsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    forward_ready!(service);
+
+    fn call(&self, mut request: ServiceRequest) -> Self::Future {
+        // Data added last takes precedence over what the App was built with
+        let mut container = Extensions::new();
+        container.insert(self.settings.current());
+        request.add_data_container(Rc::new(container));
+        self.service.call(request)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(port: u16, password: &str) -> Settings {
+        serde_json::from_value(json!({
+            "server": { "address": "127.0.0.1", "port": port },
+            "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": password }
+        })).unwrap()
+    }
+
+    fn write_config(directory: &Path, password: &str) {
+        let config = format!(
+            "[server]\naddress = \"127.0.0.1\"\nport = 8080\n\n[unifi]\nbase_url = \"https://unifi.local\"\nusername = \"kms\"\npassword = \"{}\"",
+            password
+        );
+        fs::write(directory.join("default.toml"), config).unwrap();
+    }
+
+    #[test]
+    fn flattens_fields_and_items_into_keys() {
+        let mut flattened = BTreeMap::new();
+        flatten(String::new(), &json!({ "tftp": { "port": 69 }, "auth": { "api_keys": [{ "name": "ci" }], "roles": [] } }), &mut flattened);
+        assert_eq!(flattened.get("tftp.port").map(String::as_str), Some("69"));
+        assert_eq!(flattened.get("auth.api_keys[0].name").map(String::as_str), Some("\"ci\""));
+        // Empty collections are kept, so adding the first item shows up as a change
+        assert_eq!(flattened.get("auth.roles").map(String::as_str), Some("[]"));
+        assert_eq!(flattened.len(), 3);
+    }
+
+    #[test]
+    fn lists_changed_settings() {
+        let changes = diff(&settings(8080, "secret"), &settings(8081, "secret"));
+        assert_eq!(changes.get("server.port"), Some(&("8080".to_owned(), "8081".to_owned())));
+        assert_eq!(changes.len(), 1);
+    }
+
+    #[test]
+    fn keeps_secrets_out_of_the_diff() {
+        let changes = diff(&settings(8080, "old"), &settings(8080, "new"));
+        assert!(changes.is_empty(), "{:?}", changes);
+    }
+
+    #[test]
+    fn swaps_in_a_changed_secret() {
+        let directory = std::env::temp_dir().join(format!("kms-reload-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        write_config(&directory, "old");
+        let live = LiveSettings::new(Settings::new(&directory, &[]).unwrap(), directory.clone(), Vec::new());
+
+        write_config(&directory, "new");
+        live.reload(Trigger::Hangup);
+        assert_eq!(live.current().get_unifi().get_password().expose().unwrap(), "new");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,94 @@
+use std::{future::{ready, Future, Ready}, pin::Pin, rc::Rc, time::Instant};
+
+use actix_web::{dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform}, http::header::{HeaderName, HeaderValue}, Error, HttpMessage};
+use rand::{thread_rng, Rng};
+
+use crate::logging::{self, Access};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request being handled, for log lines and anything else that runs inside the
+/// request's task. `None` outside of requests, e.g. in the TFTP and ProxyDHCP servers.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.to_owned()).ok()
+}
+
+/// Kept in the request extensions for handlers and error responses.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+// IDs end up in log lines, so only a conservative set of characters is taken from clients
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+fn request_id(request: &ServiceRequest) -> String {
+    match request.headers().get(REQUEST_ID_HEADER).and_then(|value| value.to_str().ok()) {
+        Some(id) if is_valid(id) => id.to_owned(),
+        _ => format!("{:032x}", thread_rng().gen::<u128>())
+    }
+}
+
+/// Takes the request ID from the `X-Request-Id` header or makes one up, returns it in the
+/// response header of the same name and writes the access log line once the response is ready.
+pub struct RequestTracing;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestTracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTracingMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: Rc<S>
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let id = request_id(&request);
+        request.extensions_mut().insert(RequestId(id.clone()));
+        let method = request.method().to_string();
+        let path = request.path().to_owned();
+        let started = Instant::now();
+
+        Box::pin(REQUEST_ID.scope(id.clone(), async move {
+            let mut result = service.call(request).await;
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(error) => error.as_response_error().status_code()
+            };
+            logging::access(&Access { method, path, status: status.as_u16(), duration_ms: started.elapsed().as_millis() });
+
+            if let (Ok(response), Ok(value)) = (&mut result, HeaderValue::from_str(&id)) {
+                response.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            result
+        }))
+    }
+}
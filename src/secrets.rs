@@ -0,0 +1,190 @@
+use std::{env, fmt, fs, io, path::PathBuf, sync::{Arc, Mutex}, time::SystemTime};
+
+use log::info;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use validator::ValidationError;
+
+const REDACTED: &str = "<redacted>";
+
+#[derive(Clone)]
+enum Source {
+    Value(String),
+    File(PathBuf)
+}
+
+/// A credential from the configuration. Inline values may reference environment variables as
+/// `${NAME}`; the `*_file` settings point at a file instead, such as a mounted Kubernetes secret.
+/// Files are read again once they change, so a rotated secret is picked up without a restart.
+/// Neither `Debug` nor `Serialize` ever show the secret itself.
+#[derive(Clone)]
+pub struct Secret {
+    source: Source,
+    // Modification time and content of the file when it was last read
+    cache: Arc<Mutex<Option<(SystemTime, String)>>>
+}
+
+impl Secret {
+    pub fn value(value: impl Into<String>) -> Secret {
+        Secret { source: Source::Value(value.into()), cache: Arc::default() }
+    }
+
+    pub fn file(path: impl Into<PathBuf>) -> Secret {
+        Secret { source: Source::File(path.into()), cache: Arc::default() }
+    }
+
+    /// The secret in plain text. Files end with a newline more often than not, it isn't part of
+    /// the secret.
+    pub fn expose(&self) -> io::Result<String> {
+        let path = match &self.source {
+            Source::Value(value) => return Ok(value.to_owned()),
+            Source::File(path) => path
+        };
+        let modified = fs::metadata(path)?.modified()?;
+        let mut cache = self.cache.lock().unwrap_or_else(|error| error.into_inner());
+        if let Some((read_at, secret)) = cache.as_ref() {
+            if *read_at == modified {
+                return Ok(secret.to_owned())
+            }
+            info!("Secret file {} changed, reloading it", path.display());
+        }
+        let secret = fs::read_to_string(path)?.trim_end_matches(['\r', '\n']).to_owned();
+        *cache = Some((modified, secret.clone()));
+        Ok(secret)
+    }
+}
+
+impl Default for Secret {
+    fn default() -> Self {
+        Secret::value("")
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Source::Value(_) => write!(f, "Secret({})", REDACTED),
+            Source::File(path) => write!(f, "Secret(file {})", path.display())
+        }
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+/// Replaces every `${NAME}` with the environment variable `NAME`, which has to be set.
+fn interpolate(value: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..].find('}').ok_or_else(|| format!("`{}` has an unclosed ${{", value))? + start;
+        let name = &rest[start + 2..end];
+        let variable = env::var(name).map_err(|_| format!("environment variable {} is not set", name))?;
+        result.push_str(&rest[..start]);
+        result.push_str(&variable);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        interpolate(&value).map(Secret::value).map_err(de::Error::custom)
+    }
+}
+
+/// Validator for credentials: they have to be set and, when they come from a file, readable.
+pub fn validate_secret(secret: &Secret) -> Result<(), ValidationError> {
+    match secret.expose() {
+        Ok(value) if value.is_empty() => Err(ValidationError::new("required")),
+        Ok(_) => Ok(()),
+        Err(error) => {
+            let mut validation_error = ValidationError::new("unreadable");
+            validation_error.message = Some(format!("cannot be read: {}", error).into());
+            Err(validation_error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use super::*;
+
+    // A fresh file per test, tests run in parallel
+    fn secret_file(name: &str, content: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("kms-secrets-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn touch(path: &Path, content: &str, modified: SystemTime) {
+        fs::write(path, content).unwrap();
+        fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn interpolates_environment_variables() {
+        env::set_var("KMS_TEST_SECRETS_USER", "kms");
+        env::set_var("KMS_TEST_SECRETS_PASSWORD", "hunter2");
+        assert_eq!(interpolate("${KMS_TEST_SECRETS_USER}:${KMS_TEST_SECRETS_PASSWORD}@unifi").unwrap(), "kms:hunter2@unifi");
+        assert_eq!(interpolate("no variables").unwrap(), "no variables");
+
+        let secret: Secret = serde_json::from_value(serde_json::json!("${KMS_TEST_SECRETS_PASSWORD}")).unwrap();
+        assert_eq!(secret.expose().unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn refuses_unset_variables() {
+        assert_eq!(interpolate("${KMS_TEST_SECRETS_UNSET}").unwrap_err(), "environment variable KMS_TEST_SECRETS_UNSET is not set");
+        assert!(interpolate("${KMS_TEST_SECRETS_USER").unwrap_err().contains("unclosed"));
+        assert!(serde_json::from_value::<Secret>(serde_json::json!("${KMS_TEST_SECRETS_UNSET}")).is_err());
+    }
+
+    #[test]
+    fn reads_files_again_once_they_change() {
+        let path = secret_file("rotated", "first\n");
+        let secret = Secret::file(&path);
+        assert_eq!(secret.expose().unwrap(), "first");
+
+        // Same modification time, the cached secret is kept
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        touch(&path, "unseen\n", modified);
+        assert_eq!(secret.expose().unwrap(), "first");
+
+        touch(&path, "second\r\n", modified + Duration::from_secs(1));
+        assert_eq!(secret.expose().unwrap(), "second");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn never_shows_the_secret() {
+        let secret = Secret::value("hunter2");
+        assert_eq!(format!("{:?}", secret), "Secret(<redacted>)");
+        assert_eq!(serde_json::to_string(&secret).unwrap(), "\"<redacted>\"");
+
+        let path = secret_file("redacted", "hunter2");
+        let secret = Secret::file(&path);
+        assert_eq!(format!("{:?}", secret), format!("Secret(file {})", path.display()));
+        assert_eq!(serde_json::to_string(&secret).unwrap(), "\"<redacted>\"");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn validates_secrets() {
+        assert!(validate_secret(&Secret::value("hunter2")).is_ok());
+        assert_eq!(validate_secret(&Secret::default()).unwrap_err().code, "required");
+
+        let path = secret_file("empty", "\n");
+        assert_eq!(validate_secret(&Secret::file(&path)).unwrap_err().code, "required");
+        fs::remove_file(&path).unwrap();
+        let error = validate_secret(&Secret::file(&path)).unwrap_err();
+        assert_eq!(error.code, "unreadable");
+        assert!(error.message.unwrap().starts_with("cannot be read: "));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,188 @@
+use std::{io, sync::Arc, time::Duration};
+
+use actix_web::{App, HttpServer, middleware::ErrorHandlers, rt::{self, time::Instant}, web::Data};
+use log::{error, info, warn};
+use paperclip::actix::{OpenApiExt, web::{get, post, resource, scope, ServiceConfig}};
+use serde_json::Value;
+
+use crate::{auth, errors, health, metrics, proxydhcp::ProxyDhcpServer, reload::{self, LiveSettings}, request_id, shutdown::{self, Workers}, store::Store, telemetry, tftp::TftpServer, tls, v1::{self, boot::models::assets::ChecksumCache}};
+
+/// Mounts every route of the API, for the server as well as for exporting its OpenAPI spec.
+pub fn routes(config: &mut ServiceConfig) {
+    config
+        .service(health::get_health)
+        .service(health::get_readiness)
+        .service(metrics::get_metrics)
+        .service(
+            scope("/v1/devices")
+                .service(v1::devices::routes::list_devices)
+                .service(v1::devices::routes::get_device_by_mac)
+                .service(v1::devices::routes::register_device)
+                .service(v1::devices::routes::delete_device)
+                .service(v1::devices::routes::assign_cluster)
+                .service(v1::devices::routes::unassign_cluster)
+                .service(v1::devices::routes::set_device_state)
+                .service(v1::devices::routes::set_device_inventory)
+                .service(v1::devices::routes::create_signed_url)
+                .service(v1::devices::routes::list_clients)
+                .service(v1::devices::routes::reconcile_devices)
+        )
+        .service(
+            scope("/v1/ipam")
+                .service(v1::ipam::routes::list_pools)
+                .service(v1::ipam::routes::get_pool)
+                .service(v1::ipam::routes::create_pool)
+                .service(v1::ipam::routes::delete_pool)
+        )
+        .service(
+            scope("/v1/provision")
+                .wrap(v1::provision::access::ProvisioningAccess)
+                .service(v1::provision::routes::get_provisioning_context)
+                .service(v1::provision::routes::get_network_config)
+                .service(v1::provision::routes::get_kubeadm_config)
+                .service(v1::provision::routes::get_talos_config)
+                .service(v1::provision::routes::get_template)
+                .service(v1::provision::routes::phone_home)
+        )
+        .service(
+            scope("/v1/tokens")
+                .service(resource("").route(get().to(v1::tokens::routes::list_tokens)).route(post().to(v1::tokens::routes::create_token)))
+                .service(resource("/").route(get().to(v1::tokens::routes::list_tokens)).route(post().to(v1::tokens::routes::create_token)))
+                .service(v1::tokens::routes::rotate_token)
+                .service(v1::tokens::routes::revoke_token)
+        )
+        .service(
+            scope("/v1/clusters")
+                .service(resource("").route(get().to(v1::clusters::routes::list_clusters)).route(post().to(v1::clusters::routes::create_cluster)))
+                .service(resource("/").route(get().to(v1::clusters::routes::list_clusters)).route(post().to(v1::clusters::routes::create_cluster)))
+                .service(v1::clusters::routes::get_cluster)
+                .service(v1::clusters::routes::update_cluster)
+                .service(v1::clusters::routes::delete_cluster)
+                .service(v1::clusters::routes::list_cluster_nodes)
+                .service(v1::clusters::routes::set_talos_secrets)
+                .service(v1::clusters::routes::delete_talos_secrets)
+                .service(v1::clusters::routes::set_kubernetes_access)
+                .service(v1::clusters::routes::delete_kubernetes_access)
+        )
+        .service(
+            scope("/v1/boot")
+                .service(v1::boot::routes::get_ipxe_script)
+                .service(v1::boot::routes::get_asset_manifest)
+                .service(v1::boot::routes::get_asset_checksums)
+                .service(v1::boot::routes::get_asset)
+        )
+        .service(
+            scope("/v1/api-keys")
+                .service(resource("").route(get().to(v1::api_keys::routes::list_api_keys)).route(post().to(v1::api_keys::routes::create_api_key)))
+                .service(resource("/").route(get().to(v1::api_keys::routes::list_api_keys)).route(post().to(v1::api_keys::routes::create_api_key)))
+                .service(v1::api_keys::routes::delete_api_key)
+        )
+        .service(
+            scope("/v1/templates")
+                .service(v1::templates::routes::render_template)
+        )
+        .service(
+            scope("/v1/animals")
+                .service(v1::animals::routes::get_dog)
+                .service(v1::animals::routes::create_dog)
+        );
+}
+
+/// The OpenAPI spec of [`routes`], built without binding anything.
+pub fn openapi_spec() -> Value {
+    let mut spec = Value::Null;
+    App::new()
+        .wrap_api()
+        .configure(routes)
+        .with_raw_json_spec(|app, built| {
+            spec = built;
+            app
+        })
+        .build();
+    spec
+}
+
+/// Runs the HTTP API until it is stopped, along with the TFTP and ProxyDHCP servers that are enabled.
+/// Requests are served with the latest valid configuration, the rest keeps the one it started with.
+/// On `SIGTERM` everything stops taking new work. Requests and TFTP transfers in flight get until
+/// the grace period ends to finish, ProxyDHCP is stopped right away.
+pub async fn serve(live: Arc<LiveSettings>) -> io::Result<()> {
+    let settings = live.current();
+    let store = match Store::open(settings.get_storage()) {
+        Ok(store) => Data::new(store),
+        Err(error) => {
+            error!("Storage Error: {}", error);
+            return Err(io::Error::other(error.to_string()))
+        }
+    };
+    let checksums = Data::new(ChecksumCache::default());
+    let jwks = Data::new(auth::oidc::JwksCache::default());
+    let health = Data::new(health::HealthCache::default());
+    let mut workers = Workers::default();
+    if *settings.get_tftp().get_enabled() {
+        let server = TftpServer::bind(live.clone()).await.map_err(|error| {
+            error!("TFTP Error: {}", error);
+            error
+        })?;
+        workers.add("TFTP", rt::spawn(server.run(workers.operations())));
+    }
+    if *settings.get_proxy_dhcp().get_enabled() {
+        let server = ProxyDhcpServer::bind(live.clone(), store.clone()).await.map_err(|error| {
+            error!("ProxyDHCP Error: {}", error);
+            error
+        })?;
+        workers.add("ProxyDHCP", rt::spawn(server.run()));
+    }
+
+    // Reloads still work through SIGHUP when the directory can't be watched
+    let watcher = reload::watch(live.clone()).map_err(|error| warn!("Unable to watch the configuration directory: {}", error)).ok();
+
+    let address = (settings.get_server().get_address().to_owned(), settings.get_server().get_port().to_owned());
+    let tls = settings.get_server().get_tls().to_owned();
+    let grace = Duration::from_secs(*settings.get_server().get_shutdown_grace_seconds());
+    let app_store = store.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(auth::Authentication)
+            .wrap(reload::CurrentSettings::new(live.clone()))
+            .wrap(ErrorHandlers::new().default_handler(errors::problem_response))
+            .wrap(telemetry::RequestSpans)
+            .wrap(request_id::RequestTracing)
+            .wrap(metrics::RequestMetrics)
+            .wrap_api()
+            .with_json_spec_at("/openapi.json")
+            .with_swagger_ui_at("/docs")
+            .app_data(app_store.clone())
+            .app_data(checksums.clone())
+            .app_data(jwks.clone())
+            .app_data(health.clone())
+            .configure(routes)
+            .build()
+    })
+    .on_connect(tls::on_connect)
+    .shutdown_timeout(grace.as_secs())
+    // Stopping is coordinated below, so the TFTP transfers drain alongside the requests
+    .disable_signals();
+
+    let server = match tls {
+        Some(tls) => server.bind_openssl(address, tls::acceptor(&tls)?)?,
+        None => server.bind(address)?
+    }
+    .run();
+    let handle = server.handle();
+    tokio::pin!(server);
+
+    let signal = tokio::select! {
+        result = &mut server => return result,
+        signal = shutdown::requested() => signal?
+    };
+    let deadline = Instant::now() + grace;
+    info!("Received {}, waiting up to {}s for work in flight", signal, grace.as_secs());
+    drop(watcher);
+    // The server has to be polled for the stop to get through to it
+    let (_, _, result) = tokio::join!(handle.stop(true), workers.stop(deadline), server);
+    // Requests abandoned after the grace period may be in the middle of a transaction
+    store.settle();
+    info!("Shut down");
+    result
+}
@@ -1,42 +1,543 @@
 use config::{Config, ConfigError, Environment, File};
 use derive_builder::Builder;
+use derive_more::{Display, Error};
 use getset::Getters;
-use log::{info, error};
-use serde::Deserialize;
+use ipnet::{Ipv4Net, Ipv6Net};
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationErrors, ValidationErrorsKind};
 
-use std::{net::Ipv4Addr, ops::RangeInclusive, env, process};
+use std::{collections::HashMap, net::{IpAddr, Ipv4Addr, Ipv6Addr}, ops::RangeInclusive, env, path::Path};
 
+use crate::{auth::Role, errors::describe, secrets::{validate_secret, Secret}, v1::ipam::models::pool::Pool};
 
-#[derive(Debug, Deserialize, Getters, Clone)]
+fn default_shutdown_grace_seconds() -> u64 {
+    // Kubernetes kills the pod 30 seconds after SIGTERM unless told otherwise
+    25
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
 #[allow(unused)]
 #[get = "pub with_prefix"]
 pub struct ServerSettings {
-    address: Ipv4Addr,
-    port: u16
+    address: IpAddr,
+    // Same bounds as PORT_RANGE, KMS doesn't need privileges to bind
+    #[validate(range(min = 1024, max = 65535))]
+    port: u16,
+    // Serve HTTPS instead of plain HTTP when set
+    #[validate]
+    tls: Option<TlsSettings>,
+    // How long requests and TFTP transfers in flight get to finish after SIGTERM
+    #[serde(default = "default_shutdown_grace_seconds")]
+    shutdown_grace_seconds: u64
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct TlsSettings {
+    // PEM files, the certificate file may hold the whole chain
+    #[validate(length(min = 1))]
+    certificate: String,
+    #[validate(length(min = 1))]
+    private_key: String,
+    // CA that issues node certificates. Clients may present one when set
+    client_ca: Option<String>,
+    #[serde(default)]
+    require_client_certificate: bool
 }
 
-#[derive(Debug, Deserialize, Getters, Clone)]
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
 #[allow(unused)]
 #[get = "pub with_prefix"]
 pub struct UnifiSettings {
+    #[validate(url)]
     base_url: String,
-    username: String,
-    password: String
+    #[serde(default)]
+    #[validate(custom = "validate_secret")]
+    username: Secret,
+    // Read from this file instead when set, e.g. a mounted Kubernetes secret
+    #[getset(skip)]
+    username_file: Option<String>,
+    #[serde(default)]
+    #[validate(custom = "validate_secret")]
+    password: Secret,
+    #[getset(skip)]
+    password_file: Option<String>
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct ReservedRangeSettings {
+    start: Ipv4Addr,
+    end: Ipv4Addr
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct PoolSettings {
+    #[validate(length(min = 1))]
+    name: String,
+    cidr: Ipv4Net,
+    gateway: Ipv4Addr,
+    #[serde(default)]
+    #[validate]
+    reserved: Vec<ReservedRangeSettings>,
+    #[serde(default)]
+    dns_servers: Vec<IpAddr>,
+    // Devices using SLAAC derive their address from this prefix, so it has to be a /64
+    ipv6_prefix: Option<Ipv6Net>,
+    ipv6_gateway: Option<Ipv6Addr>
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct IpamSettings {
+    #[serde(default)]
+    #[validate]
+    pools: Vec<PoolSettings>
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct StorageSettings {
+    // When unset the store only lives in memory and is lost on restart
+    #[validate(length(min = 1))]
+    path: Option<String>
+}
+
+fn default_token_ttl() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_token_max_uses() -> u32 {
+    1
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct TokenSettings {
+    // Lifetime of the per-node join tokens handed out with the kubeadm configuration
+    #[serde(default = "default_token_ttl")]
+    #[validate(range(min = 60))]
+    ttl_seconds: u64,
+    // How often a join token is handed out before the node gets a fresh one on its next fetch
+    #[serde(default = "default_token_max_uses")]
+    #[validate(range(min = 1))]
+    max_uses: u32
+}
+
+impl Default for TokenSettings {
+    fn default() -> Self {
+        TokenSettings { ttl_seconds: default_token_ttl(), max_uses: default_token_max_uses() }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct TemplateSettings {
+    // Directory with site specific templates, the built-in ones are used when unset
+    directory: Option<String>,
+    // URL installers reach KMS at, derived from the request when unset
+    #[validate(url)]
+    base_url: Option<String>,
+    // Free-form values exposed to templates as `vars`
+    #[serde(default)]
+    variables: HashMap<String, String>
+}
+
+fn default_tftp_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+fn default_tftp_port() -> u16 {
+    69
+}
+
+fn default_tftp_root() -> String {
+    "./tftp".to_owned()
+}
+
+fn default_tftp_block_size() -> u16 {
+    // Largest block that still fits a 1500 byte MTU without fragmentation
+    1468
+}
+
+fn default_tftp_timeout() -> u64 {
+    3
+}
+
+fn default_tftp_retries() -> u8 {
+    5
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct TftpSettings {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default = "default_tftp_address")]
+    address: IpAddr,
+    #[serde(default = "default_tftp_port")]
+    #[validate(range(min = 1))]
+    port: u16,
+    // Holds undionly.kpxe, ipxe.efi and whatever else PXE clients may ask for
+    #[serde(default = "default_tftp_root")]
+    root: String,
+    // Upper bound for the blksize option clients negotiate, RFC 2348 allows 8 to 65464
+    #[serde(default = "default_tftp_block_size")]
+    #[validate(range(min = 8, max = 65464))]
+    max_block_size: u16,
+    #[serde(default = "default_tftp_timeout")]
+    timeout_seconds: u64,
+    #[serde(default = "default_tftp_retries")]
+    retries: u8
+}
+
+impl Default for TftpSettings {
+    fn default() -> Self {
+        TftpSettings {
+            enabled: false,
+            address: default_tftp_address(),
+            port: default_tftp_port(),
+            root: default_tftp_root(),
+            max_block_size: default_tftp_block_size(),
+            timeout_seconds: default_tftp_timeout(),
+            retries: default_tftp_retries()
+        }
+    }
+}
+fn default_proxy_dhcp_address() -> Ipv4Addr {
+    Ipv4Addr::UNSPECIFIED
+}
+
+fn default_bios_filename() -> String {
+    "undionly.kpxe".to_owned()
+}
+
+fn default_uefi_filename() -> String {
+    "ipxe.efi".to_owned()
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct ProxyDhcpSettings {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default = "default_proxy_dhcp_address")]
+    address: Ipv4Addr,
+    // Address PXE clients load the boot file from, defaults to the server address
+    next_server: Option<Ipv4Addr>,
+    #[serde(default = "default_bios_filename")]
+    #[validate(length(min = 1))]
+    bios_filename: String,
+    #[serde(default = "default_uefi_filename")]
+    #[validate(length(min = 1))]
+    uefi_filename: String
+}
+
+impl Default for ProxyDhcpSettings {
+    fn default() -> Self {
+        ProxyDhcpSettings {
+            enabled: false,
+            address: default_proxy_dhcp_address(),
+            next_server: None,
+            bios_filename: default_bios_filename(),
+            uefi_filename: default_uefi_filename()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct BootProfileSettings {
+    #[validate(length(min = 1))]
+    name: String,
+    // Relative to the boot asset directory
+    #[validate(length(min = 1))]
+    directory: String,
+    // Asset name to file in the profile directory, every file is served under its own name when empty
+    #[serde(default)]
+    files: HashMap<String, String>
+}
+
+fn default_boot_directory() -> String {
+    "./assets".to_owned()
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct BootSettings {
+    #[serde(default = "default_boot_directory")]
+    #[validate(length(min = 1))]
+    directory: String,
+    #[serde(default)]
+    #[validate]
+    profiles: Vec<BootProfileSettings>
+}
+
+impl Default for BootSettings {
+    fn default() -> Self {
+        BootSettings { directory: default_boot_directory(), profiles: Vec::new() }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct ApiKeySettings {
+    #[validate(length(min = 1))]
+    name: String,
+    // Hex encoded SHA-256 of the key, the key itself never goes into the configuration
+    #[validate(length(equal = 64))]
+    sha256: String,
+    #[serde(default)]
+    role: Role,
+    // Limit the role to these clusters and sites, all of them when empty
+    #[serde(default)]
+    clusters: Vec<String>,
+    #[serde(default)]
+    sites: Vec<String>
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct GroupRoleSettings {
+    group: String,
+    role: Role,
+    #[serde(default)]
+    clusters: Vec<String>,
+    #[serde(default)]
+    sites: Vec<String>
+}
+
+fn default_jwks_cache_seconds() -> u64 {
+    60 * 60
+}
+
+fn default_username_claim() -> String {
+    "preferred_username".to_owned()
+}
+
+fn default_groups_claim() -> String {
+    "groups".to_owned()
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct OidcSettings {
+    #[validate(url)]
+    issuer: String,
+    // Checked against the `aud` claim when set
+    audience: Option<String>,
+    // One of the two is required, the file takes precedence
+    jwks_file: Option<String>,
+    #[validate(url)]
+    jwks_url: Option<String>,
+    #[serde(default = "default_jwks_cache_seconds")]
+    jwks_cache_seconds: u64,
+    // Falls back to `sub` when the claim is missing
+    #[serde(default = "default_username_claim")]
+    username_claim: String,
+    #[serde(default = "default_groups_claim")]
+    groups_claim: String,
+    #[serde(default)]
+    roles: Vec<GroupRoleSettings>
+}
+
+fn default_signed_url_seconds() -> u64 {
+    60 * 60
+}
+
+fn default_require_authentication() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct SigningKeySettings {
+    #[validate(length(min = 1))]
+    id: String,
+    // Secret for the HMAC of signed URLs
+    #[serde(default)]
+    #[validate(custom = "validate_secret")]
+    secret: Secret,
+    #[getset(skip)]
+    secret_file: Option<String>
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct ProvisioningSettings {
+    // Only serve provisioning data to a node presenting its client certificate or a signed URL.
+    // Turning this off hands join tokens and cluster keys to anyone who knows a MAC address
+    #[serde(default = "default_require_authentication")]
+    require_authentication: bool,
+    // The first key signs, all of them verify. Signed URLs are unavailable without keys
+    #[serde(default)]
+    #[validate]
+    signing_keys: Vec<SigningKeySettings>,
+    #[serde(default = "default_signed_url_seconds")]
+    signed_url_seconds: u64
+}
+
+impl Default for ProvisioningSettings {
+    fn default() -> Self {
+        ProvisioningSettings { require_authentication: default_require_authentication(), signing_keys: Vec::new(), signed_url_seconds: default_signed_url_seconds() }
+    }
+}
+
+fn default_auth_enabled() -> bool {
+    true
+}
+
+fn default_anonymous_paths() -> Vec<String> {
+    // Booting machines and Kubernetes probes can't present an API key
+    ["/docs", "/openapi.json", "/healthz", "/readyz", "/v1/provision", "/v1/boot"].iter().map(|path| path.to_string()).collect()
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct AuthSettings {
+    #[serde(default = "default_auth_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    #[validate]
+    api_keys: Vec<ApiKeySettings>,
+    #[validate]
+    oidc: Option<OidcSettings>,
+    // Path prefixes reachable without credentials
+    #[serde(default = "default_anonymous_paths")]
+    anonymous_paths: Vec<String>
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings { enabled: default_auth_enabled(), api_keys: Vec::new(), oidc: None, anonymous_paths: default_anonymous_paths() }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // One JSON object per line, for log shippers
+    Json
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Getters, Clone)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct LoggingSettings {
+    #[serde(default)]
+    format: LogFormat
 }
 
 
-#[derive(Clone, Debug, Deserialize, Getters, Builder)]
+fn default_service_name() -> String {
+    "kms".to_owned()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters, Clone, Validate)]
+#[allow(unused)]
+#[get = "pub with_prefix"]
+pub struct TracingSettings {
+    // OTLP/HTTP collector, e.g. http://localhost:4318/v1/traces
+    #[validate(url)]
+    otlp_endpoint: Option<String>,
+    // Appends finished spans to this file as JSON lines, for trying things out without a collector
+    file: Option<String>,
+    #[serde(default = "default_service_name")]
+    service_name: String,
+    // Share of traces started by KMS that are recorded, traces of callers follow their decision
+    #[serde(default = "default_sample_ratio")]
+    #[validate(range(min = 0.0, max = 1.0))]
+    sample_ratio: f64
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        TracingSettings { otlp_endpoint: None, file: None, service_name: default_service_name(), sample_ratio: default_sample_ratio() }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Getters, Builder, Validate)]
 #[allow(unused)]
 #[builder(setter(into))]
 #[get = "pub with_prefix"]
 pub struct Settings {
+    #[validate]
     server: ServerSettings,
-    unifi: UnifiSettings
+    #[validate]
+    unifi: UnifiSettings,
+    #[serde(default)]
+    #[builder(default)]
+    #[validate]
+    ipam: IpamSettings,
+    #[serde(default)]
+    #[builder(default)]
+    #[validate]
+    storage: StorageSettings,
+    #[serde(default)]
+    #[builder(default)]
+    #[validate]
+    tokens: TokenSettings,
+    #[serde(default)]
+    #[builder(default)]
+    #[validate]
+    templates: TemplateSettings,
+    #[serde(default)]
+    #[builder(default)]
+    #[validate]
+    tftp: TftpSettings,
+    #[serde(default)]
+    #[builder(default)]
+    #[validate]
+    boot: BootSettings,
+    #[serde(default)]
+    #[builder(default)]
+    #[validate]
+    proxy_dhcp: ProxyDhcpSettings,
+    #[serde(default)]
+    #[builder(default)]
+    #[validate]
+    auth: AuthSettings,
+    #[serde(default)]
+    #[builder(default)]
+    #[validate]
+    provisioning: ProvisioningSettings,
+    #[serde(default)]
+    #[builder(default)]
+    logging: LoggingSettings,
+    #[serde(default)]
+    #[builder(default)]
+    #[validate]
+    tracing: TracingSettings
 }
 
 const PORT_RANGE: RangeInclusive<usize> = 1024..=65535;
 
-fn validate_port(n: &str) -> Result<u16, String> {
+/// Parses a port KMS may bind to without privileges. Used for the configuration and `--port`.
+pub fn validate_port(n: &str) -> Result<u16, String> {
     let port: usize = n
         .parse()
         .map_err(|_| format!("`{n}` isn't a port number"))?;
@@ -52,43 +553,145 @@ fn validate_port(n: &str) -> Result<u16, String> {
     }
 }
 
+#[derive(Debug, Display, Error)]
+pub enum SettingsError {
+    #[display(fmt = "{}", _0)]
+    Load(ConfigError),
+    #[display(fmt = "{} invalid setting(s):\n  {}", "problems.len()", "problems.join(\"\\n  \")")]
+    Invalid { problems: Vec<String> }
+}
 
-impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
-        let stage = env::var("STAGE").unwrap_or_else(|_| "dev".into());
+impl From<ConfigError> for SettingsError {
+    fn from(error: ConfigError) -> Self {
+        SettingsError::Load(error)
+    }
+}
 
-        let config = Config::builder()
-            // Start off by merging in the "default" configuration file
-            .add_source(File::with_name("./config/default.toml"))
-            // Add in the current environment file
-            // Default to 'development' env
-            // Note that this file is _optional_
-            .add_source(
-                File::with_name(&format!("./config/{}", stage))
-                    .required(false),
-            )
-            // Add in settings from the environment (with a prefix of APP)
-            // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
-            .add_source(Environment::with_prefix("kms"))
-            .build()?;
-
-        let port = config.get::<String>("server.port");
-        match port {
-            Ok(port_num) => {
-                let is_valid = validate_port(port_num.as_str()).is_ok();
-
-                if (!is_valid) {
-                    error!("Configuration error: field \"port\" is not within range {}-{}", PORT_RANGE.start(), PORT_RANGE.end());
-                    process::exit(1);
+// Turns nested validation errors into lines like `auth.api_keys[0].sha256: has an invalid length`
+fn collect_problems(prefix: &str, errors: &ValidationErrors, problems: &mut Vec<String>) {
+    for (field, kind) in errors.errors() {
+        let path = if prefix.is_empty() { field.to_string() } else { format!("{}.{}", prefix, field) };
+        match kind {
+            ValidationErrorsKind::Field(errors) => problems.extend(errors.iter().map(|error| format!("{}: {}", path, describe(error)))),
+            ValidationErrorsKind::Struct(errors) => collect_problems(&path, errors, problems),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect_problems(&format!("{}[{}]", path, index), errors, problems);
                 }
             }
-            Err(error) => {
-                error!("Configuration Error: {}", error.to_string());
-                process::exit(1);
+        }
+    }
+}
+
+// A `*_file` setting takes precedence over the inline value, like `jwks_file` over `jwks_url`
+fn use_secret_file(secret: &mut Secret, file: &Option<String>) {
+    if let Some(path) = file {
+        *secret = Secret::file(path);
+    }
+}
+
+impl Settings {
+    fn use_secret_files(&mut self) {
+        use_secret_file(&mut self.unifi.username, &self.unifi.username_file);
+        use_secret_file(&mut self.unifi.password, &self.unifi.password_file);
+        for key in &mut self.provisioning.signing_keys {
+            use_secret_file(&mut key.secret, &key.secret_file);
+        }
+    }
+
+    /// Loads `default.toml` and the optional file named after `STAGE` from `directory`, then the
+    /// `KMS_` environment variables. `overrides` are `(key, value)` pairs, e.g. from command line
+    /// flags, that take precedence over all of them. Every invalid setting is reported at once.
+    pub fn new(directory: &Path, overrides: &[(&str, String)]) -> Result<Self, SettingsError> {
+        let stage = env::var("STAGE").unwrap_or_else(|_| "dev".into());
+
+        let mut builder = Config::builder()
+            .add_source(File::from(directory.join("default.toml")))
+            // Picks up dev.toml, prod.yaml and the like when present
+            .add_source(File::from(directory.join(&stage)).required(false))
+            .add_source(Environment::with_prefix("kms"));
+        for (key, value) in overrides {
+            builder = builder.set_override(*key, value.to_owned())?;
+        }
+        let mut settings: Settings = builder.build()?.try_deserialize()?;
+        settings.use_secret_files();
+
+        let mut problems = Vec::new();
+        if let Err(errors) = settings.validate() {
+            collect_problems("", &errors, &mut problems);
+        }
+        // The same checks pools created through the API go through
+        for (index, pool) in settings.ipam.pools.iter().enumerate() {
+            if let Err(errors) = Pool::from(pool).check() {
+                collect_problems(&format!("ipam.pools[{}]", index), &errors, &mut problems);
             }
         }
+        if !problems.is_empty() {
+            problems.sort();
+            problems.dedup();
+            return Err(SettingsError::Invalid { problems })
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    // A fresh directory per test, tests run in parallel
+    fn config_dir(name: &str, config: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("kms-settings-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("default.toml"), config).unwrap();
+        directory
+    }
+
+    const VALID: &str = "[server]\naddress = \"127.0.0.1\"\nport = 8080\n\n[unifi]\nbase_url = \"https://unifi.local\"\nusername = \"kms\"\npassword = \"secret\"\n";
+
+    #[test]
+    fn loads_valid_settings() {
+        let directory = config_dir("valid", VALID);
+        let settings = Settings::new(&directory, &[("server.port", "9090".to_owned())]).unwrap();
+        assert_eq!(*settings.get_server().get_port(), 9090);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let directory = config_dir("invalid", "\
+[server]
+address = \"127.0.0.1\"
+port = 80
+
+[unifi]
+base_url = \"unifi.local\"
+username = \"kms\"
+password = \"\"
+
+[[auth.api_keys]]
+name = \"ci\"
+sha256 = \"abc\"
 
-        // You can deserialize (and thus freeze) the entire configuration as
-        config.try_deserialize()
+[[ipam.pools]]
+name = \"lab\"
+cidr = \"10.0.0.0/24\"
+gateway = \"10.1.0.1\"
+");
+        let problems = match Settings::new(&directory, &[]) {
+            Err(SettingsError::Invalid { problems }) => problems,
+            other => panic!("expected invalid settings, got {:?}", other)
+        };
+        assert_eq!(problems, [
+            "auth.api_keys[0].sha256: must be exactly 64 characters long",
+            "ipam.pools[0].gateway: is out of range",
+            "server.port: must be between 1024 and 65535",
+            "unifi.base_url: must be a URL, e.g. https://example.com",
+            "unifi.password: is required"
+        ]);
+        fs::remove_dir_all(directory).unwrap();
     }
 }
\ No newline at end of file
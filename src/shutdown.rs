@@ -0,0 +1,83 @@
+use std::{io, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+
+use actix_web::rt::{signal::{ctrl_c, unix::{signal, SignalKind}}, task::JoinHandle, time::{sleep, Instant}};
+use log::{info, warn};
+
+// How often the drain looks whether operations are still running
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Resolves once the process is asked to stop, by the `SIGTERM` Kubernetes sends or by Ctrl-C.
+pub async fn requested() -> io::Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok("SIGTERM"),
+        result = ctrl_c() => result.map(|_| "SIGINT")
+    }
+}
+
+/// Counts operations that should finish before the process exits, such as TFTP transfers.
+#[derive(Clone, Default)]
+pub struct Operations {
+    running: Arc<AtomicUsize>
+}
+
+/// Marks an operation as running until it is dropped.
+pub struct Operation {
+    running: Arc<AtomicUsize>
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        self.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Operations {
+    pub fn start(&self) -> Operation {
+        self.running.fetch_add(1, Ordering::SeqCst);
+        Operation { running: self.running.clone() }
+    }
+
+    /// Waits for the running operations to finish, at most until `deadline`. Returns how many
+    /// are still running.
+    pub async fn drain(&self, deadline: Instant) -> usize {
+        loop {
+            let running = self.running.load(Ordering::SeqCst);
+            if running == 0 || Instant::now() >= deadline {
+                return running
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// The servers running next to the HTTP API. Their listeners are stopped on shutdown, what they
+/// already started is drained if it counts as one of the [`Operations`]. ProxyDHCP has nothing to
+/// drain, it answers every packet right away.
+#[derive(Default)]
+pub struct Workers {
+    listeners: Vec<(&'static str, JoinHandle<()>)>,
+    operations: Operations
+}
+
+impl Workers {
+    pub fn operations(&self) -> Operations {
+        self.operations.clone()
+    }
+
+    pub fn add(&mut self, name: &'static str, listener: JoinHandle<()>) {
+        self.listeners.push((name, listener));
+    }
+
+    /// Stops accepting new work and gives what's running until `deadline` to finish.
+    pub async fn stop(self, deadline: Instant) {
+        for (name, listener) in self.listeners {
+            listener.abort();
+            info!("Stopped the {} server", name);
+        }
+        match self.operations.drain(deadline).await {
+            0 => {}
+            left => warn!("Abandoning {} operation(s) still running after the grace period", left)
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::{collections::BTreeMap, fs::{self, File, OpenOptions, TryLockError}, io, path::PathBuf, sync::{RwLock, PoisonError}};
+
+use derive_more::{Display, Error, From};
+use log::{info, error};
+use opentelemetry::trace::SpanKind;
+use serde_derive::{Serialize, Deserialize};
+
+use crate::{metrics, telemetry, settings::StorageSettings, v1::{devices::models::device::Device, ipam::models::pool::Pool, clusters::models::{access::KubernetesAccess, cluster::Cluster, talos::TalosSecrets}, tokens::models::token::BootstrapToken, api_keys::models::api_key::ApiKey}};
+
+#[derive(Debug, Display, Error, From)]
+pub enum StoreError {
+    #[display(fmt = "Storage I/O error: {}", _0)]
+    Io(io::Error),
+    #[display(fmt = "Storage serialization error: {}", _0)]
+    Serialization(serde_json::Error),
+    #[display(fmt = "Store file {} is in use by another KMS process", "path.display()")]
+    #[from(ignore)]
+    Locked { path: PathBuf },
+    #[display(fmt = "Store was opened read-only")]
+    ReadOnly
+}
+
+/// Everything KMS keeps track of. Devices are keyed by their lowercase, colon separated MAC address,
+/// pools by name, everything about a cluster by the cluster's name, bootstrap tokens by their ID and
+/// API keys by name.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Tables {
+    #[serde(default)]
+    pub devices: BTreeMap<String, Device>,
+    #[serde(default)]
+    pub pools: BTreeMap<String, Pool>,
+    #[serde(default)]
+    pub clusters: BTreeMap<String, Cluster>,
+    #[serde(default)]
+    pub tokens: BTreeMap<String, BootstrapToken>,
+    // Kept apart from the clusters so listing clusters never exposes them
+    #[serde(default)]
+    pub talos_secrets: BTreeMap<String, TalosSecrets>,
+    #[serde(default)]
+    pub api_keys: BTreeMap<String, ApiKey>,
+    // Kept apart from the clusters for the same reason as the Talos secrets
+    #[serde(default)]
+    pub kubernetes_access: BTreeMap<String, KubernetesAccess>,
+    // Unix time the init node of a cluster fetched its kubeadm configuration. Tokens issued after
+    // that only reach the cluster through its API
+    #[serde(default)]
+    pub initialized_clusters: BTreeMap<String, u64>
+}
+
+/// In-memory store that is optionally written through to a JSON file after every change.
+pub struct Store {
+    path: Option<PathBuf>,
+    tables: RwLock<Tables>,
+    // Held while the store is open, so two processes never overwrite each other's changes
+    _lock: Option<File>,
+    read_only: bool
+}
+
+// Takes an exclusive lock on a file next to the store, the store file itself is replaced on every write
+fn lock(path: &PathBuf) -> Result<File, StoreError> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(path.with_extension("lock"))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(StoreError::Locked { path: path.to_owned() }),
+        Err(TryLockError::Error(error)) => Err(error.into())
+    }
+}
+
+// The store file is replaced as a whole on every write, so it can be read without the lock
+fn load(path: &Option<PathBuf>) -> Result<Tables, StoreError> {
+    match path {
+        Some(file) if file.exists() => {
+            info!("Loading store from {}", file.display());
+            Ok(serde_json::from_slice(&fs::read(file)?)?)
+        }
+        Some(file) => {
+            info!("Store file {} does not exist yet, starting empty", file.display());
+            Ok(Tables::default())
+        }
+        None => {
+            info!("No storage path configured, devices are kept in memory only");
+            Ok(Tables::default())
+        }
+    }
+}
+
+impl Store {
+    /// Loads the store file, if one is configured, and locks it until the store is dropped.
+    pub fn open(settings: &StorageSettings) -> Result<Store, StoreError> {
+        let path = settings.get_path().as_ref().map(PathBuf::from);
+        let lock = path.as_ref().map(lock).transpose()?;
+        let tables = load(&path)?;
+        Ok(Store { path, tables: RwLock::new(tables), _lock: lock, read_only: false })
+    }
+
+    /// Loads the store file without locking it or creating anything, for commands that only read
+    /// while a server may hold the store. Transactions on it fail.
+    pub fn open_read_only(settings: &StorageSettings) -> Result<Store, StoreError> {
+        let path = settings.get_path().as_ref().map(PathBuf::from);
+        let tables = load(&path)?;
+        Ok(Store { path, tables: RwLock::new(tables), _lock: None, read_only: true })
+    }
+
+    pub fn read<T>(&self, f: impl FnOnce(&Tables) -> T) -> T {
+        let _timer = metrics::store_timer("read");
+        let _span = telemetry::span("store.read", SpanKind::Internal);
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+        f(&tables)
+    }
+
+    /// Runs `f` against the tables while holding the write lock. The change is persisted when `f`
+    /// succeeds and rolled back when either `f` or persisting fails.
+    pub fn transaction<T, E>(&self, f: impl FnOnce(&mut Tables) -> Result<T, E>) -> Result<T, E>
+    where E: From<StoreError> {
+        let _timer = metrics::store_timer("transaction");
+        let _span = telemetry::span("store.transaction", SpanKind::Internal);
+        let mut tables = self.tables.write().unwrap_or_else(PoisonError::into_inner);
+        let snapshot = tables.clone();
+
+        let result = f(&mut tables).and_then(|value| {
+            self.persist(&tables)?;
+            Ok(value)
+        });
+
+        if result.is_err() {
+            *tables = snapshot;
+        }
+        result
+    }
+
+    /// Waits for a transaction that is being applied or written right now, by taking the lock it
+    /// holds. Afterwards the store file has every change a caller has been told about.
+    pub fn settle(&self) {
+        drop(self.tables.write().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    /// Checks that the store file could be written right now. Changes are written to a file next to
+    /// it and renamed over it, so its directory has to be writable as well.
+    pub fn check(&self) -> Result<(), StoreError> {
+        if let Some(path) = &self.path {
+            if path.exists() {
+                OpenOptions::new().append(true).open(path)?;
+            }
+            let probe = path.with_extension("probe");
+            fs::write(&probe, b"")?;
+            fs::remove_file(&probe)?;
+        }
+        Ok(())
+    }
+
+    fn persist(&self, tables: &Tables) -> Result<(), StoreError> {
+        if self.read_only {
+            return Err(StoreError::ReadOnly)
+        }
+        if let Some(path) = &self.path {
+            let _timer = metrics::store_timer("persist");
+            let _span = telemetry::span("store.persist", SpanKind::Internal);
+            // Write next to the target and rename so a crash never leaves a half written file
+            let temporary = path.with_extension("tmp");
+            fs::write(&temporary, serde_json::to_vec_pretty(tables)?)?;
+            fs::rename(&temporary, path).map_err(|err| {
+                error!("Unable to replace store file {}: {}", path.display(), err);
+                err
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{mpsc, Arc}, thread, time::Duration};
+
+    use eui48::MacAddress;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn settles_once_the_open_transaction_is_written() {
+        let path = std::env::temp_dir().join(format!("kms-store-{}-settle.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let settings: StorageSettings = serde_json::from_value(json!({ "path": path })).unwrap();
+        let store = Arc::new(Store::open(&settings).unwrap());
+
+        let (started, transaction_started) = mpsc::channel();
+        let writer = {
+            let store = store.clone();
+            thread::spawn(move || store.transaction(|tables| {
+                started.send(()).unwrap();
+                thread::sleep(Duration::from_millis(200));
+                let device = Device::new("node-1", MacAddress::parse_str("00:00:5e:00:53:01").unwrap(), "10.0.0.11".parse().unwrap(), None);
+                tables.devices.insert(device.get_mac_address().to_owned(), device);
+                Ok::<_, StoreError>(())
+            }))
+        };
+
+        transaction_started.recv().unwrap();
+        store.settle();
+        // Read straight from the file, what a restarted KMS would see
+        let tables: Tables = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert!(tables.devices.contains_key("00:00:5e:00:53:01"));
+
+        writer.join().unwrap().unwrap();
+        drop(store);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("lock")).unwrap();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,216 @@
+use std::{borrow::Cow, fs::{File, OpenOptions}, future::{ready, Future, Ready}, io::Write, pin::Pin, rc::Rc, time::{SystemTime, UNIX_EPOCH}};
+
+use actix_web::{dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform}, http::header::HeaderMap, Error};
+use log::info;
+use opentelemetry::{global::{self, BoxedSpan}, propagation::Extractor, runtime::TokioCurrentThread, sdk::{export::trace::{ExportResult, SpanData, SpanExporter}, propagation::TraceContextPropagator, trace::{self, Sampler, TracerProvider}, Resource}, trace::{FutureExt, SpanKind, Status, TraceContextExt, TraceError, Tracer}, Context, KeyValue};
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use serde_json::{json, Map, Value};
+
+use crate::{request_id, settings::TracingSettings};
+
+const TRACER: &str = "kms";
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_nanos()).unwrap_or(0)
+}
+
+/// Writes finished spans to a file, one JSON object per line.
+#[derive(Debug)]
+struct FileExporter {
+    file: File
+}
+
+impl FileExporter {
+    fn line(span: &SpanData) -> Value {
+        let attributes: Map<String, Value> = span.attributes.iter()
+            .map(|(key, value)| (key.as_str().to_owned(), Value::String(value.to_string())))
+            .collect();
+        json!({
+            "traceId": span.span_context.trace_id().to_string(),
+            "spanId": span.span_context.span_id().to_string(),
+            "parentSpanId": span.parent_span_id.to_string(),
+            "name": span.name,
+            "kind": format!("{:?}", span.span_kind),
+            "startTimeUnixNano": unix_nanos(span.start_time),
+            "endTimeUnixNano": unix_nanos(span.end_time),
+            "status": format!("{:?}", span.status),
+            "attributes": attributes
+        })
+    }
+}
+
+impl SpanExporter for FileExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        let result = batch.iter()
+            .try_for_each(|span| writeln!(self.file, "{}", FileExporter::line(span)))
+            .and_then(|_| self.file.flush())
+            .map_err(|error| TraceError::from(format!("Unable to write spans: {}", error)));
+        Box::pin(ready(result))
+    }
+}
+
+/// Installs the exporters that are configured. Without any, spans are never recorded and the
+/// instrumentation costs next to nothing.
+pub fn init(settings: &TracingSettings) -> Result<(), TraceError> {
+    if settings.get_otlp_endpoint().is_none() && settings.get_file().is_none() {
+        return Ok(())
+    }
+
+    let config = trace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(*settings.get_sample_ratio()))))
+        .with_resource(Resource::new(vec![KeyValue::new("service.name", settings.get_service_name().to_owned())]));
+    let mut builder = TracerProvider::builder().with_config(config);
+    if let Some(endpoint) = settings.get_otlp_endpoint() {
+        let exporter = SpanExporterBuilder::from(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint)).build_span_exporter()?;
+        builder = builder.with_batch_exporter(exporter, TokioCurrentThread);
+        info!("Exporting traces to {}", endpoint);
+    }
+    if let Some(path) = settings.get_file() {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|error| TraceError::from(format!("Unable to open trace file {}: {}", path, error)))?;
+        builder = builder.with_batch_exporter(FileExporter { file }, TokioCurrentThread);
+        info!("Writing traces to {}", path);
+    }
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(builder.build());
+    Ok(())
+}
+
+/// Exports the spans that are still buffered.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Starts a span below the one of the current request, if any. It ends when dropped.
+pub fn span(name: impl Into<Cow<'static, str>>, kind: SpanKind) -> BoxedSpan {
+    let tracer = global::tracer(TRACER);
+    tracer.span_builder(name).with_kind(kind).start(&tracer)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Opens a server span for every request, continuing the trace of the caller when it sends a
+/// `traceparent` header. Spans started while handling the request become its children.
+pub struct RequestSpans;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestSpans
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestSpansMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestSpansMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct RequestSpansMiddleware<S> {
+    service: Rc<S>
+}
+
+impl<S, B> Service<ServiceRequest> for RequestSpansMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let parent: Context = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(request.headers())));
+        let route = request.match_pattern().unwrap_or_else(|| "unmatched".to_owned());
+        let mut attributes = vec![
+            KeyValue::new("http.method", request.method().to_string()),
+            KeyValue::new("http.route", route.clone()),
+            KeyValue::new("http.target", request.path().to_owned())
+        ];
+        if let Some(id) = request_id::current() {
+            attributes.push(KeyValue::new("kms.request_id", id));
+        }
+        let tracer = global::tracer(TRACER);
+        let span = tracer.span_builder(format!("{} {}", request.method(), route))
+            .with_kind(SpanKind::Server)
+            .with_attributes(attributes)
+            .start_with_context(&tracer, &parent);
+        let context = parent.with_span(span);
+
+        Box::pin(async move {
+            let result = service.call(request).with_context(context.clone()).await;
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(error) => error.as_response_error().status_code()
+            };
+            let span = context.span();
+            span.set_attribute(KeyValue::new("http.status_code", i64::from(status.as_u16())));
+            if status.is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+            span.end();
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use actix_web::{test::{self, TestRequest}, web, App, HttpResponse};
+
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    async fn handler() -> HttpResponse {
+        drop(span("child", SpanKind::Internal));
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn writes_request_spans_to_the_file() {
+        let path = std::env::temp_dir().join(format!("kms-telemetry-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let settings: TracingSettings = serde_json::from_value(json!({ "file": path })).unwrap();
+        init(&settings).unwrap();
+
+        let app = test::init_service(App::new().wrap(RequestSpans).route("/things/{id}", web::get().to(handler))).await;
+        let request = TestRequest::get().uri("/things/1")
+            .insert_header(("traceparent", format!("00-{}-00f067aa0ba902b7-01", TRACE_ID)))
+            .to_request();
+        test::call_service(&app, request).await;
+        shutdown();
+
+        let spans: Vec<Value> = fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let server = spans.iter().find(|span| span["name"] == "GET /things/{id}").unwrap();
+        assert_eq!(server["traceId"], TRACE_ID);
+        assert_eq!(server["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(server["kind"], "Server");
+        assert_eq!(server["attributes"]["http.route"], "/things/{id}");
+        assert_eq!(server["attributes"]["http.target"], "/things/1");
+        assert_eq!(server["attributes"]["http.status_code"], "200");
+
+        let child = spans.iter().find(|span| span["name"] == "child" && span["traceId"] == TRACE_ID).unwrap();
+        assert_eq!(child["parentSpanId"], server["spanId"]);
+        fs::remove_file(path).unwrap();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,348 @@
+use std::{collections::HashMap, fmt, fs, io, net::Ipv4Addr, path::PathBuf};
+
+use actix_web::HttpRequest;
+use derive_more::{Display, Error, From};
+use eui48::MacAddress;
+use handlebars::{Handlebars, RenderError};
+use lazy_static::lazy_static;
+use log::info;
+use regex::Regex;
+use serde_derive::Serialize;
+
+use crate::{auth::signed_url, settings::{Settings, TemplateSettings}, v1::{devices::models::device::{Disk, HardwareInventory}, provision::models::context::ProvisioningContext}};
+
+lazy_static! {
+    // A single file name, so a template name can never point outside the template directory
+    pub static ref TEMPLATE_NAME_RE: Regex = Regex::new(r"^[A-Za-z0-9][A-Za-z0-9._-]*$").unwrap();
+}
+
+/// Templates shipped with KMS, used when no file on disk matches.
+const BUILTIN: [(&str, &str); 4] = [
+    ("boot.ipxe", include_str!("templates/boot.ipxe.hbs")),
+    ("kickstart.ks", include_str!("templates/kickstart.ks.hbs")),
+    ("meta-data", include_str!("templates/meta-data.hbs")),
+    ("user-data", include_str!("templates/user-data.hbs"))
+];
+
+#[derive(Debug, Display, Error, From)]
+pub enum TemplateError {
+    #[display(fmt = "Invalid template name {}", name)]
+    #[from(ignore)]
+    InvalidName { name: String },
+    #[display(fmt = "Unknown template {}", name)]
+    #[from(ignore)]
+    Unknown { name: String },
+    #[display(fmt = "Unable to read template: {}", _0)]
+    Io(io::Error),
+    #[display(fmt = "{}", _0)]
+    Render(RenderError)
+}
+
+/// Where a rendered template was loaded from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TemplateSource {
+    Builtin,
+    File(PathBuf)
+}
+
+impl fmt::Display for TemplateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateSource::Builtin => write!(f, "builtin"),
+            TemplateSource::File(path) => write!(f, "{}", path.display())
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SiteSettings {
+    base_url: String,
+    server_address: String,
+    server_port: u16
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Urls {
+    network_config: String,
+    kubeadm: String,
+    talos: String,
+    kickstart: String,
+    user_data: String,
+    // NoCloud seed, cloud-init fetches it with `meta-data`, `user-data` and `vendor-data` in place of `%s`
+    seed: String,
+    phone_home: String,
+    // `?exp=...&sig=...` to append to further provisioning URLs, empty without signing keys
+    query: String
+}
+
+/// IPv4 addressing derived from the device's pool, in the shapes installers tend to ask for.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Addressing {
+    prefix_length: u8,
+    netmask: Ipv4Addr,
+    gateway: Ipv4Addr,
+    dns_servers: Vec<String>
+}
+
+/// The data every template is rendered with. The provisioning context (`device`, `cluster`,
+/// `pool`, `nodes`) is flattened into the top level, `generated` holds the artifact KMS would
+/// serve itself when a template overrides one of the generated endpoints.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TemplateContext<'a> {
+    #[serde(flatten)]
+    provisioning: &'a ProvisioningContext,
+    inventory: Option<&'a HardwareInventory>,
+    install_disk: Option<&'a Disk>,
+    // Only present when the device belongs to a pool
+    network: Option<Addressing>,
+    settings: SiteSettings,
+    vars: &'a HashMap<String, String>,
+    urls: Urls,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generated: Option<String>
+}
+
+impl<'a> TemplateContext<'a> {
+    /// `base_url` is only used when the template settings do not configure one. The provisioning
+    /// URLs are signed when `sign` is set and keys are configured.
+    pub fn new(settings: &'a Settings, provisioning: &'a ProvisioningContext, base_url: &str, sign: bool) -> TemplateContext<'a> {
+        let base_url = settings.get_templates().get_base_url().as_deref().unwrap_or(base_url).trim_end_matches('/').to_owned();
+        let device = provisioning.get_device();
+        let provision = format!("{}/v1/provision/{}", base_url, device.get_mac_address());
+        // Installers fetch these without credentials, so they carry a signature when keys are configured
+        let signature = MacAddress::parse_str(device.get_mac_address()).ok().filter(|_| sign)
+            .and_then(|mac| signed_url::query(settings.get_provisioning(), &mac))
+            .map_or_else(String::new, |(query, _)| format!("?{}", query));
+        let inventory = device.get_inventory().as_ref();
+
+        TemplateContext {
+            provisioning,
+            inventory,
+            install_disk: inventory.and_then(|inventory| inventory.install_disk()),
+            network: provisioning.get_pool().as_ref().and_then(|pool| {
+                let network = pool.network().ok()?;
+                Some(Addressing {
+                    prefix_length: network.prefix_len(),
+                    netmask: network.netmask(),
+                    gateway: pool.get_gateway().to_owned(),
+                    dns_servers: pool.get_dns_servers().iter().map(|server| server.to_string()).collect()
+                })
+            }),
+            settings: SiteSettings {
+                base_url: base_url.clone(),
+                server_address: settings.get_server().get_address().to_string(),
+                server_port: *settings.get_server().get_port()
+            },
+            vars: settings.get_templates().get_variables(),
+            urls: Urls {
+                network_config: format!("{}/network-config{}", provision, signature),
+                kubeadm: format!("{}/kubeadm.yaml{}", provision, signature),
+                talos: format!("{}/talos.yaml{}", provision, signature),
+                kickstart: format!("{}/templates/kickstart.ks{}", provision, signature),
+                user_data: format!("{}/templates/user-data{}", provision, signature),
+                seed: format!("{}/templates/%s{}", provision, signature),
+                phone_home: format!("{}/phone-home{}", provision, signature),
+                query: signature
+            },
+            generated: None
+        }
+    }
+
+    pub fn with_generated(mut self, generated: String) -> TemplateContext<'a> {
+        self.generated = Some(generated);
+        self
+    }
+}
+
+/// The URL the client reached KMS at, as seen through any proxy headers.
+pub fn request_base_url(request: &HttpRequest) -> String {
+    let info = request.connection_info();
+    format!("{}://{}", info.scheme(), info.host())
+}
+
+pub struct TemplateEngine {
+    directory: Option<PathBuf>,
+    registry: Handlebars<'static>
+}
+
+impl TemplateEngine {
+    pub fn new(settings: &TemplateSettings) -> TemplateEngine {
+        let mut registry = Handlebars::new();
+        // Templates produce shell scripts and YAML, HTML escaping would only corrupt them
+        registry.register_escape_fn(handlebars::no_escape);
+        TemplateEngine { directory: settings.get_directory().as_ref().map(PathBuf::from), registry }
+    }
+
+    /// Candidate files for `name`, most specific first: the device, its cluster and role, the
+    /// cluster, the role and finally the template directory itself.
+    fn candidates(&self, name: &str, provisioning: &ProvisioningContext) -> Vec<PathBuf> {
+        let directory = match &self.directory {
+            Some(directory) => directory,
+            None => return Vec::new()
+        };
+        let device = provisioning.get_device();
+        let role = device.get_role().and_then(|role| serde_json::to_value(role).ok())
+            .and_then(|role| role.as_str().map(str::to_owned));
+
+        let mut candidates = vec![directory.join("devices").join(device.get_mac_address()).join(name)];
+        if let Some(cluster) = device.get_cluster() {
+            if let Some(role) = &role {
+                candidates.push(directory.join("clusters").join(cluster).join("roles").join(role).join(name));
+            }
+            candidates.push(directory.join("clusters").join(cluster).join(name));
+        }
+        if let Some(role) = &role {
+            candidates.push(directory.join("roles").join(role).join(name));
+        }
+        candidates.push(directory.join(name));
+        candidates
+    }
+
+    /// Looks up the file on disk that overrides `name` for this device, if any.
+    pub fn find(&self, name: &str, provisioning: &ProvisioningContext) -> Result<Option<(String, TemplateSource)>, TemplateError> {
+        if !TEMPLATE_NAME_RE.is_match(name) || name.contains("..") {
+            return Err(TemplateError::InvalidName { name: name.to_owned() })
+        }
+        for candidate in self.candidates(name, provisioning) {
+            if candidate.is_file() {
+                let template = fs::read_to_string(&candidate)?;
+                return Ok(Some((template, TemplateSource::File(candidate))))
+            }
+        }
+        Ok(None)
+    }
+
+    /// Finds the template for `name`, falling back to the built-in one.
+    pub fn resolve(&self, name: &str, provisioning: &ProvisioningContext) -> Result<(String, TemplateSource), TemplateError> {
+        if let Some(found) = self.find(name, provisioning)? {
+            return Ok(found)
+        }
+        BUILTIN.iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, template)| (template.to_string(), TemplateSource::Builtin))
+            .ok_or_else(|| TemplateError::Unknown { name: name.to_owned() })
+    }
+
+    pub fn render(&self, name: &str, context: &TemplateContext) -> Result<(String, TemplateSource), TemplateError> {
+        let (template, source) = self.resolve(name, context.provisioning)?;
+        info!("Rendering template {} from {} for {}", name, source, context.provisioning.get_device().get_mac_address());
+        Ok((self.registry.render_template(&template, context)?, source))
+    }
+
+    /// Renders the on-disk override of a generated artifact, or returns the generated artifact
+    /// unchanged when there is none. Overrides see the generated artifact as `generated`.
+    pub fn render_generated(&self, name: &str, context: TemplateContext, generated: String) -> Result<String, TemplateError> {
+        match self.find(name, context.provisioning)? {
+            Some((template, source)) => {
+                info!("Rendering {} override {} for {}", name, source, context.provisioning.get_device().get_mac_address());
+                Ok(self.registry.render_template(&template, &context.with_generated(generated))?)
+            }
+            None => Ok(generated)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use crate::{store::Tables, v1::{clusters::models::cluster::NodeRole, devices::models::device::Device}};
+    use super::*;
+
+    const MAC: &str = "00:00:5e:00:53:01";
+
+    // A fresh template directory per test, tests run in parallel
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("kms-templates-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn write(directory: &Path, path: &str, content: &str) {
+        let file = directory.join(path);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(file, content).unwrap();
+    }
+
+    fn settings(directory: &Path) -> Settings {
+        serde_json::from_value(json!({
+            "server": { "address": "127.0.0.1", "port": 8080 },
+            "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" },
+            "templates": { "directory": directory, "base_url": "http://kms.test" }
+        })).unwrap()
+    }
+
+    fn provisioning(settings: &Settings) -> ProvisioningContext {
+        let mut device = Device::new("node-1", MacAddress::parse_str(MAC).unwrap(), "10.0.0.11".parse().unwrap(), None);
+        device.set_cluster(Some("prod".to_owned()));
+        device.set_role(Some(NodeRole::Worker));
+        ProvisioningContext::for_device(settings, &Tables::default(), device)
+    }
+
+    fn source(engine: &TemplateEngine, provisioning: &ProvisioningContext) -> TemplateSource {
+        engine.resolve("kickstart.ks", provisioning).unwrap().1
+    }
+
+    #[test]
+    fn lists_candidates_most_specific_first() {
+        let directory = directory("candidates");
+        let settings = settings(&directory);
+        let engine = TemplateEngine::new(settings.get_templates());
+        let candidates: Vec<PathBuf> = ["devices/00:00:5e:00:53:01", "clusters/prod/roles/worker", "clusters/prod", "roles/worker", ""].iter()
+            .map(|parent| directory.join(parent).join("user-data"))
+            .collect();
+        assert_eq!(engine.candidates("user-data", &provisioning(&settings)), candidates);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn prefers_the_device_then_the_role_then_the_default() {
+        let directory = directory("lookup");
+        let settings = settings(&directory);
+        let engine = TemplateEngine::new(settings.get_templates());
+        let provisioning = provisioning(&settings);
+        assert_eq!(source(&engine, &provisioning), TemplateSource::Builtin);
+
+        write(&directory, "kickstart.ks", "default");
+        assert_eq!(source(&engine, &provisioning), TemplateSource::File(directory.join("kickstart.ks")));
+        write(&directory, "roles/worker/kickstart.ks", "role");
+        assert_eq!(source(&engine, &provisioning), TemplateSource::File(directory.join("roles/worker/kickstart.ks")));
+        write(&directory, "clusters/prod/roles/control-plane/kickstart.ks", "other role");
+        assert_eq!(source(&engine, &provisioning), TemplateSource::File(directory.join("roles/worker/kickstart.ks")));
+        write(&directory, &format!("devices/{}/kickstart.ks", MAC), "device");
+        assert_eq!(source(&engine, &provisioning), TemplateSource::File(directory.join("devices").join(MAC).join("kickstart.ks")));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn refuses_names_outside_the_directory() {
+        let directory = directory("names");
+        let settings = settings(&directory);
+        let engine = TemplateEngine::new(settings.get_templates());
+        let provisioning = provisioning(&settings);
+        assert!(matches!(engine.resolve("../secret", &provisioning), Err(TemplateError::InvalidName { .. })));
+        assert!(matches!(engine.resolve("missing", &provisioning), Err(TemplateError::Unknown { .. })));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn serves_the_generated_document_unless_overridden() {
+        let directory = directory("generated");
+        let settings = settings(&directory);
+        let engine = TemplateEngine::new(settings.get_templates());
+        let provisioning = provisioning(&settings);
+        let context = || TemplateContext::new(&settings, &provisioning, "http://ignored", false);
+        assert_eq!(engine.render_generated("network-config", context(), "version: 2\n".to_owned()).unwrap(), "version: 2\n");
+
+        write(&directory, "roles/worker/network-config", "# {{device.hostname}}\n{{generated}}");
+        assert_eq!(engine.render_generated("network-config", context(), "version: 2\n".to_owned()).unwrap(), "# node-1\nversion: 2\n");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
\ No newline at end of file
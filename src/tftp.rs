@@ -0,0 +1,398 @@
+use std::{fs, io, net::{IpAddr, SocketAddr}, path::{Component, Path, PathBuf}, sync::Arc, time::Duration};
+
+use actix_web::rt::{net::UdpSocket, spawn, time::timeout};
+use log::{info, warn, error};
+
+use crate::{reload::LiveSettings, settings::{Settings, TftpSettings}, shutdown::Operations, v1::boot::models::service};
+
+const RRQ: u16 = 1;
+const WRQ: u16 = 2;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+const OACK: u16 = 6;
+
+// Error codes from RFC 1350
+const FILE_NOT_FOUND: u16 = 1;
+const ACCESS_VIOLATION: u16 = 2;
+const ILLEGAL_OPERATION: u16 = 4;
+
+// Block size limits from RFC 2348
+const DEFAULT_BLOCK_SIZE: usize = 512;
+const MIN_BLOCK_SIZE: usize = 8;
+const MAX_BLOCK_SIZE: usize = 65464;
+
+// iPXE fetches this from the server it was loaded from when it has no embedded script
+const AUTOEXEC: &str = "autoexec.ipxe";
+
+struct ReadRequest {
+    filename: String,
+    mode: String,
+    options: Vec<(String, String)>
+}
+
+/// Parses the body of a RRQ: filename, mode and option pairs, each terminated by a zero byte.
+fn parse_request(body: &[u8]) -> Option<ReadRequest> {
+    if body.last() != Some(&0) {
+        return None
+    }
+    let mut fields = body[..body.len() - 1].split(|byte| *byte == 0).map(|field| String::from_utf8_lossy(field).into_owned());
+    let filename = fields.next().filter(|filename| !filename.is_empty())?;
+    let mode = fields.next()?;
+    let rest: Vec<String> = fields.collect();
+    let options = rest.chunks(2)
+        .filter(|pair| pair.len() == 2)
+        .map(|pair| (pair[0].to_ascii_lowercase(), pair[1].to_owned()))
+        .collect();
+    Some(ReadRequest { filename, mode, options })
+}
+
+fn packet(opcode: u16, number: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(4 + payload.len());
+    packet.extend_from_slice(&opcode.to_be_bytes());
+    packet.extend_from_slice(&number.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+async fn send_error(socket: &UdpSocket, code: u16, message: &str) -> io::Result<()> {
+    let mut payload = message.as_bytes().to_vec();
+    payload.push(0);
+    socket.send(&packet(ERROR, code, &payload)).await.map(|_| ())
+}
+
+struct Transfer {
+    block_size: usize,
+    timeout: Duration,
+    retries: u8,
+    // Options acknowledged back to the client, empty when it asked for none we support
+    acknowledged: Vec<(String, String)>
+}
+
+/// Applies the options of RFC 2347-2349 the client asked for. Unknown options are left out of
+/// the OACK, which is how TFTP declines them.
+fn negotiate(settings: &TftpSettings, options: &[(String, String)], file_size: usize) -> Transfer {
+    let mut transfer = Transfer {
+        block_size: DEFAULT_BLOCK_SIZE,
+        timeout: Duration::from_secs(*settings.get_timeout_seconds()),
+        retries: *settings.get_retries(),
+        acknowledged: Vec::new()
+    };
+    let max_block_size = (*settings.get_max_block_size() as usize).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+
+    for (name, value) in options {
+        match name.as_str() {
+            "blksize" => if let Ok(requested) = value.parse::<usize>() {
+                transfer.block_size = requested.clamp(MIN_BLOCK_SIZE, max_block_size);
+                transfer.acknowledged.push((name.to_owned(), transfer.block_size.to_string()));
+            }
+            "tsize" => transfer.acknowledged.push((name.to_owned(), file_size.to_string())),
+            "timeout" => if let Ok(seconds) = value.parse::<u64>() {
+                if (1..=255).contains(&seconds) {
+                    transfer.timeout = Duration::from_secs(seconds);
+                    transfer.acknowledged.push((name.to_owned(), value.to_owned()));
+                }
+            }
+            _ => {}
+        }
+    }
+    transfer
+}
+
+/// Only plain relative paths below the root are served. PXE ROMs of some vendors use
+/// backslashes and a leading slash, both are accepted. Symlinks are followed as long as they
+/// stay below the root.
+fn resolve(root: &Path, filename: &str) -> io::Result<PathBuf> {
+    let outside = || io::Error::new(io::ErrorKind::PermissionDenied, "path outside of the TFTP root");
+    let normalized = filename.replace('\\', "/");
+    let relative = Path::new(normalized.trim_start_matches('/'));
+    if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(outside())
+    }
+    let path = fs::canonicalize(root.join(relative))?;
+    if path.starts_with(fs::canonicalize(root)?) {
+        Ok(path)
+    } else {
+        Err(outside())
+    }
+}
+
+// Transfers read the settings current when they start, only the listening address is fixed
+struct Shared {
+    live: Arc<LiveSettings>,
+    address: IpAddr
+}
+
+impl Shared {
+    fn read(&self, settings: &Settings, filename: &str) -> io::Result<Vec<u8>> {
+        match resolve(Path::new(settings.get_tftp().get_root()), filename) {
+            Ok(path) => fs::read(path),
+            Err(error) if error.kind() == io::ErrorKind::NotFound && filename.trim_start_matches(['/', '\\']) == AUTOEXEC => Ok(service::chain_script(settings).into_bytes()),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Sends `packet` until the client acknowledges `block`, giving up after the configured
+    /// number of retries or when the client sends an error.
+    async fn send_until_acknowledged(&self, socket: &UdpSocket, packet: &[u8], block: u16, transfer: &Transfer) -> io::Result<bool> {
+        let mut buffer = [0u8; 516];
+        for _ in 0..=transfer.retries {
+            socket.send(packet).await?;
+            while let Ok(received) = timeout(transfer.timeout, socket.recv(&mut buffer)).await {
+                let length = received?;
+                if length < 4 {
+                    continue
+                }
+                match u16::from_be_bytes([buffer[0], buffer[1]]) {
+                    ACK if u16::from_be_bytes([buffer[2], buffer[3]]) == block => return Ok(true),
+                    ERROR => return Ok(false),
+                    // Duplicate ACKs of earlier blocks are ignored, answering them causes the
+                    // Sorcerer's Apprentice problem
+                    _ => {}
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    async fn handle(&self, request: &[u8], peer: SocketAddr) -> io::Result<()> {
+        let socket = UdpSocket::bind(SocketAddr::new(self.address, 0)).await?;
+        socket.connect(peer).await?;
+
+        if request.len() < 2 {
+            return Ok(())
+        }
+        match u16::from_be_bytes([request[0], request[1]]) {
+            RRQ => {}
+            WRQ => return send_error(&socket, ACCESS_VIOLATION, "Uploads are not supported").await,
+            _ => return send_error(&socket, ILLEGAL_OPERATION, "Expected a read request").await
+        }
+        let request = match parse_request(&request[2..]) {
+            Some(request) => request,
+            None => return send_error(&socket, ILLEGAL_OPERATION, "Malformed read request").await
+        };
+        // netascii is served byte for byte, every file KMS serves is binary anyway
+        if request.mode.eq_ignore_ascii_case("mail") {
+            return send_error(&socket, ILLEGAL_OPERATION, "Mail mode is not supported").await
+        }
+
+        let settings = self.live.current();
+        let contents = match self.read(&settings, &request.filename) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+                warn!("TFTP client {} requested {} outside of the root", peer, request.filename);
+                return send_error(&socket, ACCESS_VIOLATION, "Access violation").await
+            }
+            Err(error) => {
+                info!("TFTP client {} requested {}: {}", peer, request.filename, error);
+                return send_error(&socket, FILE_NOT_FOUND, "File not found").await
+            }
+        };
+        let transfer = negotiate(settings.get_tftp(), &request.options, contents.len());
+        info!("TFTP client {} requested {} ({} bytes, block size {})", peer, request.filename, contents.len(), transfer.block_size);
+
+        if !transfer.acknowledged.is_empty() {
+            let mut payload = Vec::new();
+            for (name, value) in &transfer.acknowledged {
+                payload.extend_from_slice(name.as_bytes());
+                payload.push(0);
+                payload.extend_from_slice(value.as_bytes());
+                payload.push(0);
+            }
+            let mut oack = OACK.to_be_bytes().to_vec();
+            oack.extend_from_slice(&payload);
+            if !self.send_until_acknowledged(&socket, &oack, 0, &transfer).await? {
+                warn!("TFTP client {} did not acknowledge the options for {}", peer, request.filename);
+                return Ok(())
+            }
+        }
+
+        let mut block: u16 = 0;
+        let mut offset = 0;
+        loop {
+            // Block numbers roll over for files larger than 65535 blocks, which iPXE and most ROMs accept
+            block = block.wrapping_add(1);
+            let end = (offset + transfer.block_size).min(contents.len());
+            if !self.send_until_acknowledged(&socket, &packet(DATA, block, &contents[offset..end]), block, &transfer).await? {
+                warn!("TFTP transfer of {} to {} was aborted", request.filename, peer);
+                return Ok(())
+            }
+            // A block shorter than the block size, possibly empty, ends the transfer
+            if end - offset < transfer.block_size {
+                return Ok(())
+            }
+            offset = end;
+        }
+    }
+}
+
+/// Read-only TFTP server for PXE ROMs that need to load iPXE before they can speak HTTP.
+pub struct TftpServer {
+    socket: UdpSocket,
+    shared: Arc<Shared>
+}
+
+impl TftpServer {
+    pub async fn bind(live: Arc<LiveSettings>) -> io::Result<TftpServer> {
+        let settings = live.current();
+        let tftp = settings.get_tftp();
+        let socket = UdpSocket::bind((*tftp.get_address(), *tftp.get_port())).await?;
+        info!("TFTP server serving {} on {}:{}", tftp.get_root(), tftp.get_address(), tftp.get_port());
+
+        Ok(TftpServer { socket, shared: Arc::new(Shared { live, address: *tftp.get_address() }) })
+    }
+
+    /// Every read request is answered from its own socket, as RFC 1350 requires, so slow
+    /// clients never hold up others. Transfers count as `operations` until they end.
+    pub async fn run(self, operations: Operations) {
+        let mut buffer = [0u8; 1024];
+        loop {
+            let (length, peer) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(error) => {
+                    error!("TFTP receive error: {}", error);
+                    continue
+                }
+            };
+            let request = buffer[..length].to_vec();
+            let shared = self.shared.clone();
+            let operation = operations.start();
+            spawn(async move {
+                let _operation = operation;
+                if let Err(error) = shared.handle(&request, peer).await {
+                    warn!("TFTP transfer to {} failed: {}", peer, error);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use actix_web::rt::time::{sleep, Instant};
+    use serde_json::json;
+
+    use crate::shutdown::Workers;
+    use super::*;
+
+    // A fresh directory per test, tests run in parallel
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("kms-tftp-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("root/efi")).unwrap();
+        fs::write(directory.join("root/efi/ipxe.efi"), b"ipxe").unwrap();
+        fs::write(directory.join("secret"), b"secret").unwrap();
+        directory
+    }
+
+    #[test]
+    fn parses_read_requests() {
+        let request = parse_request(b"undionly.kpxe\x00octet\x00blksize\x001468\x00TSIZE\x000\x00").unwrap();
+        assert_eq!(request.filename, "undionly.kpxe");
+        assert_eq!(request.mode, "octet");
+        assert_eq!(request.options, vec![("blksize".to_owned(), "1468".to_owned()), ("tsize".to_owned(), "0".to_owned())]);
+    }
+
+    #[test]
+    fn ignores_incomplete_options() {
+        let request = parse_request(b"ipxe.efi\0netascii\0blksize\0").unwrap();
+        assert_eq!(request.mode, "netascii");
+        assert!(request.options.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_read_requests() {
+        assert!(parse_request(b"").is_none());
+        assert!(parse_request(b"ipxe.efi\0octet").is_none());
+        assert!(parse_request(b"ipxe.efi\0").is_none());
+        assert!(parse_request(b"\0octet\0").is_none());
+    }
+
+    #[test]
+    fn resolves_files_below_the_root() {
+        let directory = directory("below");
+        let root = directory.join("root");
+        let expected = fs::canonicalize(root.join("efi/ipxe.efi")).unwrap();
+        assert_eq!(resolve(&root, "efi/ipxe.efi").unwrap(), expected);
+        assert_eq!(resolve(&root, "/efi/ipxe.efi").unwrap(), expected);
+        assert_eq!(resolve(&root, "\\efi\\ipxe.efi").unwrap(), expected);
+        assert_eq!(resolve(&root, "efi/missing.efi").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn refuses_paths_leaving_the_root() {
+        let directory = directory("leaving");
+        let root = directory.join("root");
+        for filename in ["../secret", "efi/../../secret", "..\\secret", "efi/../efi/ipxe.efi"] {
+            assert_eq!(resolve(&root, filename).unwrap_err().kind(), io::ErrorKind::PermissionDenied, "{}", filename);
+        }
+    }
+
+    #[test]
+    fn follows_symlinks_only_within_the_root() {
+        let directory = directory("symlinks");
+        let root = directory.join("root");
+        symlink(directory.join("secret"), root.join("escape")).unwrap();
+        symlink(directory.join("root/efi"), root.join("uefi")).unwrap();
+
+        assert_eq!(resolve(&root, "escape").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(resolve(&root, "uefi/ipxe.efi").unwrap(), fs::canonicalize(root.join("efi/ipxe.efi")).unwrap());
+    }
+
+    async fn receive(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buffer = [0u8; 1024];
+        let (length, peer) = timeout(Duration::from_secs(2), socket.recv_from(&mut buffer)).await.unwrap().unwrap();
+        (buffer[..length].to_vec(), peer)
+    }
+
+    #[actix_web::test]
+    async fn finishes_transfers_in_flight_when_stopped() {
+        let directory = directory("drain");
+        let contents: Vec<u8> = (0..1100u32).map(|byte| byte as u8).collect();
+        fs::write(directory.join("root/undionly.kpxe"), &contents).unwrap();
+        let settings: Settings = serde_json::from_value(json!({
+            "server": { "address": "127.0.0.1", "port": 8080 },
+            "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" },
+            "tftp": { "enabled": true, "address": "127.0.0.1", "port": 0, "root": directory.join("root") }
+        })).unwrap();
+        let server = TftpServer::bind(Arc::new(LiveSettings::new(settings, directory.clone(), Vec::new()))).await.unwrap();
+        let address = server.socket.local_addr().unwrap();
+        let mut workers = Workers::default();
+        workers.add("TFTP", spawn(server.run(workers.operations())));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"\x00\x01undionly.kpxe\x00octet\x00", address).await.unwrap();
+        let (first, transfer) = receive(&client).await;
+        assert_eq!(&first[..4], &[0, DATA as u8, 0, 1]);
+
+        let grace_period = Duration::from_secs(5);
+        let stopping = spawn(workers.stop(Instant::now() + grace_period));
+        sleep(Duration::from_millis(50)).await;
+
+        // The listener is gone, the transfer that already started goes on
+        client.send_to(b"\x00\x01undionly.kpxe\x00octet\x00", address).await.unwrap();
+        let mut received = first[4..].to_vec();
+        for block in 1..=3u16 {
+            // Stopping waits for the transfer until its last block is acknowledged
+            assert!(!stopping.is_finished());
+            client.send_to(&packet(ACK, block, &[]), transfer).await.unwrap();
+            if block < 3 {
+                let (data, peer) = receive(&client).await;
+                assert_eq!(peer, transfer);
+                assert_eq!(u16::from_be_bytes([data[2], data[3]]), block + 1);
+                received.extend_from_slice(&data[4..]);
+            }
+        }
+        assert_eq!(received, contents);
+
+        let started = Instant::now();
+        timeout(grace_period, stopping).await.unwrap().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        // Nothing answered the request sent after the stop
+        let mut buffer = [0u8; 1024];
+        assert!(timeout(Duration::from_millis(100), client.recv_from(&mut buffer)).await.is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,60 @@
+use std::{any::Any, io, str};
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use log::info;
+use openssl::{error::ErrorStack, nid::Nid, ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode}, x509::{X509NameRef, X509Ref}};
+
+use crate::settings::TlsSettings;
+
+/// The names of the verified certificate a client presented, its subject common name and DNS
+/// subject alternative names. Nodes are identified by hostname or MAC address in either.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    pub names: Vec<String>
+}
+
+// The raw bytes rather than a conversion that stops at an embedded NUL, "node1\0.evil" is not "node1"
+fn common_names(subject: &X509NameRef) -> impl Iterator<Item = String> + '_ {
+    subject.entries_by_nid(Nid::COMMONNAME).filter_map(|entry| str::from_utf8(entry.data().as_slice()).ok().map(str::to_owned))
+}
+
+fn certificate_names(certificate: &X509Ref) -> Vec<String> {
+    let alternative_names = certificate.subject_alt_names().into_iter().flatten()
+        .filter_map(|name| name.dnsname().map(str::to_owned));
+    common_names(certificate.subject_name()).chain(alternative_names).collect()
+}
+
+fn tls_error(error: ErrorStack) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+pub fn acceptor(settings: &TlsSettings) -> io::Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(tls_error)?;
+    builder.set_private_key_file(settings.get_private_key(), SslFiletype::PEM).map_err(tls_error)?;
+    builder.set_certificate_chain_file(settings.get_certificate()).map_err(tls_error)?;
+
+    if let Some(client_ca) = settings.get_client_ca() {
+        builder.set_ca_file(client_ca).map_err(tls_error)?;
+        // Without FAIL_IF_NO_PEER_CERT clients may still connect anonymously, but a certificate
+        // they do present has to be valid
+        let mode = if *settings.get_require_client_certificate() {
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT
+        } else {
+            SslVerifyMode::PEER
+        };
+        builder.set_verify(mode);
+        info!("Accepting client certificates issued by {}", client_ca);
+    }
+    Ok(builder)
+}
+
+/// Keeps the client certificate of TLS connections for the request handlers, which find it
+/// with `HttpRequest::conn_data`.
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    if let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        if let Some(certificate) = stream.ssl().peer_certificate() {
+            extensions.insert(ClientCertificate { names: certificate_names(&certificate) });
+        }
+    }
+}
@@ -1,2 +1,18 @@
 pub mod devices;
-pub mod animals;
\ No newline at end of file
+pub mod animals;
+pub mod ipam;
+pub mod provision;
+pub mod clusters;
+pub mod tokens;
+pub mod templates;
+pub mod boot;
+pub mod api_keys;
+
+use paperclip::actix::Apiv2Schema;
+use serde_derive::{Serialize, Deserialize};
+
+/// The envelope of every v1 response body.
+#[derive(Serialize, Deserialize, Apiv2Schema)]
+pub struct Response<T> {
+    pub data: T
+}
\ No newline at end of file
@@ -1,23 +1,24 @@
-use crate::v1::animals::models::{errors::{Errors, parse_validation_errors}, responses::{Response}, dog::{Dog}};
+use crate::{auth::Authenticated, errors::{Errors, parse_validation_errors}, v1::{Response, animals::models::dog::{Dog}}};
 use validator::Validate;
 use paperclip::actix::{web::Json, api_v2_operation, get, post};
 
-use actix_web::{Error, Result};
+use actix_web::Result;
+use log::debug;
 
 #[api_v2_operation]
 #[get("/dog")]
-pub async fn get_dog() -> Result<Json<Response<Dog>>, Error> {
+pub async fn get_dog(_auth: Authenticated) -> Result<Json<Response<Dog>>, Errors> {
     let data = Response {data: Dog::new("Labrador", "Black", true)};
 
-    println!("{}", &data.data.get_breed());
+    debug!("{}", &data.data.get_breed());
 
-    Err(Errors::NotFoundError.into())
+    Err(Errors::NotFoundError)
     //Ok(web::Json(data))
 }
 
 #[api_v2_operation]
 #[post("/dog")]
-pub async fn create_dog(dog_info: Json<Dog>) -> Result<Json<Response<Dog>>, Error> {
+pub async fn create_dog(_auth: Authenticated, dog_info: Json<Dog>) -> Result<Json<Response<Dog>>, Errors> {
     let mut dog = dog_info.0;
 
     dog.set_breed("Terrier".to_string());
@@ -32,7 +33,7 @@ pub async fn create_dog(dog_info: Json<Dog>) -> Result<Json<Response<Dog>>, Erro
             )
         ),
         Err(e) => {
-            Err(Errors::ValidationError { field_errors: parse_validation_errors(e) }.into())
+            Err(Errors::ValidationError { field_errors: parse_validation_errors(e) })
         }
     }
 }
\ No newline at end of file
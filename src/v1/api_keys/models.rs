@@ -0,0 +1,78 @@
+pub mod api_key {
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use getset::{Getters};
+
+    use crate::{auth::{self, Role}, v1::tokens::models::token::now};
+
+    /// An API key kept in the store. Only the SHA-256 of the key is stored, the key itself is
+    /// returned once when it is created.
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct ApiKey {
+        name: String,
+        sha256: String,
+        #[serde(default)]
+        role: Role,
+        #[serde(default)]
+        clusters: Vec<String>,
+        #[serde(default)]
+        sites: Vec<String>,
+        created_at: u64
+    }
+
+    impl ApiKey {
+        pub fn generate(name: &str, role: Role, clusters: &[String], sites: &[String]) -> (ApiKey, String) {
+            let key = auth::generate_key();
+            let api_key = ApiKey {
+                name: name.to_owned(),
+                sha256: auth::hash_key(&key),
+                role,
+                clusters: clusters.to_vec(),
+                sites: sites.to_vec(),
+                created_at: now()
+            };
+            (api_key, key)
+        }
+    }
+}
+
+pub mod requests {
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use validator::{Validate};
+    use getset::{Getters};
+
+    use crate::auth::Role;
+
+    #[derive(Serialize, Deserialize, Validate, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct CreateApiKey {
+        #[validate(length(min = 1))]
+        name: String,
+        #[serde(default)]
+        role: Role,
+        // Limit the role to these clusters and sites, all of them when empty
+        #[serde(default)]
+        clusters: Vec<String>,
+        #[serde(default)]
+        sites: Vec<String>
+    }
+}
+
+pub mod responses {
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+
+    use super::api_key::ApiKey;
+
+    #[derive(Serialize, Deserialize, Apiv2Schema)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CreatedApiKey {
+        // Shown only in this response
+        pub key: String,
+        pub api_key: ApiKey
+    }
+}
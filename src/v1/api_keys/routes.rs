@@ -0,0 +1,112 @@
+use crate::{auth::{Authenticated, Role, policy::Scope}, errors::{Errors, parse_validation_errors}, v1::{Response, api_keys::models::{api_key::ApiKey, requests::CreateApiKey, responses::CreatedApiKey}}, settings::Settings, store::Store};
+
+use actix_web::{web::{Path, Data}, Result, HttpResponse};
+use log::info;
+use paperclip::actix::{web::Json, api_v2_operation, delete};
+use validator::Validate;
+
+
+// Mounted in `server::routes`, with and without the trailing slash the route macros would force
+#[api_v2_operation]
+pub async fn list_api_keys(auth: Authenticated, store: Data<Store>) -> Result<Json<Response<Vec<ApiKey>>>, Errors> {
+    auth.0.authorize(Role::Admin, "list API keys", Scope::global())?;
+    let keys = store.read(|tables| tables.api_keys.values().cloned().collect());
+    Ok(Json(Response { data: keys }))
+}
+
+#[api_v2_operation]
+pub async fn create_api_key(auth: Authenticated, body: Json<CreateApiKey>, settings: Data<Settings>, store: Data<Store>) -> Result<Json<Response<CreatedApiKey>>, Errors> {
+    let request = body.into_inner();
+    auth.0.authorize(Role::Admin, "create API keys", Scope::global())?;
+
+    if let Err(e) = request.validate() {
+        return Err(Errors::ValidationError { field_errors: parse_validation_errors(e) })
+    }
+
+    let (api_key, key) = ApiKey::generate(request.get_name(), *request.get_role(), request.get_clusters(), request.get_sites());
+    store.transaction(|tables| {
+        let configured = settings.get_auth().get_api_keys().iter().any(|configured| configured.get_name() == request.get_name());
+        if configured || tables.api_keys.contains_key(request.get_name()) {
+            return Err(Errors::ConflictError { message: format!("API key {} already exists", request.get_name()) })
+        }
+        tables.api_keys.insert(api_key.get_name().to_owned(), api_key.clone());
+        Ok(())
+    })?;
+
+    info!("Created API key {}", api_key.get_name());
+    Ok(Json(Response { data: CreatedApiKey { key, api_key } }))
+}
+
+#[api_v2_operation]
+#[delete("/{name}")]
+pub async fn delete_api_key(auth: Authenticated, path: Path<String>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let name = path.into_inner();
+    auth.0.authorize(Role::Admin, "delete API keys", Scope::global())?;
+
+    store.transaction(|tables| tables.api_keys.remove(&name).map(|_| ()).ok_or(Errors::NotFoundError))?;
+
+    info!("Deleted API key {}", name);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::{header, StatusCode}, test::{self, TestRequest}, App};
+    use paperclip::actix::OpenApiExt;
+    use serde_json::{json, Value};
+
+    use crate::{auth::{self, hash_key}, server, settings::StorageSettings};
+    use super::*;
+
+    const ADMIN_KEY: &str = "admin-key";
+
+    fn settings() -> Settings {
+        serde_json::from_value(json!({
+            "server": { "address": "127.0.0.1", "port": 8080 },
+            "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" },
+            "auth": { "api_keys": [{ "name": "ops", "sha256": hash_key(ADMIN_KEY), "role": "admin" }] }
+        })).unwrap()
+    }
+
+    fn with_key(request: TestRequest, key: &str) -> TestRequest {
+        request.insert_header((header::AUTHORIZATION, format!("ApiKey {}", key)))
+    }
+
+    #[actix_web::test]
+    async fn creates_keys_that_authenticate() {
+        let app = test::init_service(App::new()
+            .wrap(auth::Authentication)
+            .wrap_api()
+            .app_data(Data::new(settings()))
+            .app_data(Data::new(Store::open(&StorageSettings::default()).unwrap()))
+            .configure(server::routes)
+            .build()).await;
+
+        let response = test::call_service(&app, TestRequest::get().uri("/v1/api-keys").to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = with_key(TestRequest::post().uri("/v1/api-keys"), ADMIN_KEY).set_json(json!({ "name": "ci", "role": "viewer" }));
+        let body: Value = test::call_and_read_body_json(&app, request.to_request()).await;
+        let key = body["data"]["key"].as_str().unwrap().to_owned();
+        assert_eq!(body["data"]["apiKey"]["name"], "ci");
+        let request = with_key(TestRequest::post().uri("/v1/api-keys"), ADMIN_KEY).set_json(json!({ "name": "ops" }));
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::CONFLICT);
+
+        // The new key authenticates, but only with the role it was given
+        let response = test::call_service(&app, with_key(TestRequest::get().uri("/v1/api-keys"), &key).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&app, with_key(TestRequest::get().uri("/v1/clusters"), &key).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The OpenAPI spec lists the collection with a trailing slash
+        for uri in ["/v1/api-keys", "/v1/api-keys/"] {
+            let body: Value = test::call_and_read_body_json(&app, with_key(TestRequest::get().uri(uri), ADMIN_KEY).to_request()).await;
+            assert_eq!(body["data"][0]["name"], "ci");
+        }
+
+        let response = test::call_service(&app, with_key(TestRequest::delete().uri("/v1/api-keys/ci"), ADMIN_KEY).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test::call_service(&app, with_key(TestRequest::get().uri("/v1/clusters"), &key).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
\ No newline at end of file
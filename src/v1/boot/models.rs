@@ -0,0 +1,176 @@
+pub mod service {
+    use eui48::MacAddress;
+    use log::info;
+
+    use crate::{errors::{Errors, parse_mac_address}, settings::Settings, store::Store, templates::{TemplateContext, TemplateEngine}, v1::{devices::models::device::ProvisioningState, provision::models::context::ProvisioningContext}};
+
+    fn local_boot(reason: &str) -> String {
+        format!("#!ipxe\necho KMS: {}, booting from the local disk\nexit\n", reason)
+    }
+
+    /// The configured base URL, or KMS' own port on `host` when there is none.
+    pub fn base_url(settings: &Settings, host: &str) -> String {
+        let scheme = if settings.get_server().get_tls().is_some() { "https" } else { "http" };
+        match settings.get_templates().get_base_url() {
+            Some(base_url) => base_url.trim_end_matches('/').to_owned(),
+            None => format!("{}://{}:{}", scheme, host, settings.get_server().get_port())
+        }
+    }
+
+    /// The script handed out over TFTP as `autoexec.ipxe`. It only sends iPXE back to KMS over
+    /// HTTP, where `ipxe_script` decides what the machine boots.
+    pub fn chain_script(settings: &Settings) -> String {
+        // The TFTP server iPXE was loaded from is KMS itself
+        format!("#!ipxe\nchain {}/v1/boot/ipxe/${{net0/mac}} || exit\n", base_url(settings, "${next-server}"))
+    }
+
+    /// Where iPXE fetches the boot script of `mac` from when it is told directly, e.g. by ProxyDHCP.
+    pub fn ipxe_script_url(settings: &Settings, host: &str, mac: &MacAddress) -> String {
+        format!("{}/v1/boot/ipxe/{}", base_url(settings, host), mac.to_hex_string())
+    }
+
+    /// Whether `mac` is a registered device waiting to be provisioned, the only machines KMS
+    /// boots into an installer.
+    pub fn awaits_provisioning(store: &Store, mac: &MacAddress) -> bool {
+        store.read(|tables| tables.devices.get(&mac.to_hex_string())
+            .is_some_and(|device| *device.get_state() == ProvisioningState::Provisioning))
+    }
+
+    /// The single place that decides how a PXE booting machine proceeds: registered devices
+    /// waiting for provisioning get the `boot.ipxe` template, everything else boots from disk.
+    /// Its URLs are signed when signing keys are configured, iPXE and the installer it boots fetch
+    /// them without credentials.
+    pub fn ipxe_script(settings: &Settings, store: &Store, mac_address: &str, base_url: &str) -> Result<String, Errors> {
+        let mac = parse_mac_address(mac_address)?;
+        let context = store.read(|tables| {
+            let device = tables.devices.get(&mac.to_hex_string()).cloned()?;
+            Some(ProvisioningContext::for_device(settings, tables, device))
+        });
+
+        match context {
+            None => {
+                info!("Unknown device {} is PXE booting, sending it to the local disk", mac);
+                Ok(local_boot("device not registered"))
+            }
+            Some(context) if *context.get_device().get_state() != ProvisioningState::Provisioning => {
+                info!("Device {} is {:?}, sending it to the local disk", mac, context.get_device().get_state());
+                Ok(local_boot("device not in provisioning state"))
+            }
+            Some(context) => {
+                let template_context = TemplateContext::new(settings, &context, base_url, true);
+                let (script, _) = TemplateEngine::new(settings.get_templates()).render("boot.ipxe", &template_context)?;
+                info!("Sending installer boot script to {}", mac);
+                Ok(script)
+            }
+        }
+    }
+}
+
+pub mod assets {
+    use std::{collections::{BTreeMap, HashMap}, fs::{self, File}, io, path::{Path, PathBuf}, sync::{Mutex, PoisonError}, time::SystemTime};
+
+    use getset::Getters;
+    use log::error;
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use sha2::{Digest, Sha256};
+
+    use crate::{errors::Errors, settings::BootSettings};
+
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct Asset {
+        name: String,
+        size_bytes: u64,
+        sha256: String
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    pub struct AssetManifest {
+        profile: String,
+        assets: Vec<Asset>
+    }
+
+    impl AssetManifest {
+        /// The manifest in `sha256sum` format, so installers can run `sha256sum -c` against it.
+        pub fn sha256sums(&self) -> String {
+            self.assets.iter().map(|asset| format!("{}  {}\n", asset.sha256, asset.name)).collect()
+        }
+    }
+
+    struct Checksum {
+        modified: SystemTime,
+        size_bytes: u64,
+        sha256: String
+    }
+
+    /// Hashing an ISO takes seconds, so checksums are kept until the file's size or mtime changes.
+    #[derive(Default)]
+    pub struct ChecksumCache {
+        entries: Mutex<HashMap<PathBuf, Checksum>>
+    }
+
+    impl ChecksumCache {
+        pub fn sha256(&self, path: &Path) -> io::Result<(u64, String)> {
+            let metadata = fs::metadata(path)?;
+            let modified = metadata.modified()?;
+            if let Some(cached) = self.entries.lock().unwrap_or_else(PoisonError::into_inner).get(path) {
+                if cached.modified == modified && cached.size_bytes == metadata.len() {
+                    return Ok((cached.size_bytes, cached.sha256.clone()))
+                }
+            }
+
+            let mut hasher = Sha256::new();
+            io::copy(&mut File::open(path)?, &mut hasher)?;
+            let sha256 = hex::encode(hasher.finalize());
+            self.entries.lock().unwrap_or_else(PoisonError::into_inner).insert(path.to_owned(), Checksum { modified, size_bytes: metadata.len(), sha256: sha256.clone() });
+            Ok((metadata.len(), sha256))
+        }
+    }
+
+    fn io_error(path: &Path, error: io::Error) -> Errors {
+        match error.kind() {
+            io::ErrorKind::NotFound => Errors::NotFoundError,
+            _ => {
+                error!("Unable to read boot asset {}: {}", path.display(), error);
+                Errors::InternalServerError
+            }
+        }
+    }
+
+    /// Asset names of `profile` and the files they are served from. Only names listed here can be
+    /// requested, which keeps requests from reaching outside the profile.
+    pub fn profile_files(settings: &BootSettings, profile: &str) -> Result<BTreeMap<String, PathBuf>, Errors> {
+        let profile = settings.get_profiles().iter().find(|candidate| candidate.get_name() == profile).ok_or(Errors::NotFoundError)?;
+        let directory = Path::new(settings.get_directory()).join(profile.get_directory());
+
+        if !profile.get_files().is_empty() {
+            return Ok(profile.get_files().iter().map(|(name, file)| (name.to_owned(), directory.join(file))).collect())
+        }
+
+        let mut files = BTreeMap::new();
+        for entry in fs::read_dir(&directory).map_err(|error| io_error(&directory, error))? {
+            let entry = entry.map_err(|error| io_error(&directory, error))?;
+            if entry.file_type().is_ok_and(|file_type| file_type.is_file()) {
+                files.insert(entry.file_name().to_string_lossy().into_owned(), entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    pub fn asset_path(settings: &BootSettings, profile: &str, name: &str) -> Result<PathBuf, Errors> {
+        profile_files(settings, profile)?.remove(name).ok_or(Errors::NotFoundError)
+    }
+
+    /// Blocks while hashing, run it off the async workers.
+    pub fn manifest(settings: &BootSettings, cache: &ChecksumCache, profile: &str) -> Result<AssetManifest, Errors> {
+        let mut assets = Vec::new();
+        for (name, path) in profile_files(settings, profile)? {
+            let (size_bytes, sha256) = cache.sha256(&path).map_err(|error| io_error(&path, error))?;
+            assets.push(Asset { name, size_bytes, sha256 });
+        }
+        Ok(AssetManifest { profile: profile.to_owned(), assets })
+    }
+}
\ No newline at end of file
@@ -0,0 +1,106 @@
+use crate::{errors::Errors, v1::{Response, boot::models::{service, assets::{self, AssetManifest, ChecksumCache}}}, settings::Settings, store::Store, templates::request_base_url};
+
+use actix_files::NamedFile;
+use actix_web::{web::{self, Path, Data}, Result, HttpRequest, HttpResponse};
+use paperclip::actix::{web::Json, api_v2_operation, get};
+
+
+#[api_v2_operation]
+#[get("/ipxe/{mac_address}")]
+pub async fn get_ipxe_script(path: Path<String>, request: HttpRequest, settings: Data<Settings>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let script = service::ipxe_script(&settings, &store, &path.into_inner(), &request_base_url(&request))?;
+    Ok(HttpResponse::Ok().content_type("text/plain").body(script))
+}
+
+async fn build_manifest(profile: String, settings: Data<Settings>, cache: Data<ChecksumCache>) -> Result<AssetManifest, Errors> {
+    web::block(move || assets::manifest(settings.get_boot(), &cache, &profile)).await?
+}
+
+#[api_v2_operation]
+#[get("/assets/{profile}")]
+pub async fn get_asset_manifest(path: Path<String>, settings: Data<Settings>, cache: Data<ChecksumCache>) -> Result<Json<Response<AssetManifest>>, Errors> {
+    let manifest = build_manifest(path.into_inner(), settings, cache).await?;
+    Ok(Json(Response { data: manifest }))
+}
+
+#[api_v2_operation]
+#[get("/assets/{profile}/SHA256SUMS")]
+pub async fn get_asset_checksums(path: Path<String>, settings: Data<Settings>, cache: Data<ChecksumCache>) -> Result<HttpResponse, Errors> {
+    let manifest = build_manifest(path.into_inner(), settings, cache).await?;
+    Ok(HttpResponse::Ok().content_type("text/plain").body(manifest.sha256sums()))
+}
+
+/// Served with ETag and Last-Modified headers and support for Range requests, so interrupted
+/// downloads of large images can resume.
+#[api_v2_operation]
+#[get("/assets/{profile}/{name}")]
+pub async fn get_asset(path: Path<(String, String)>, request: HttpRequest, settings: Data<Settings>) -> Result<HttpResponse, Errors> {
+    let (profile, name) = path.into_inner();
+    let file = assets::asset_path(settings.get_boot(), &profile, &name)?;
+    let asset = NamedFile::open_async(&file).await?.disable_content_disposition();
+    Ok(asset.into_response(&request))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::{self, TestRequest}, App};
+    use eui48::MacAddress;
+    use paperclip::actix::OpenApiExt;
+    use serde_json::json;
+
+    use crate::{server, settings::StorageSettings, v1::devices::models::device::{Device, ProvisioningState}};
+    use super::*;
+
+    const BASE_URL: &str = "http://kms.test";
+
+    fn settings() -> Settings {
+        serde_json::from_value(json!({
+            "server": { "address": "127.0.0.1", "port": 8080 },
+            "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" },
+            "provisioning": { "signing_keys": [{ "id": "test", "secret": "signing secret" }] },
+            "templates": {
+                "base_url": BASE_URL,
+                "variables": { "kernel_url": "http://mirror/vmlinuz", "initrd_url": "http://mirror/initrd.img" }
+            }
+        })).unwrap()
+    }
+
+    fn store() -> Store {
+        let store = Store::open(&StorageSettings::default()).unwrap();
+        let mut device = Device::new("node-1", MacAddress::parse_str("00:00:5e:00:53:01").unwrap(), "10.0.0.11".parse().unwrap(), None);
+        device.set_state(ProvisioningState::Provisioning);
+        store.transaction(|tables| {
+            tables.devices.insert(device.get_mac_address().to_owned(), device);
+            Ok::<_, Errors>(())
+        }).unwrap();
+        store
+    }
+
+    #[actix_web::test]
+    async fn signs_every_link_of_the_boot_script() {
+        let app = test::init_service(App::new()
+            .wrap_api()
+            .app_data(Data::new(settings()))
+            .app_data(Data::new(store()))
+            .configure(server::routes)
+            .build()).await;
+
+        let script = test::call_and_read_body(&app, TestRequest::get().uri("/v1/boot/ipxe/00:00:5e:00:53:01").to_request()).await;
+        let script = String::from_utf8(script.to_vec()).unwrap();
+        let kernel = script.lines().find(|line| line.starts_with("kernel ")).unwrap();
+        let argument = |name: &str| kernel.split(' ').find_map(|argument| argument.strip_prefix(name)).unwrap().to_owned();
+        let (kickstart, seed) = (argument("inst.ks="), argument("ds=nocloud-net;s="));
+
+        for link in [kickstart.clone(), seed.replace("%s", "meta-data"), seed.replace("%s", "user-data")] {
+            let path = link.strip_prefix(BASE_URL).unwrap();
+            assert!(path.contains("?exp=") && path.contains("&sig="), "{} is not signed", link);
+            let response = test::call_service(&app, TestRequest::get().uri(path).to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", link);
+        }
+
+        let unsigned = kickstart.strip_prefix(BASE_URL).unwrap().split('?').next().unwrap().to_owned();
+        let response = test::call_service(&app, TestRequest::get().uri(&unsigned).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,2 @@
+pub mod models;
+pub mod routes;
\ No newline at end of file
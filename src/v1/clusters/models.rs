@@ -0,0 +1,194 @@
+pub mod cluster {
+    use ipnet::IpNet;
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use validator::{Validate, ValidationError};
+    use getset::{Getters};
+    use lazy_static::lazy_static;
+    use regex::Regex;
+
+    use crate::v1::devices::models::device::MAC_ADDRESS_RE;
+
+    lazy_static! {
+        static ref KUBERNETES_VERSION_RE: Regex = Regex::new(r"^v?\d+\.\d+\.\d+$").unwrap();
+        static ref CLUSTER_NAME_RE: Regex = Regex::new(r"^[a-z0-9]([-a-z0-9]*[a-z0-9])?$").unwrap();
+        static ref CERTIFICATE_KEY_RE: Regex = Regex::new(r"^[a-f0-9]{64}$").unwrap();
+        static ref CA_CERT_HASH_RE: Regex = Regex::new(r"^sha256:[a-f0-9]{64}$").unwrap();
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum NodeRole {
+        ControlPlane,
+        Worker,
+        Etcd
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Cni {
+        Calico,
+        Cilium,
+        Flannel,
+        None
+    }
+
+    fn validate_cidr(cidr: &str) -> Result<(), ValidationError> {
+        match cidr.parse::<IpNet>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ValidationError::new("cidr"))
+        }
+    }
+
+    fn validate_ca_cert_hashes(hashes: &[String]) -> Result<(), ValidationError> {
+        if hashes.iter().all(|hash| CA_CERT_HASH_RE.is_match(hash)) {
+            Ok(())
+        } else {
+            Err(ValidationError::new("regex"))
+        }
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Validate, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct Cluster {
+        #[validate(regex = "CLUSTER_NAME_RE", length(max = 63))]
+        name: String,
+        #[validate(regex = "KUBERNETES_VERSION_RE")]
+        kubernetes_version: String,
+        // host:port the control plane is reachable at, usually a load balancer or VIP
+        #[validate(length(min = 1))]
+        api_endpoint: String,
+        #[validate(custom = "validate_cidr")]
+        pod_cidr: String,
+        #[validate(custom = "validate_cidr")]
+        service_cidr: String,
+        cni: Cni,
+        // Key for the certificates kubeadm uploads, lets further control plane nodes join without copying PKI
+        #[validate(regex = "CERTIFICATE_KEY_RE")]
+        certificate_key: Option<String>,
+        // Public key pins of the cluster CA used for token discovery, e.g. sha256:<hex>
+        #[serde(default)]
+        #[validate(custom = "validate_ca_cert_hashes")]
+        ca_cert_hashes: Vec<String>,
+        // Lets nodes join without `caCertHashes`, trusting whichever API server answers first
+        #[serde(default)]
+        unsafe_skip_ca_verification: bool,
+        // MAC address of the control plane node that runs `kubeadm init`, defaults to the lowest control plane MAC
+        #[validate(regex = "MAC_ADDRESS_RE")]
+        init_node: Option<String>,
+        // Set for clusters running Talos Linux, selects the installer image
+        #[validate(regex = "KUBERNETES_VERSION_RE")]
+        talos_version: Option<String>
+    }
+
+    impl Cluster {
+        /// The cluster without its certificate key, which lets anyone holding a join token add control plane nodes.
+        pub fn without_secrets(mut self) -> Cluster {
+            self.certificate_key = None;
+            self
+        }
+    }
+}
+
+/// The secrets bundle produced by `talosctl gen secrets`, converted to JSON. Field names follow that file.
+pub mod talos {
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use getset::{Getters};
+
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    pub struct CertAndKey {
+        crt: String,
+        #[serde(default)]
+        key: String
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    pub struct KeyOnly {
+        key: String
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    pub struct ClusterIdentity {
+        id: String,
+        secret: String
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    pub struct ClusterSecrets {
+        bootstraptoken: String,
+        secretboxencryptionsecret: String
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    pub struct TrustdInfo {
+        token: String
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    pub struct Certs {
+        etcd: CertAndKey,
+        k8s: CertAndKey,
+        k8saggregator: CertAndKey,
+        k8sserviceaccount: KeyOnly,
+        os: CertAndKey
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    pub struct TalosSecrets {
+        cluster: ClusterIdentity,
+        secrets: ClusterSecrets,
+        trustdinfo: TrustdInfo,
+        certs: Certs
+    }
+}
+
+/// Credentials KMS uses to manage bootstrap token Secrets in a running cluster, e.g. of a service
+/// account allowed to create, update and delete Secrets in `kube-system`.
+pub mod access {
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use validator::{Validate};
+    use getset::{Getters};
+
+    #[derive(Clone, Serialize, Deserialize, Validate, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct KubernetesAccess {
+        // URL of the API server, e.g. https://10.0.0.10:6443
+        #[validate(url)]
+        server: String,
+        // PEM encoded cluster CA the API server certificate is checked against
+        #[validate(length(min = 1))]
+        ca_certificate: String,
+        // Bearer token
+        #[validate(length(min = 1))]
+        token: String
+    }
+}
+
+pub mod requests {
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use validator::{Validate};
+    use getset::{Getters};
+
+    use super::cluster::NodeRole;
+
+    #[derive(Serialize, Deserialize, Validate, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct ClusterAssignment {
+        #[validate(length(min = 1))]
+        cluster: String,
+        role: NodeRole
+    }
+}
\ No newline at end of file
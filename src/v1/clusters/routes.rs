@@ -0,0 +1,302 @@
+use crate::{auth::{Authenticated, Role, policy::Scope}, errors::{Errors, parse_validation_errors, validation_error}, v1::{Response, clusters::models::{access::KubernetesAccess, cluster::Cluster, talos::TalosSecrets}, devices::models::device::Device}, store::Store};
+
+use actix_web::{web::{Path, Data}, Result, HttpResponse};
+use log::info;
+use paperclip::actix::{web::Json, api_v2_operation, get, put, delete};
+use validator::Validate;
+
+
+// Only admins of a cluster get to see its certificate key
+fn readable(auth: &Authenticated, cluster: Cluster) -> Cluster {
+    if auth.0.can(Role::Admin, Scope::cluster(cluster.get_name())) {
+        cluster
+    } else {
+        cluster.without_secrets()
+    }
+}
+
+// Mounted in `server::routes`, with and without the trailing slash the route macros would force
+#[api_v2_operation]
+pub async fn list_clusters(auth: Authenticated, store: Data<Store>) -> Result<Json<Response<Vec<Cluster>>>, Errors> {
+    let clusters = store.read(|tables| tables.clusters.values()
+        .filter(|cluster| auth.0.can(Role::Viewer, Scope::cluster(cluster.get_name())))
+        .map(|cluster| readable(&auth, cluster.clone()))
+        .collect());
+    Ok(Json(Response { data: clusters }))
+}
+
+#[api_v2_operation]
+#[get("/{name}")]
+pub async fn get_cluster(auth: Authenticated, path: Path<String>, store: Data<Store>) -> Result<Json<Response<Cluster>>, Errors> {
+    let name = path.into_inner();
+    auth.0.authorize(Role::Viewer, "read clusters", Scope::cluster(&name))?;
+    match store.read(|tables| tables.clusters.get(&name).cloned()) {
+        Some(cluster) => Ok(Json(Response { data: readable(&auth, cluster) })),
+        None => Err(Errors::NotFoundError)
+    }
+}
+
+#[api_v2_operation]
+#[get("/{name}/nodes")]
+pub async fn list_cluster_nodes(auth: Authenticated, path: Path<String>, store: Data<Store>) -> Result<Json<Response<Vec<Device>>>, Errors> {
+    let name = path.into_inner();
+    auth.0.authorize(Role::Viewer, "list cluster nodes", Scope::cluster(&name))?;
+    let nodes = store.read(|tables| {
+        if !tables.clusters.contains_key(&name) {
+            return Err(Errors::NotFoundError)
+        }
+        Ok(tables.devices.values().filter(|device| device.get_cluster().as_deref() == Some(name.as_str())).cloned().collect())
+    })?;
+    Ok(Json(Response { data: nodes }))
+}
+
+#[api_v2_operation]
+pub async fn create_cluster(auth: Authenticated, body: Json<Cluster>, store: Data<Store>) -> Result<Json<Response<Cluster>>, Errors> {
+    let cluster = body.into_inner();
+
+    if let Err(e) = cluster.validate() {
+        return Err(Errors::ValidationError { field_errors: parse_validation_errors(e) })
+    }
+    auth.0.authorize(Role::Admin, "configure clusters", Scope::cluster(cluster.get_name()))?;
+
+    let created = store.transaction(|tables| {
+        if tables.clusters.contains_key(cluster.get_name()) {
+            return Err(Errors::ConflictError { message: format!("Cluster {} already exists", cluster.get_name()) })
+        }
+        tables.clusters.insert(cluster.get_name().to_owned(), cluster.clone());
+        Ok(cluster)
+    })?;
+
+    info!("Created cluster {} running Kubernetes {}", created.get_name(), created.get_kubernetes_version());
+    Ok(Json(Response { data: created }))
+}
+
+#[api_v2_operation]
+#[put("/{name}")]
+pub async fn update_cluster(auth: Authenticated, path: Path<String>, body: Json<Cluster>, store: Data<Store>) -> Result<Json<Response<Cluster>>, Errors> {
+    let name = path.into_inner();
+    let cluster = body.into_inner();
+    auth.0.authorize(Role::Admin, "configure clusters", Scope::cluster(&name))?;
+
+    if let Err(e) = cluster.validate() {
+        return Err(Errors::ValidationError { field_errors: parse_validation_errors(e) })
+    }
+    if cluster.get_name() != &name {
+        return Err(validation_error("name", "immutable"))
+    }
+
+    let updated = store.transaction(|tables| {
+        let existing = tables.clusters.get_mut(&name).ok_or(Errors::NotFoundError)?;
+        *existing = cluster;
+        Ok::<_, Errors>(existing.clone())
+    })?;
+
+    info!("Updated cluster {}", updated.get_name());
+    Ok(Json(Response { data: updated }))
+}
+
+#[api_v2_operation]
+#[delete("/{name}")]
+pub async fn delete_cluster(auth: Authenticated, path: Path<String>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let name = path.into_inner();
+    auth.0.authorize(Role::Admin, "delete clusters", Scope::cluster(&name))?;
+
+    store.transaction(|tables| {
+        if !tables.clusters.contains_key(&name) {
+            return Err(Errors::NotFoundError)
+        }
+        if tables.devices.values().any(|device| device.get_cluster().as_deref() == Some(name.as_str())) {
+            return Err(Errors::ConflictError { message: format!("Cluster {} still has nodes assigned", name) })
+        }
+        tables.clusters.remove(&name);
+        tables.talos_secrets.remove(&name);
+        tables.kubernetes_access.remove(&name);
+        tables.initialized_clusters.remove(&name);
+        Ok(())
+    })?;
+
+    info!("Deleted cluster {}", name);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[api_v2_operation]
+#[put("/{name}/talos-secrets")]
+pub async fn set_talos_secrets(auth: Authenticated, path: Path<String>, body: Json<TalosSecrets>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let name = path.into_inner();
+    auth.0.authorize(Role::Admin, "configure clusters", Scope::cluster(&name))?;
+    let secrets = body.into_inner();
+
+    store.transaction(|tables| {
+        if !tables.clusters.contains_key(&name) {
+            return Err(Errors::NotFoundError)
+        }
+        tables.talos_secrets.insert(name.clone(), secrets);
+        Ok(())
+    })?;
+
+    info!("Stored Talos secrets for cluster {}", name);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[api_v2_operation]
+#[delete("/{name}/talos-secrets")]
+pub async fn delete_talos_secrets(auth: Authenticated, path: Path<String>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let name = path.into_inner();
+    auth.0.authorize(Role::Admin, "configure clusters", Scope::cluster(&name))?;
+
+    store.transaction(|tables| tables.talos_secrets.remove(&name).map(|_| ()).ok_or(Errors::NotFoundError))?;
+
+    info!("Deleted Talos secrets of cluster {}", name);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[api_v2_operation]
+#[put("/{name}/kubernetes-access")]
+pub async fn set_kubernetes_access(auth: Authenticated, path: Path<String>, body: Json<KubernetesAccess>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let name = path.into_inner();
+    auth.0.authorize(Role::Admin, "configure clusters", Scope::cluster(&name))?;
+    let access = body.into_inner();
+
+    if let Err(e) = access.validate() {
+        return Err(Errors::ValidationError { field_errors: parse_validation_errors(e) })
+    }
+    store.transaction(|tables| {
+        if !tables.clusters.contains_key(&name) {
+            return Err(Errors::NotFoundError)
+        }
+        tables.kubernetes_access.insert(name.clone(), access);
+        Ok(())
+    })?;
+
+    info!("Stored Kubernetes API access for cluster {}", name);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[api_v2_operation]
+#[delete("/{name}/kubernetes-access")]
+pub async fn delete_kubernetes_access(auth: Authenticated, path: Path<String>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let name = path.into_inner();
+    auth.0.authorize(Role::Admin, "configure clusters", Scope::cluster(&name))?;
+
+    store.transaction(|tables| tables.kubernetes_access.remove(&name).map(|_| ()).ok_or(Errors::NotFoundError))?;
+
+    info!("Deleted Kubernetes API access of cluster {}", name);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::{self, TestRequest}, App};
+    use eui48::MacAddress;
+    use paperclip::actix::OpenApiExt;
+    use serde_json::{json, Value};
+
+    use crate::{auth, server, settings::{Settings, StorageSettings}};
+    use super::*;
+
+    fn settings() -> Settings {
+        serde_json::from_value(json!({
+            "server": { "address": "127.0.0.1", "port": 8080 },
+            "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" },
+            "auth": { "enabled": false },
+            "provisioning": { "require_authentication": false }
+        })).unwrap()
+    }
+
+    fn store() -> Store {
+        let device = Device::new("node-1", MacAddress::parse_str("00:00:5e:00:53:01").unwrap(), "10.0.0.11".parse().unwrap(), None);
+        let store = Store::open(&StorageSettings::default()).unwrap();
+        store.transaction(|tables| {
+            tables.devices.insert(device.get_mac_address().to_owned(), device);
+            Ok::<_, Errors>(())
+        }).unwrap();
+        store
+    }
+
+    fn cluster() -> Value {
+        json!({
+            "name": "prod",
+            "kubernetesVersion": "1.27.3",
+            "apiEndpoint": "10.0.0.10:6443",
+            "podCidr": "10.244.0.0/16",
+            "serviceCidr": "10.96.0.0/12",
+            "cni": "calico"
+        })
+    }
+
+    #[actix_web::test]
+    async fn manages_clusters() {
+        let app = test::init_service(App::new()
+            .wrap(auth::Authentication)
+            .wrap_api()
+            .app_data(Data::new(settings()))
+            .app_data(Data::new(store()))
+            .configure(server::routes)
+            .build()).await;
+
+        let response = test::call_service(&app, TestRequest::post().uri("/v1/clusters").set_json(cluster()).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&app, TestRequest::post().uri("/v1/clusters").set_json(cluster()).to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let mut update = cluster();
+        update["kubernetesVersion"] = json!("1.28.1");
+        let response = test::call_service(&app, TestRequest::put().uri("/v1/clusters/prod").set_json(&update).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = test::call_and_read_body_json(&app, TestRequest::get().uri("/v1/clusters/prod").to_request()).await;
+        assert_eq!(body["data"]["kubernetesVersion"], "1.28.1");
+
+        update["name"] = json!("staging");
+        let response = test::call_service(&app, TestRequest::put().uri("/v1/clusters/prod").set_json(&update).to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let problem: Value = test::read_body_json(response).await;
+        assert_eq!(problem["errors"]["name"][0]["code"], "immutable");
+
+        // The OpenAPI spec lists the collection with a trailing slash
+        for uri in ["/v1/clusters", "/v1/clusters/"] {
+            let body: Value = test::call_and_read_body_json(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(body["data"].as_array().unwrap().len(), 1);
+        }
+        let response = test::call_service(&app, TestRequest::get().uri("/v1/clusters/staging").to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn assigns_nodes_to_clusters() {
+        let app = test::init_service(App::new()
+            .wrap(auth::Authentication)
+            .wrap_api()
+            .app_data(Data::new(settings()))
+            .app_data(Data::new(store()))
+            .configure(server::routes)
+            .build()).await;
+        let assign = |cluster: &str| TestRequest::put().uri("/v1/devices/device/00:00:5e:00:53:01/cluster")
+            .set_json(json!({ "cluster": cluster, "role": "control-plane" }))
+            .to_request();
+
+        let response = test::call_service(&app, assign("prod")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let problem: Value = test::read_body_json(response).await;
+        assert_eq!(problem["errors"]["cluster"][0]["code"], "unknown");
+
+        test::call_service(&app, TestRequest::post().uri("/v1/clusters").set_json(cluster()).to_request()).await;
+        let body: Value = test::call_and_read_body_json(&app, assign("prod")).await;
+        assert_eq!(body["data"]["cluster"], "prod");
+        assert_eq!(body["data"]["role"], "control-plane");
+
+        let body: Value = test::call_and_read_body_json(&app, TestRequest::get().uri("/v1/clusters/prod/nodes").to_request()).await;
+        assert_eq!(body["data"][0]["hostname"], "node-1");
+        // The provisioning context hands the cluster and its nodes to the templates
+        let context: Value = test::call_and_read_body_json(&app, TestRequest::get().uri("/v1/provision/00:00:5e:00:53:01").to_request()).await;
+        assert_eq!(context["data"]["cluster"]["name"], "prod");
+        assert_eq!(context["data"]["nodes"][0]["role"], "control-plane");
+
+        let response = test::call_service(&app, TestRequest::delete().uri("/v1/clusters/prod").to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let body: Value = test::call_and_read_body_json(&app, TestRequest::delete().uri("/v1/devices/device/00:00:5e:00:53:01/cluster").to_request()).await;
+        assert!(body["data"]["cluster"].is_null());
+        let response = test::call_service(&app, TestRequest::delete().uri("/v1/clusters/prod").to_request()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+}
\ No newline at end of file
@@ -1,2 +1,3 @@
 pub mod routes;
-pub mod models;
\ No newline at end of file
+pub mod models;
+pub mod service;
@@ -1,22 +1,69 @@
 pub mod device {
-    use std::net::Ipv4Addr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     use eui48::MacAddress;
     use paperclip::actix::Apiv2Schema;
-    use crate::clients::dynamodb::DynamoDBClient;
     use serde_derive::{Serialize, Deserialize};
     use validator::{Validate};
     use getset::{Getters, Setters};
     use lazy_static::lazy_static;
     use regex::Regex;
 
-    const CLIENT: DynamoDBClient = DynamoDBClient { client: ""  };
+    use crate::v1::clusters::models::cluster::NodeRole;
 
     lazy_static! {
-        static ref MAC_ADDRESS_RE: Regex = Regex::new(r"^([0-9A-Fa-f]{2}[:-]){5}([0-9A-Fa-f]{2})$").unwrap();
+        pub static ref MAC_ADDRESS_RE: Regex = Regex::new(r"^([0-9A-Fa-f]{2}[:-]){5}([0-9A-Fa-f]{2})$").unwrap();
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Ipv6Mode {
+        Static,
+        Slaac
+    }
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ProvisioningState {
+        // Waiting to be (re)installed, set on registration
+        #[default]
+        Provisioning,
+        Provisioned,
+        Failed
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Validate, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct Disk {
+        // Device path, e.g. /dev/sda or /dev/nvme0n1
+        #[validate(length(min = 1))]
+        name: String,
+        size_bytes: u64,
+        model: Option<String>,
+        rotational: Option<bool>
     }
 
-    #[derive(Serialize, Deserialize, Validate, Getters, Setters, Apiv2Schema)]
+    /// Hardware facts reported by the device itself, e.g. from a discovery image or `%pre` script.
+    #[derive(Clone, Serialize, Deserialize, Validate, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct HardwareInventory {
+        cpu_cores: Option<u32>,
+        memory_bytes: Option<u64>,
+        #[serde(default)]
+        #[validate]
+        disks: Vec<Disk>
+    }
+
+    impl HardwareInventory {
+        /// Picks the disk to install onto: solid state disks first, then in device name order.
+        pub fn install_disk(&self) -> Option<&Disk> {
+            self.disks.iter().min_by_key(|disk| (disk.rotational != Some(false), disk.name.clone()))
+        }
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Validate, Getters, Setters, Apiv2Schema)]
     #[get = "pub with_prefix"]
     #[set = "pub with_prefix"]
     #[serde(rename_all = "camelCase")]
@@ -25,104 +72,132 @@ pub mod device {
         hostname: String,
         #[validate(regex = "MAC_ADDRESS_RE")]
         mac_address: String,
-        ip_address: Ipv4Addr
+        ip_address: Ipv4Addr,
+        network: Option<String>,
+        #[serde(default)]
+        ipv6_address: Option<Ipv6Addr>,
+        #[serde(default)]
+        ipv6_mode: Option<Ipv6Mode>,
+        #[serde(default)]
+        cluster: Option<String>,
+        #[serde(default)]
+        role: Option<NodeRole>,
+        #[serde(default)]
+        state: ProvisioningState,
+        #[serde(default)]
+        inventory: Option<HardwareInventory>
     }
 
     impl Device {
-        pub fn from_mac_address(mac_address: MacAddress) -> Device {
-            // We look up the device here
-            CLIENT.client;
-            Device {
-                hostname: "fake".to_owned(),
-                mac_address:mac_address.to_hex_string(),
-                ip_address: Ipv4Addr::new(1, 1, 1, 1)
-            }
-        }
-        pub fn new(hostname: &str, mac_address: MacAddress, ip_address: Ipv4Addr) -> Self {
+        pub fn new(hostname: &str, mac_address: MacAddress, ip_address: Ipv4Addr, network: Option<String>) -> Self {
             Device {
                 hostname: hostname.to_owned(),
                 mac_address: mac_address.to_hex_string(),
-                ip_address: ip_address.to_owned()
+                ip_address: ip_address.to_owned(),
+                network,
+                ipv6_address: None,
+                ipv6_mode: None,
+                cluster: None,
+                role: None,
+                state: ProvisioningState::default(),
+                inventory: None
             }
         }
     }
 }
 
-pub mod errors {
-    use std::{collections::HashMap, borrow::Cow};
-    use actix_web::{error, HttpResponse};
-    use derive_more::{Display, Error};
-    use eui48::ParseError;
+pub mod requests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
     use paperclip::actix::Apiv2Schema;
     use serde_derive::{Serialize, Deserialize};
-    use validator::{ValidationErrors, ValidationError};
-    use convert_case::{Case, Casing};
-
-    #[derive(Debug, Display, Error)]
-    pub enum Errors {
-        #[display(fmt = "Validation Error")]
-        ValidationError { field_errors: HashMap<String, Vec<Cow<'static, str>>> },
-        #[display(fmt = "Internal Server Exception")]
-        InternalServerError,
-        #[display(fmt = "Not Found")]
-        NotFoundError,
-        #[display(fmt = "Unauthorized")]
-        UnauthorizedError,
-        #[display(fmt = "Forbidden")]
-        ForbiddenError
-    }
+    use validator::{Validate};
+    use getset::{Getters, Setters};
 
-    #[derive(Serialize, Deserialize, Apiv2Schema)]
-    #[serde(rename_all = "camelCase")]
-    pub struct ErrorMessage<T> {
-        error_message: T,
-    }
+    use super::device::{MAC_ADDRESS_RE, Ipv6Mode, ProvisioningState};
+    use crate::v1::clusters::models::cluster::NodeRole;
 
-    impl error::ResponseError for Errors {
-        fn error_response(&self) -> HttpResponse {
-            match &*self {
-                Errors::ValidationError { field_errors } => HttpResponse::BadRequest().json(ErrorMessage {
-                    error_message: field_errors
-                }),
-                Errors::InternalServerError => HttpResponse::InternalServerError().json(ErrorMessage {
-                    error_message: "Internal Server Error"
-                }),
-                Errors::NotFoundError => HttpResponse::NotFound().json(ErrorMessage {
-                    error_message: "Resource Not Found"
-                }),
-                Errors::UnauthorizedError => HttpResponse::Unauthorized().json(ErrorMessage {
-                    error_message: "Unauthorized"
-                }),
-                Errors::ForbiddenError => HttpResponse::Forbidden().json(ErrorMessage {
-                    error_message: "Forbidden"
-                })
-            }
-        }
+    #[derive(Default, Serialize, Deserialize, Validate, Getters, Setters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[set = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct RegisterDevice {
+        #[validate(length(min = 3))]
+        hostname: String,
+        #[validate(regex = "MAC_ADDRESS_RE")]
+        mac_address: String,
+        // Allocated from the pool of `network` when left out
+        ip_address: Option<Ipv4Addr>,
+        #[validate(length(min = 1))]
+        network: Option<String>,
+        ipv6_address: Option<Ipv6Addr>,
+        // Defaults to static when an IPv6 address is given
+        ipv6_mode: Option<Ipv6Mode>,
+        #[validate(length(min = 1))]
+        cluster: Option<String>,
+        role: Option<NodeRole>
     }
 
-    fn parse_validation_errors(validation_errors: ValidationErrors ) -> HashMap<String, Vec<Cow<'static, str>>> {
-        let mut error_map = HashMap::new();
-        for (field_name, field_errors) in validation_errors.field_errors() {
-            let error_codes: Vec<Cow<str>> = field_errors.iter().map(|error| error.code.clone()).collect();
-            error_map.insert(field_name.to_string().to_case(Case::Camel), error_codes);
-        }
-        return error_map;
-    }
-    pub fn create_error_response_for_mac_address(error: ParseError) -> HashMap<String, Vec<Cow<'static, str>>> {
-        let code  = if error.to_owned().to_string().contains("Invalid length") { "length" } else { "bytes" };   
-        println!("Error: {}", error);
-        let mut validation_errors = ValidationErrors::new();
-        validation_errors.add("macAddress", ValidationError::new(code));
-        parse_validation_errors(validation_errors)
+    #[derive(Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct StateChange {
+        state: ProvisioningState
     }
 }
 
 pub mod responses {
+    use std::net::Ipv4Addr;
+
     use paperclip::actix::Apiv2Schema;
     use serde_derive::{Serialize, Deserialize};
 
+    use super::device::Device;
+
+    /// A provisioning URL of one device that works without credentials until it expires. The
+    /// same query string can be appended to the device's other provisioning URLs.
     #[derive(Serialize, Deserialize, Apiv2Schema)]
-    pub struct Response<T> {
-        pub data: T
+    #[serde(rename_all = "camelCase")]
+    pub struct SignedUrl {
+        pub url: String,
+        pub query: String,
+        pub expires_at: u64
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum DriftKind {
+        // The controller sees the device with another address than the registered one
+        AddressMismatch,
+        // Another client holds the registered address
+        AddressConflict
     }
-}
\ No newline at end of file
+
+    /// A registered device whose address differs from what the UniFi controller sees.
+    #[derive(Serialize, Deserialize, Apiv2Schema)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Drift {
+        pub mac_address: String,
+        pub hostname: String,
+        pub kind: DriftKind,
+        pub expected_address: Ipv4Addr,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub actual_address: Option<Ipv4Addr>,
+        // MAC address of the client holding the registered address
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub holder: Option<String>
+    }
+
+    impl Drift {
+        pub fn new(device: &Device, kind: DriftKind, actual_address: Option<Ipv4Addr>, holder: Option<String>) -> Drift {
+            Drift {
+                mac_address: device.get_mac_address().to_owned(),
+                hostname: device.get_hostname().to_owned(),
+                kind,
+                expected_address: device.get_ip_address().to_owned(),
+                actual_address,
+                holder
+            }
+        }
+    }
+}
@@ -1,92 +1,268 @@
-use std::{collections::HashMap, borrow::Cow, panic};
+use crate::{auth::{Authenticated, Role, policy::Scope, signed_url}, errors::{Errors, parse_validation_errors, parse_mac_address, validation_error}, v1::{Response, devices::{models::{responses::{Drift, SignedUrl}, device::{Device, HardwareInventory}, requests::{RegisterDevice, StateChange}}, service}, clusters::models::requests::ClusterAssignment}, clients::unifi::UnifiApiClient, settings::Settings, store::Store, templates::request_base_url};
 
-use crate::{v1::devices::models::{responses::Response, errors::Errors, device::Device, errors::create_error_response_for_mac_address}, clients::unifi::{UnifiApiClient, models::ListClientsResponse}, settings::Settings};
-use eui48::MacAddress;
+use actix_web::{web::{Path, Data}, Result, HttpRequest, HttpResponse};
+use log::{info, error};
+use paperclip::actix::{web::{Json}, api_v2_operation, get, post, put, delete};
+use validator::Validate;
 
-use actix_web::{web::{Path, Data}, Result, HttpResponse};
-use log::{warn, info, error};
-use paperclip::actix::{web::{Json}, api_v2_operation, get};
 
+#[api_v2_operation]
+#[get("/device")]
+pub async fn list_devices(auth: Authenticated, store: Data<Store>) -> Result<Json<Response<Vec<Device>>>, Errors> {
+    Ok(Json(Response { data: service::list(&auth.0, &store) }))
+}
 
 #[api_v2_operation]
 #[get("/device/{mac_address}")]
-pub async fn get_device_by_mac(path: Path<String>) -> Result<Json<Response<Device>>, actix_web::Error> {
-    let mac_address = path.into_inner();
-    let device: Device;
-
-    // There's an odd error with some invalid MACs that the parser panics at
-    let result = panic::catch_unwind(|| {
-        MacAddress::parse_str(mac_address.clone().as_str());
-    });
-
-    if result.is_err() {
-        warn!("Error parsing MAC address: {:?}", result.unwrap_err());
-        let mut error_map = HashMap::new();
-        error_map.insert("macAddress".to_owned(), vec![Cow::from("length")]);
-        return Err(
-            Errors::ValidationError { field_errors: error_map }.into()
-        )
-    }
+pub async fn get_device_by_mac(auth: Authenticated, path: Path<String>, store: Data<Store>) -> Result<Json<Response<Device>>, Errors> {
+    let device = service::get(&auth.0, &store, &path.into_inner())?;
+    Ok(Json(Response { data: device }))
+}
 
-    info!("No errors detected while parsing MAC address");
+#[api_v2_operation]
+#[post("/device")]
+pub async fn register_device(auth: Authenticated, body: Json<RegisterDevice>, settings: Data<Settings>, store: Data<Store>) -> Result<Json<Response<Device>>, Errors> {
+    let device = service::register(&auth.0, body.into_inner(), &settings, &store).await?;
+    Ok(Json(Response { data: device }))
+}
 
-    match MacAddress::parse_str(mac_address.clone().as_str()) {
-        Ok(mac) => {
-            info!("Searching for device with MAC: {}", mac);
-            device = Device::from_mac_address(mac);
+#[api_v2_operation]
+#[delete("/device/{mac_address}")]
+pub async fn delete_device(auth: Authenticated, path: Path<String>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    service::delete(&auth.0, &store, &path.into_inner())?;
+    Ok(HttpResponse::NoContent().finish())
+}
 
-            info!("Found device with name: {}", &device.get_hostname());
+/// Lists the registered devices whose address differs from what the UniFi controller sees.
+#[api_v2_operation]
+#[get("/reconcile")]
+pub async fn reconcile_devices(auth: Authenticated, settings: Data<Settings>, store: Data<Store>) -> Result<Json<Response<Vec<Drift>>>, Errors> {
+    let drifts = service::reconcile(&auth.0, &settings, &store).await?;
+    Ok(Json(Response { data: drifts }))
+}
 
-            Ok(
-                Json(
-                    Response {
-                        data: device
-                    }
-                )
-            )
-        }
-        Err(error) => {
-            Err(
-                Errors::ValidationError { field_errors: create_error_response_for_mac_address(error) }.into()
-            )
+#[api_v2_operation]
+#[put("/device/{mac_address}/cluster")]
+pub async fn assign_cluster(auth: Authenticated, path: Path<String>, body: Json<ClusterAssignment>, store: Data<Store>) -> Result<Json<Response<Device>>, Errors> {
+    let mac = parse_mac_address(&path.into_inner())?;
+    let assignment = body.into_inner();
+
+    if let Err(e) = assignment.validate() {
+        return Err(Errors::ValidationError { field_errors: parse_validation_errors(e) })
+    }
+
+    let device = store.transaction(|tables| {
+        if !tables.clusters.contains_key(assignment.get_cluster()) {
+            return Err(validation_error("cluster", "unknown"))
         }
+        let device = tables.devices.get_mut(&mac.to_hex_string()).ok_or(Errors::NotFoundError)?;
+        // Moving a device needs access to both the cluster it leaves and the one it joins
+        auth.0.authorize(Role::Operator, "edit devices", Scope::device(device))?;
+        auth.0.authorize(Role::Operator, "assign devices", Scope::new(Some(assignment.get_cluster().as_str()), device.get_network().as_deref()))?;
+        device.set_cluster(Some(assignment.get_cluster().to_owned())).set_role(Some(assignment.get_role().to_owned()));
+        Ok(device.clone())
+    })?;
+
+    info!("Assigned device {} to cluster {} as {:?}", device.get_mac_address(), assignment.get_cluster(), assignment.get_role());
+    Ok(Json(Response { data: device }))
+}
+
+#[api_v2_operation]
+#[delete("/device/{mac_address}/cluster")]
+pub async fn unassign_cluster(auth: Authenticated, path: Path<String>, store: Data<Store>) -> Result<Json<Response<Device>>, Errors> {
+    let mac = parse_mac_address(&path.into_inner())?;
+
+    let device = store.transaction(|tables| {
+        let device = tables.devices.get_mut(&mac.to_hex_string()).ok_or(Errors::NotFoundError)?;
+        auth.0.authorize(Role::Operator, "edit devices", Scope::device(device))?;
+        device.set_cluster(None).set_role(None);
+        Ok::<_, Errors>(device.clone())
+    })?;
+
+    info!("Removed device {} from its cluster", device.get_mac_address());
+    Ok(Json(Response { data: device }))
+}
+
+#[api_v2_operation]
+#[put("/device/{mac_address}/state")]
+pub async fn set_device_state(auth: Authenticated, path: Path<String>, body: Json<StateChange>, store: Data<Store>) -> Result<Json<Response<Device>>, Errors> {
+    let mac = parse_mac_address(&path.into_inner())?;
+    let state = body.into_inner().get_state().to_owned();
+
+    let device = store.transaction(|tables| {
+        let device = tables.devices.get_mut(&mac.to_hex_string()).ok_or(Errors::NotFoundError)?;
+        auth.0.authorize(Role::Operator, "change the state of devices", Scope::device(device))?;
+        device.set_state(state);
+        Ok::<_, Errors>(device.clone())
+    })?;
+
+    info!("Device {} is now {:?}", device.get_mac_address(), device.get_state());
+    Ok(Json(Response { data: device }))
+}
+
+#[api_v2_operation]
+#[put("/device/{mac_address}/inventory")]
+pub async fn set_device_inventory(auth: Authenticated, path: Path<String>, body: Json<HardwareInventory>, store: Data<Store>) -> Result<Json<Response<Device>>, Errors> {
+    let mac = parse_mac_address(&path.into_inner())?;
+    let inventory = body.into_inner();
+
+    if let Err(e) = inventory.validate() {
+        return Err(Errors::ValidationError { field_errors: parse_validation_errors(e) })
     }
+
+    let device = store.transaction(|tables| {
+        let device = tables.devices.get_mut(&mac.to_hex_string()).ok_or(Errors::NotFoundError)?;
+        auth.0.authorize(Role::Operator, "edit devices", Scope::device(device))?;
+        device.set_inventory(Some(inventory));
+        Ok::<_, Errors>(device.clone())
+    })?;
+
+    info!("Updated hardware inventory of {}", device.get_mac_address());
+    Ok(Json(Response { data: device }))
+}
+
+/// Issues a signed URL for installers that can neither present a client certificate nor carry
+/// an API key. The URL reads the device's join token and the cluster's certificate key, so only
+/// admins of the cluster may sign it.
+#[api_v2_operation]
+#[post("/device/{mac_address}/signed-url")]
+pub async fn create_signed_url(auth: Authenticated, path: Path<String>, request: HttpRequest, settings: Data<Settings>, store: Data<Store>) -> Result<Json<Response<SignedUrl>>, Errors> {
+    let mac = parse_mac_address(&path.into_inner())?;
+    let device = store.read(|tables| tables.devices.get(&mac.to_hex_string()).cloned()).ok_or(Errors::NotFoundError)?;
+    auth.0.authorize(Role::Admin, "sign provisioning URLs", Scope::device(&device))?;
+
+    let (query, expires_at) = signed_url::query(settings.get_provisioning(), &mac)
+        .ok_or_else(|| Errors::ConflictError { message: "No provisioning.signing_keys are configured".to_owned() })?;
+    let url = format!("{}/v1/provision/{}?{}", request_base_url(&request), mac.to_hex_string(), query);
+
+    info!("Signed provisioning URLs of {} until {}", mac, expires_at);
+    Ok(Json(Response { data: SignedUrl { url, query, expires_at } }))
 }
 
 
 #[api_v2_operation]
 #[get("/list")]
-async fn list_clients(data: Data<Settings>) -> HttpResponse {
+async fn list_clients(auth: Authenticated, data: Data<Settings>) -> Result<HttpResponse, Errors> {
+    // The UniFi controller sees every network, so only unrestricted viewers may list its clients
+    auth.0.authorize(Role::Viewer, "list network clients", Scope::global())?;
     let unifi_settings = data.get_unifi();
-    let mut client = UnifiApiClient::new(
-        unifi_settings.get_base_url().clone(),
-        unifi_settings.get_username().clone(),
-        unifi_settings.get_password().clone()
-    );
-    
-    let response = client.list_clients().await;
-    let body: Result<ListClientsResponse, reqwest::Error>;
-
-    match response.unwrap().error_for_status() {
-        Ok(res) => {
-            body = res.json().await;
-        }
-        Err(err) => {
-            return HttpResponse::InternalServerError().json(err.to_string());
-        }
-    }
+    let mut client = UnifiApiClient::from_settings(unifi_settings).map_err(|error| {
+        error!("Unable to read the UniFi credentials: {}", error);
+        Errors::InternalServerError
+    })?;
 
-    match &body {
-        Ok(response_body) => {
-            for item in response_body.get_data() {
-                info!("Item name: {} | Item MAC: {}",item.get_name().as_ref().unwrap_or(&"None".to_owned()), item.get_mac().as_ref().unwrap_or(&"None".to_owned()));
-            }
-            HttpResponse::Ok().json(body.unwrap())
-        }
+    match client.list_client_devices().await {
+        Ok(clients) => Ok(HttpResponse::Ok().json(clients)),
         Err(error) => {
-            println!("{}", error);
-            error!("Error: {}", error);
-            HttpResponse::InternalServerError().finish()
+            error!("Unable to list UniFi clients: {}", error);
+            Err(Errors::InternalServerError)
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::{self, TestRequest}, App};
+    use paperclip::actix::OpenApiExt;
+    use serde_json::{json, Value};
+
+    use crate::{auth, server, settings::StorageSettings};
+    use super::*;
+
+    fn settings() -> Settings {
+        serde_json::from_value(json!({
+            "server": { "address": "127.0.0.1", "port": 8080 },
+            // Nothing listens there, live conflict detection is skipped
+            "unifi": { "base_url": "http://127.0.0.1:9", "username": "kms", "password": "secret" },
+            "auth": { "enabled": false },
+            "provisioning": { "require_authentication": false },
+            "ipam": { "pools": [{
+                "name": "lab",
+                "cidr": "10.0.20.0/24",
+                "gateway": "10.0.20.1",
+                "dns_servers": ["10.0.20.1"],
+                "ipv6_prefix": "2001:db8:20::/64",
+                "ipv6_gateway": "2001:db8:20::1"
+            }] }
+        })).unwrap()
+    }
+
+    fn register(body: Value) -> TestRequest {
+        TestRequest::post().uri("/v1/devices/device").set_json(body)
+    }
+
+    #[actix_web::test]
+    async fn derives_slaac_addresses_from_the_pool() {
+        let app = test::init_service(App::new()
+            .wrap(auth::Authentication)
+            .wrap_api()
+            .app_data(Data::new(settings()))
+            .app_data(Data::new(Store::open(&StorageSettings::default()).unwrap()))
+            .configure(server::routes)
+            .build()).await;
+        let response = test::call_service(&app, register(json!({ "hostname": "node-1", "macAddress": "00:00:5e:00:53:01", "network": "lab", "ipv6Mode": "slaac" })).to_request()).await;
+        let status = response.status();
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["ipAddress"], "10.0.20.2");
+        assert_eq!(body["data"]["ipv6Address"], "2001:db8:20:0:200:5eff:fe00:5301");
+        assert_eq!(body["data"]["ipv6Mode"], "slaac");
+
+        let response = test::call_service(&app, TestRequest::get().uri("/v1/provision/00:00:5e:00:53:01/network-config").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("content-type").unwrap(), "text/yaml");
+        let config: Value = serde_yaml::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(config["ethernets"]["primary"]["addresses"], json!(["10.0.20.2/24"]));
+        assert_eq!(config["ethernets"]["primary"]["accept-ra"], true);
+    }
+
+    #[actix_web::test]
+    async fn keeps_static_ipv6_addresses_unique_and_inside_the_prefix() {
+        let app = test::init_service(App::new()
+            .wrap(auth::Authentication)
+            .wrap_api()
+            .app_data(Data::new(settings()))
+            .app_data(Data::new(Store::open(&StorageSettings::default()).unwrap()))
+            .configure(server::routes)
+            .build()).await;
+        let response = test::call_service(&app, register(json!({ "hostname": "node-1", "macAddress": "00:00:5e:00:53:01", "network": "lab", "ipv6Address": "2001:db8:20::11" })).to_request()).await;
+        let status = response.status();
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["ipv6Mode"], "static");
+
+        let response = test::call_service(&app, register(json!({ "hostname": "node-2", "macAddress": "00:00:5e:00:53:02", "network": "lab", "ipv6Address": "2001:db8:20::11" })).to_request()).await;
+        let status = response.status();
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["detail"], "Address 2001:db8:20::11 is already in use by 00:00:5e:00:53:01");
+
+        let response = test::call_service(&app, register(json!({ "hostname": "node-2", "macAddress": "00:00:5e:00:53:02", "network": "lab", "ipv6Address": "2001:db8:99::11" })).to_request()).await;
+        let status = response.status();
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errors"]["ipv6Address"][0]["code"], "range");
+    }
+
+    #[actix_web::test]
+    async fn refuses_slaac_with_a_given_address() {
+        let app = test::init_service(App::new()
+            .wrap(auth::Authentication)
+            .wrap_api()
+            .app_data(Data::new(settings()))
+            .app_data(Data::new(Store::open(&StorageSettings::default()).unwrap()))
+            .configure(server::routes)
+            .build()).await;
+        let response = test::call_service(&app, register(json!({ "hostname": "node-1", "macAddress": "00:00:5e:00:53:01", "network": "lab", "ipv6Mode": "slaac", "ipv6Address": "2001:db8:20::11" })).to_request()).await;
+        let status = response.status();
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errors"]["ipv6Address"][0]["code"], "slaac");
+
+        let response = test::call_service(&app, register(json!({ "hostname": "node-1", "macAddress": "00:00:5e:00:53:01", "ipAddress": "192.168.1.10", "ipv6Mode": "slaac" })).to_request()).await;
+        let status = response.status();
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errors"]["ipv6Mode"][0]["code"], "prefix");
+    }
 }
\ No newline at end of file
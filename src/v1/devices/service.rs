@@ -0,0 +1,161 @@
+use std::{collections::HashMap, net::Ipv4Addr};
+
+use log::{error, info};
+use validator::Validate;
+
+use crate::{auth::{Principal, Role, policy::Scope}, clients::unifi::UnifiApiClient, errors::{Errors, parse_validation_errors, parse_mac_address, validation_error}, v1::{devices::models::{device::{Device, Ipv6Mode}, requests::RegisterDevice, responses::{Drift, DriftKind}}, ipam::models::allocation}, settings::Settings, store::Store};
+
+pub fn get(principal: &Principal, store: &Store, mac_address: &str) -> Result<Device, Errors> {
+    let mac = parse_mac_address(mac_address)?;
+
+    info!("Searching for device with MAC: {}", mac);
+    match store.read(|tables| tables.devices.get(&mac.to_hex_string()).cloned()) {
+        Some(device) => {
+            principal.authorize(Role::Viewer, "read devices", Scope::device(&device))?;
+            info!("Found device with name: {}", &device.get_hostname());
+            Ok(device)
+        }
+        None => Err(Errors::NotFoundError)
+    }
+}
+
+/// The devices the principal may read, in MAC address order.
+pub fn list(principal: &Principal, store: &Store) -> Vec<Device> {
+    store.read(|tables| {
+        tables.devices.values().filter(|device| principal.can(Role::Viewer, Scope::device(device))).cloned().collect()
+    })
+}
+
+pub async fn register(principal: &Principal, request: RegisterDevice, settings: &Settings, store: &Store) -> Result<Device, Errors> {
+    if let Err(e) = request.validate() {
+        return Err(Errors::ValidationError { field_errors: parse_validation_errors(e) })
+    }
+    principal.authorize(Role::Operator, "register devices", Scope::new(request.get_cluster().as_deref(), request.get_network().as_deref()))?;
+
+    let mac = parse_mac_address(request.get_mac_address())?;
+    let owner = mac.to_hex_string();
+    let live_addresses = allocation::live_addresses(settings).await;
+
+    let device = store.transaction(|tables| {
+        if tables.devices.contains_key(&owner) {
+            return Err(Errors::ConflictError { message: format!("Device {} is already registered", owner) })
+        }
+
+        match (request.get_cluster(), request.get_role()) {
+            (Some(name), Some(_)) if !tables.clusters.contains_key(name) => return Err(validation_error("cluster", "unknown")),
+            (Some(_), None) => return Err(validation_error("role", "required")),
+            (None, Some(_)) => return Err(validation_error("cluster", "required")),
+            _ => {}
+        }
+
+        let mut in_use = live_addresses;
+        in_use.extend(allocation::stored_addresses(tables));
+
+        let pool = match request.get_network() {
+            Some(name) => match allocation::find_pool(settings, tables, name) {
+                Some(pool) => Some(pool),
+                None => return Err(validation_error("network", "unknown"))
+            },
+            None => None
+        };
+
+        let ip_address = match (request.get_ip_address(), &pool) {
+            (Some(address), _) => {
+                if pool.as_ref().is_some_and(|pool| !pool.contains(address)) {
+                    return Err(validation_error("ipAddress", "range"))
+                }
+                if let Some(holder) = in_use.get(address).filter(|holder| **holder != owner) {
+                    return Err(Errors::ConflictError { message: format!("Address {} is already in use by {}", address, holder) })
+                }
+                address.to_owned()
+            }
+            (None, Some(pool)) => match pool.next_free(&in_use, &owner) {
+                Some(address) => address,
+                None => return Err(Errors::ConflictError { message: format!("Pool {} has no free addresses left", pool.get_name()) })
+            },
+            (None, None) => return Err(validation_error("network", "required"))
+        };
+
+        // A static address without a network still picks up the IPv6 prefix of the pool it falls in
+        let pool = pool.or_else(|| allocation::list_pools(settings, tables).into_iter().find(|pool| pool.contains(&ip_address)));
+
+        let (ipv6_address, ipv6_mode) = match (request.get_ipv6_mode(), request.get_ipv6_address()) {
+            (Some(Ipv6Mode::Slaac), Some(_)) => return Err(validation_error("ipv6Address", "slaac")),
+            (Some(Ipv6Mode::Slaac), None) => match pool.as_ref().and_then(|pool| pool.slaac_address(&mac)) {
+                Some(address) => (Some(address), Some(Ipv6Mode::Slaac)),
+                None => return Err(validation_error("ipv6Mode", "prefix"))
+            },
+            (Some(Ipv6Mode::Static) | None, Some(address)) => {
+                if pool.as_ref().and_then(|pool| pool.ipv6_network()).is_some_and(|network| !network.contains(address)) {
+                    return Err(validation_error("ipv6Address", "range"))
+                }
+                if let Some(holder) = allocation::stored_ipv6_addresses(tables).get(address).filter(|holder| **holder != owner) {
+                    return Err(Errors::ConflictError { message: format!("Address {} is already in use by {}", address, holder) })
+                }
+                (Some(address.to_owned()), Some(Ipv6Mode::Static))
+            }
+            (Some(Ipv6Mode::Static), None) => return Err(validation_error("ipv6Address", "required")),
+            (None, None) => (None, None)
+        };
+
+        let mut device = Device::new(request.get_hostname(), mac, ip_address, request.get_network().to_owned());
+        device.set_ipv6_address(ipv6_address).set_ipv6_mode(ipv6_mode)
+            .set_cluster(request.get_cluster().to_owned()).set_role(request.get_role().to_owned());
+        tables.devices.insert(owner.clone(), device.clone());
+        Ok(device)
+    })?;
+
+    info!("Registered device {} ({}) with address {}", device.get_hostname(), device.get_mac_address(), device.get_ip_address());
+    Ok(device)
+}
+
+pub fn delete(principal: &Principal, store: &Store, mac_address: &str) -> Result<Device, Errors> {
+    let mac = parse_mac_address(mac_address)?;
+
+    let device = store.transaction(|tables| {
+        let device = tables.devices.get(&mac.to_hex_string()).ok_or(Errors::NotFoundError)?;
+        principal.authorize(Role::Admin, "delete devices", Scope::device(device))?;
+        tables.devices.remove(&mac.to_hex_string()).ok_or(Errors::NotFoundError)
+    })?;
+
+    info!("Deleted device {} and released address {}", device.get_mac_address(), device.get_ip_address());
+    Ok(device)
+}
+
+/// Compares the registered devices with the clients the UniFi controller currently sees. Devices
+/// that are offline are not reported, only addresses that differ from the registration.
+pub async fn reconcile(principal: &Principal, settings: &Settings, store: &Store) -> Result<Vec<Drift>, Errors> {
+    // The UniFi controller sees every network, so only unrestricted viewers may compare against it
+    principal.authorize(Role::Viewer, "reconcile devices", Scope::global())?;
+
+    let mut client = UnifiApiClient::from_settings(settings.get_unifi()).map_err(|error| {
+        error!("Unable to read the UniFi credentials: {}", error);
+        Errors::InternalServerError
+    })?;
+    let clients = client.list_client_devices().await.map_err(|error| {
+        error!("UniFi controller error: {}", error);
+        Errors::InternalServerError
+    })?;
+    let mut live: HashMap<String, Ipv4Addr> = HashMap::new();
+    let mut holders: HashMap<Ipv4Addr, String> = HashMap::new();
+    for client in clients.get_data() {
+        if let (Some(mac), Some(address)) = (client.get_mac(), client.get_ip().as_ref().and_then(|ip| ip.parse().ok())) {
+            live.insert(mac.to_lowercase(), address);
+            holders.insert(address, mac.to_lowercase());
+        }
+    }
+
+    let (registered, drifts): (usize, Vec<Drift>) = store.read(|tables| {
+        (tables.devices.len(), tables.devices.values().filter_map(|device| {
+            let expected = device.get_ip_address().to_owned();
+            match (live.get(device.get_mac_address()), holders.get(&expected)) {
+                (Some(actual), _) if *actual != expected => Some(Drift::new(device, DriftKind::AddressMismatch, Some(*actual), None)),
+                (_, Some(holder)) if holder != device.get_mac_address() => Some(Drift::new(device, DriftKind::AddressConflict, None, Some(holder.to_owned()))),
+                _ => None
+            }
+        }).collect())
+    });
+
+    info!("Compared {} devices with {} UniFi clients, {} differ", registered, live.len(), drifts.len());
+    Ok(drifts)
+}
@@ -0,0 +1,316 @@
+pub mod pool {
+    use std::{collections::HashMap, net::{IpAddr, Ipv4Addr, Ipv6Addr}};
+
+    use eui48::MacAddress;
+    use ipnet::{Ipv4Net, Ipv6Net, AddrParseError};
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use validator::{Validate, ValidationError, ValidationErrors};
+    use getset::{Getters};
+
+    use crate::settings::{PoolSettings, ReservedRangeSettings};
+
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct ReservedRange {
+        start: Ipv4Addr,
+        end: Ipv4Addr
+    }
+
+    impl ReservedRange {
+        pub fn contains(&self, address: &Ipv4Addr) -> bool {
+            self.start <= *address && *address <= self.end
+        }
+    }
+
+    impl From<&ReservedRangeSettings> for ReservedRange {
+        fn from(settings: &ReservedRangeSettings) -> Self {
+            ReservedRange {
+                start: settings.get_start().to_owned(),
+                end: settings.get_end().to_owned()
+            }
+        }
+    }
+
+    fn validate_cidr(cidr: &str) -> Result<(), ValidationError> {
+        match cidr.parse::<Ipv4Net>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ValidationError::new("cidr"))
+        }
+    }
+
+    fn validate_ipv6_prefix(prefix: &str) -> Result<(), ValidationError> {
+        match prefix.parse::<Ipv6Net>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ValidationError::new("cidr"))
+        }
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Validate, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct Pool {
+        #[validate(length(min = 1))]
+        name: String,
+        #[validate(custom = "validate_cidr")]
+        cidr: String,
+        gateway: Ipv4Addr,
+        #[serde(default)]
+        reserved: Vec<ReservedRange>,
+        #[serde(default)]
+        dns_servers: Vec<IpAddr>,
+        #[validate(custom = "validate_ipv6_prefix")]
+        ipv6_prefix: Option<String>,
+        ipv6_gateway: Option<Ipv6Addr>
+    }
+
+    impl Pool {
+        pub fn network(&self) -> Result<Ipv4Net, AddrParseError> {
+            self.cidr.parse()
+        }
+
+        pub fn ipv6_network(&self) -> Option<Ipv6Net> {
+            self.ipv6_prefix.as_ref()?.parse().ok()
+        }
+
+        /// Derives the modified EUI-64 address a SLAAC client with `mac` picks inside the pool's /64.
+        pub fn slaac_address(&self, mac: &MacAddress) -> Option<Ipv6Addr> {
+            let network = self.ipv6_network().filter(|network| network.prefix_len() == 64)?;
+            let bytes = mac.as_bytes();
+            let mut segments = network.network().segments();
+            segments[4] = u16::from_be_bytes([bytes[0] ^ 0x02, bytes[1]]);
+            segments[5] = u16::from_be_bytes([bytes[2], 0xff]);
+            segments[6] = u16::from_be_bytes([0xfe, bytes[3]]);
+            segments[7] = u16::from_be_bytes([bytes[4], bytes[5]]);
+            Some(Ipv6Addr::from(segments))
+        }
+
+        pub fn contains(&self, address: &Ipv4Addr) -> bool {
+            self.network().map(|network| network.contains(address)).unwrap_or(false)
+        }
+
+        pub fn is_reserved(&self, address: &Ipv4Addr) -> bool {
+            *address == self.gateway || self.reserved.iter().any(|range| range.contains(address))
+        }
+
+        /// Checks the pool beyond field validation: the gateway and reserved ranges have to sit inside the CIDR.
+        pub fn check(&self) -> Result<(), ValidationErrors> {
+            let mut errors = match self.validate() {
+                Ok(_) => ValidationErrors::new(),
+                Err(errors) => errors
+            };
+
+            if self.network().is_ok() {
+                if !self.contains(&self.gateway) {
+                    errors.add("gateway", ValidationError::new("range"));
+                }
+                if self.reserved.iter().any(|range| range.start > range.end || !self.contains(&range.start) || !self.contains(&range.end)) {
+                    errors.add("reserved", ValidationError::new("range"));
+                }
+            }
+
+            // SLAAC addresses only exist in a /64
+            if self.ipv6_network().is_some_and(|network| network.prefix_len() != 64) {
+                errors.add("ipv6_prefix", ValidationError::new("prefix_length"));
+            }
+
+            if let Some(gateway) = &self.ipv6_gateway {
+                match self.ipv6_network() {
+                    Some(network) if network.contains(gateway) => {}
+                    Some(_) => errors.add("ipv6_gateway", ValidationError::new("range")),
+                    None if self.ipv6_prefix.is_none() => errors.add("ipv6_prefix", ValidationError::new("required")),
+                    None => {}
+                }
+            }
+
+            if errors.is_empty() { Ok(()) } else { Err(errors) }
+        }
+
+        /// Returns the lowest host address that is neither reserved nor claimed by a MAC other than `owner`.
+        pub fn next_free(&self, in_use: &HashMap<Ipv4Addr, String>, owner: &str) -> Option<Ipv4Addr> {
+            self.network().ok()?.hosts().find(|address| {
+                !self.is_reserved(address) && in_use.get(address).is_none_or(|mac| mac == owner)
+            })
+        }
+    }
+
+    impl From<&PoolSettings> for Pool {
+        fn from(settings: &PoolSettings) -> Self {
+            Pool {
+                name: settings.get_name().to_owned(),
+                cidr: settings.get_cidr().to_string(),
+                gateway: settings.get_gateway().to_owned(),
+                reserved: settings.get_reserved().iter().map(ReservedRange::from).collect(),
+                dns_servers: settings.get_dns_servers().to_owned(),
+                ipv6_prefix: settings.get_ipv6_prefix().as_ref().map(|prefix| prefix.to_string()),
+                ipv6_gateway: settings.get_ipv6_gateway().to_owned()
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use serde_json::json;
+
+        use super::*;
+
+        fn pool() -> Pool {
+            serde_json::from_value(json!({
+                "name": "lab",
+                "cidr": "10.0.0.0/29",
+                "gateway": "10.0.0.1",
+                "reserved": [{ "start": "10.0.0.2", "end": "10.0.0.3" }],
+                "ipv6Prefix": "2001:db8:0:1::/64"
+            })).unwrap()
+        }
+
+        fn address(last: u8) -> Ipv4Addr {
+            Ipv4Addr::new(10, 0, 0, last)
+        }
+
+        #[test]
+        fn skips_the_gateway_and_reserved_ranges() {
+            assert_eq!(pool().next_free(&HashMap::new(), "00:11:22:33:44:55"), Some(address(4)));
+        }
+
+        #[test]
+        fn skips_addresses_of_other_devices() {
+            let in_use = HashMap::from([(address(4), "00:11:22:33:44:66".to_owned()), (address(5), "00:11:22:33:44:77".to_owned())]);
+            assert_eq!(pool().next_free(&in_use, "00:11:22:33:44:55"), Some(address(6)));
+        }
+
+        #[test]
+        fn hands_owners_their_address_again() {
+            let in_use = HashMap::from([(address(4), "00:11:22:33:44:55".to_owned())]);
+            assert_eq!(pool().next_free(&in_use, "00:11:22:33:44:55"), Some(address(4)));
+        }
+
+        #[test]
+        fn runs_out_of_addresses() {
+            // A /29 has the hosts .1 to .6, the network and broadcast addresses are never handed out
+            let in_use = HashMap::from([(address(4), "a".to_owned()), (address(5), "b".to_owned()), (address(6), "c".to_owned())]);
+            assert_eq!(pool().next_free(&in_use, "d"), None);
+        }
+
+        #[test]
+        fn derives_modified_eui64_addresses() {
+            let mac = MacAddress::parse_str("00:11:22:33:44:55").unwrap();
+            assert_eq!(pool().slaac_address(&mac), Some("2001:db8:0:1:211:22ff:fe33:4455".parse().unwrap()));
+
+            // The universal/local bit is flipped, not set
+            let local = MacAddress::parse_str("02:11:22:33:44:55").unwrap();
+            assert_eq!(pool().slaac_address(&local), Some("2001:db8:0:1:11:22ff:fe33:4455".parse().unwrap()));
+        }
+
+        #[test]
+        fn derives_no_address_outside_a_64() {
+            let mac = MacAddress::parse_str("00:11:22:33:44:55").unwrap();
+            let mut pool = pool();
+            pool.ipv6_prefix = Some("2001:db8::/48".to_owned());
+            assert_eq!(pool.slaac_address(&mac), None);
+            pool.ipv6_prefix = None;
+            assert_eq!(pool.slaac_address(&mac), None);
+        }
+
+        #[test]
+        fn checks_addresses_against_the_network() {
+            assert!(pool().check().is_ok());
+
+            let mut pool = pool();
+            pool.gateway = Ipv4Addr::new(10, 1, 0, 1);
+            pool.reserved.push(ReservedRange { start: address(5), end: address(4) });
+            pool.ipv6_gateway = Some("2001:db8:0:2::1".parse().unwrap());
+            let errors = pool.check().unwrap_err();
+            assert!(errors.field_errors().contains_key("gateway"));
+            assert!(errors.field_errors().contains_key("reserved"));
+            assert!(errors.field_errors().contains_key("ipv6_gateway"));
+        }
+
+        #[test]
+        fn requires_a_64_for_slaac() {
+            let mut pool = pool();
+            pool.ipv6_prefix = Some("2001:db8::/48".to_owned());
+            let errors = pool.check().unwrap_err();
+            assert_eq!(errors.field_errors()["ipv6_prefix"][0].code, "prefix_length");
+        }
+    }
+}
+
+pub mod allocation {
+    use std::{collections::HashMap, net::{Ipv4Addr, Ipv6Addr}};
+
+    use log::{info, warn};
+
+    use crate::{clients::unifi::UnifiApiClient, settings::Settings, store::Tables, v1::devices::models::device::Device};
+    use super::pool::Pool;
+
+    /// Pools from the settings file come first and shadow API-created pools of the same name.
+    pub fn list_pools(settings: &Settings, tables: &Tables) -> Vec<Pool> {
+        let mut pools: Vec<Pool> = settings.get_ipam().get_pools().iter().map(Pool::from).collect();
+        for pool in tables.pools.values() {
+            if !pools.iter().any(|existing| existing.get_name() == pool.get_name()) {
+                pools.push(pool.clone());
+            }
+        }
+        pools
+    }
+
+    pub fn find_pool(settings: &Settings, tables: &Tables, name: &str) -> Option<Pool> {
+        list_pools(settings, tables).into_iter().find(|pool| pool.get_name() == name)
+    }
+
+    pub fn is_configured(settings: &Settings, name: &str) -> bool {
+        settings.get_ipam().get_pools().iter().any(|pool| pool.get_name() == name)
+    }
+
+    /// Maps every address held by a registered device to the MAC address holding it.
+    pub fn stored_addresses(tables: &Tables) -> HashMap<Ipv4Addr, String> {
+        tables.devices.values()
+            .map(|device| (device.get_ip_address().to_owned(), device.get_mac_address().to_owned()))
+            .collect()
+    }
+
+    pub fn stored_ipv6_addresses(tables: &Tables) -> HashMap<Ipv6Addr, String> {
+        tables.devices.values()
+            .filter_map(|device| Some((device.get_ipv6_address().to_owned()?, device.get_mac_address().to_owned())))
+            .collect()
+    }
+
+    /// Finds the pool a device's addresses come from, either by its network or by the pool containing its address.
+    pub fn pool_for_device(settings: &Settings, tables: &Tables, device: &Device) -> Option<Pool> {
+        match device.get_network() {
+            Some(name) => find_pool(settings, tables, name),
+            None => list_pools(settings, tables).into_iter().find(|pool| pool.contains(device.get_ip_address()))
+        }
+    }
+
+    /// Maps every address currently leased to a UniFi client to its MAC address.
+    /// An unreachable controller only yields a warning so registrations keep working.
+    pub async fn live_addresses(settings: &Settings) -> HashMap<Ipv4Addr, String> {
+        let mut client = match UnifiApiClient::from_settings(settings.get_unifi()) {
+            Ok(client) => client,
+            Err(error) => {
+                warn!("Unable to read the UniFi credentials, skipping live conflict detection: {}", error);
+                return HashMap::new()
+            }
+        };
+        match client.list_client_devices().await {
+            Ok(response) => {
+                let addresses: HashMap<Ipv4Addr, String> = response.get_data().iter()
+                    .filter_map(|item| {
+                        let address = item.get_ip().as_ref()?.parse().ok()?;
+                        let mac = item.get_mac().as_ref()?.to_lowercase();
+                        Some((address, mac))
+                    })
+                    .collect();
+                info!("Loaded {} live client addresses from UniFi", addresses.len());
+                addresses
+            }
+            Err(error) => {
+                warn!("Unable to list UniFi clients, skipping live conflict detection: {}", error);
+                HashMap::new()
+            }
+        }
+    }
+}
\ No newline at end of file
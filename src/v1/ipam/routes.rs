@@ -0,0 +1,72 @@
+use crate::{auth::{Authenticated, Role, policy::Scope}, errors::{Errors, parse_validation_errors}, v1::{Response, ipam::models::{pool::Pool, allocation}}, settings::Settings, store::Store};
+
+use actix_web::{web::{Path, Data}, Result, HttpResponse};
+use log::info;
+use paperclip::actix::{web::Json, api_v2_operation, get, post, delete};
+
+
+#[api_v2_operation]
+#[get("/pools")]
+pub async fn list_pools(auth: Authenticated, settings: Data<Settings>, store: Data<Store>) -> Result<Json<Response<Vec<Pool>>>, Errors> {
+    let mut pools = store.read(|tables| allocation::list_pools(&settings, tables));
+    pools.retain(|pool| auth.0.can(Role::Viewer, Scope::site(pool.get_name())));
+    Ok(Json(Response { data: pools }))
+}
+
+#[api_v2_operation]
+#[get("/pools/{name}")]
+pub async fn get_pool(auth: Authenticated, path: Path<String>, settings: Data<Settings>, store: Data<Store>) -> Result<Json<Response<Pool>>, Errors> {
+    let name = path.into_inner();
+    auth.0.authorize(Role::Viewer, "read pools", Scope::site(&name))?;
+    match store.read(|tables| allocation::find_pool(&settings, tables, &name)) {
+        Some(pool) => Ok(Json(Response { data: pool })),
+        None => Err(Errors::NotFoundError)
+    }
+}
+
+#[api_v2_operation]
+#[post("/pools")]
+pub async fn create_pool(auth: Authenticated, body: Json<Pool>, settings: Data<Settings>, store: Data<Store>) -> Result<Json<Response<Pool>>, Errors> {
+    let pool = body.into_inner();
+    auth.0.authorize(Role::Admin, "create pools", Scope::site(pool.get_name()))?;
+
+    if let Err(e) = pool.check() {
+        return Err(Errors::ValidationError { field_errors: parse_validation_errors(e) })
+    }
+
+    let created = store.transaction(|tables| {
+        if allocation::find_pool(&settings, tables, pool.get_name()).is_some() {
+            return Err(Errors::ConflictError { message: format!("Pool {} already exists", pool.get_name()) })
+        }
+        tables.pools.insert(pool.get_name().to_owned(), pool.clone());
+        Ok(pool)
+    })?;
+
+    info!("Created pool {} ({})", created.get_name(), created.get_cidr());
+    Ok(Json(Response { data: created }))
+}
+
+#[api_v2_operation]
+#[delete("/pools/{name}")]
+pub async fn delete_pool(auth: Authenticated, path: Path<String>, settings: Data<Settings>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let name = path.into_inner();
+    auth.0.authorize(Role::Admin, "delete pools", Scope::site(&name))?;
+
+    if allocation::is_configured(&settings, &name) {
+        return Err(Errors::ConflictError { message: format!("Pool {} is defined in the settings and cannot be deleted", name) })
+    }
+
+    store.transaction(|tables| {
+        if !tables.pools.contains_key(&name) {
+            return Err(Errors::NotFoundError)
+        }
+        if tables.devices.values().any(|device| device.get_network().as_deref() == Some(name.as_str())) {
+            return Err(Errors::ConflictError { message: format!("Pool {} still has devices assigned", name) })
+        }
+        tables.pools.remove(&name);
+        Ok(())
+    })?;
+
+    info!("Deleted pool {}", name);
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,3 @@
+pub mod access;
+pub mod models;
+pub mod routes;
@@ -0,0 +1,114 @@
+use std::{future::{ready, Future, Ready}, pin::Pin, rc::Rc};
+
+use actix_web::{body::EitherBody, dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform}, web::{Data, Query}, Error, HttpRequest};
+use eui48::MacAddress;
+use log::warn;
+use serde_derive::Deserialize;
+
+use crate::{auth::signed_url, errors::{Errors, parse_mac_address}, settings::Settings, store::Store, tls::ClientCertificate, v1::devices::models::device::Device};
+
+#[derive(Deserialize)]
+struct Signature {
+    exp: u64,
+    sig: String
+}
+
+fn identifies(certificate: &ClientCertificate, device: &Device) -> bool {
+    certificate.names.iter().any(|name| name.eq_ignore_ascii_case(device.get_hostname())
+        || MacAddress::parse_str(name).is_ok_and(|mac| &mac.to_hex_string() == device.get_mac_address()))
+}
+
+fn has_signature(settings: &Settings, request: &HttpRequest, mac: &MacAddress) -> bool {
+    match Query::<Signature>::from_query(request.query_string()) {
+        Ok(signature) => signed_url::verify(settings.get_provisioning(), mac, signature.exp, &signature.sig),
+        Err(_) => false
+    }
+}
+
+/// Whether the client proved to be the device `mac` itself, with a URL signed for it or its
+/// client certificate.
+pub fn is_device(settings: &Settings, store: &Store, request: &HttpRequest, mac: &MacAddress) -> bool {
+    if has_signature(settings, request, mac) {
+        return true
+    }
+
+    let certificate = match request.conn_data::<ClientCertificate>() {
+        Some(certificate) => certificate,
+        None => return false
+    };
+    match store.read(|tables| tables.devices.get(&mac.to_hex_string()).cloned()) {
+        Some(device) if identifies(certificate, &device) => true,
+        Some(_) => {
+            warn!("Client certificate for {:?} does not belong to {}", certificate.names, mac);
+            false
+        }
+        None => false
+    }
+}
+
+/// Provisioning data carries join tokens and cluster keys, so unless
+/// `provisioning.require_authentication` is turned off only the node itself may fetch it.
+fn authorize(request: &ServiceRequest) -> Result<(), Errors> {
+    let settings = request.app_data::<Data<Settings>>().ok_or(Errors::InternalServerError)?;
+    if !settings.get_provisioning().get_require_authentication() {
+        return Ok(())
+    }
+
+    // Every provisioning route starts with the MAC address of the device
+    let segment = request.match_info().unprocessed().trim_start_matches('/').split('/').next().unwrap_or_default();
+    let mac = parse_mac_address(segment)?;
+    let store = request.app_data::<Data<Store>>().ok_or(Errors::InternalServerError)?;
+    if is_device(settings, store, request.request(), &mac) {
+        return Ok(())
+    }
+
+    warn!("Refused provisioning data of {} to an unauthenticated client", mac);
+    Err(Errors::UnauthorizedError)
+}
+
+/// Guards the provisioning scope, the routes below it don't check access themselves.
+pub struct ProvisioningAccess;
+
+impl<S, B> Transform<S, ServiceRequest> for ProvisioningAccess
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ProvisioningAccessMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ProvisioningAccessMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct ProvisioningAccessMiddleware<S> {
+    service: Rc<S>
+}
+
+impl<S, B> Service<ServiceRequest> for ProvisioningAccessMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            // Rejections become responses here instead of errors, so the error handlers wrapped
+            // around the app turn them into problems like every other error
+            if let Err(error) = authorize(&request) {
+                return Ok(request.error_response(error).map_into_right_body())
+            }
+            service.call(request).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
@@ -0,0 +1,1023 @@
+pub mod network {
+    use std::{collections::BTreeMap, net::IpAddr};
+
+    use serde_derive::Serialize;
+
+    use crate::v1::{devices::models::device::{Device, Ipv6Mode}, ipam::models::pool::Pool};
+
+    const INTERFACE: &str = "primary";
+
+    #[derive(Serialize)]
+    pub struct MatchRule {
+        macaddress: String
+    }
+
+    #[derive(Serialize)]
+    pub struct Route {
+        to: String,
+        via: IpAddr
+    }
+
+    #[derive(Serialize)]
+    pub struct Nameservers {
+        addresses: Vec<IpAddr>
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub struct Ethernet {
+        #[serde(rename = "match")]
+        matching: MatchRule,
+        dhcp4: bool,
+        dhcp6: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        accept_ra: Option<bool>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        addresses: Vec<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        routes: Vec<Route>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nameservers: Option<Nameservers>
+    }
+
+    /// Netplan style (cloud-init network-config version 2) description of a device's interface.
+    #[derive(Serialize)]
+    pub struct NetworkConfig {
+        version: u8,
+        ethernets: BTreeMap<String, Ethernet>
+    }
+
+    impl NetworkConfig {
+        /// Without a pool there is no prefix length or gateway to configure, so IPv4 falls back to DHCP.
+        pub fn for_device(device: &Device, pool: Option<&Pool>) -> NetworkConfig {
+            let mut ethernet = Ethernet {
+                matching: MatchRule { macaddress: device.get_mac_address().to_owned() },
+                dhcp4: true,
+                dhcp6: false,
+                accept_ra: None,
+                addresses: Vec::new(),
+                routes: Vec::new(),
+                nameservers: None
+            };
+
+            if let Some((pool, network)) = pool.and_then(|pool| Some((pool, pool.network().ok()?))) {
+                ethernet.dhcp4 = false;
+                ethernet.addresses.push(format!("{}/{}", device.get_ip_address(), network.prefix_len()));
+                ethernet.routes.push(Route { to: "0.0.0.0/0".to_owned(), via: IpAddr::V4(pool.get_gateway().to_owned()) });
+                if !pool.get_dns_servers().is_empty() {
+                    ethernet.nameservers = Some(Nameservers { addresses: pool.get_dns_servers().to_owned() });
+                }
+            }
+
+            let ipv6_gateway = pool.and_then(|pool| pool.get_ipv6_gateway().to_owned());
+            match (device.get_ipv6_mode(), device.get_ipv6_address()) {
+                (Some(Ipv6Mode::Slaac), _) => {
+                    ethernet.accept_ra = Some(true);
+                }
+                (_, Some(address)) => {
+                    let prefix_len = pool.and_then(|pool| pool.ipv6_network()).map_or(64, |network| network.prefix_len());
+                    ethernet.accept_ra = Some(false);
+                    ethernet.addresses.push(format!("{}/{}", address, prefix_len));
+                    if let Some(gateway) = ipv6_gateway {
+                        ethernet.routes.push(Route { to: "::/0".to_owned(), via: IpAddr::V6(gateway) });
+                    }
+                }
+                (_, None) => {}
+            }
+
+            let mut ethernets = BTreeMap::new();
+            ethernets.insert(INTERFACE.to_owned(), ethernet);
+            NetworkConfig { version: 2, ethernets }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use eui48::MacAddress;
+        use serde_json::{json, Value};
+
+        use crate::v1::devices::models::device::Ipv6Mode;
+        use super::*;
+
+        fn device() -> Device {
+            Device::new("node-1", MacAddress::parse_str("00:00:5e:00:53:01").unwrap(), "10.0.20.11".parse().unwrap(), Some("lab".to_owned()))
+        }
+
+        fn pool(dns_servers: &[&str]) -> Pool {
+            serde_json::from_value(json!({
+                "name": "lab",
+                "cidr": "10.0.20.0/24",
+                "gateway": "10.0.20.1",
+                "dnsServers": dns_servers,
+                "ipv6Prefix": "2001:db8:20::/64",
+                "ipv6Gateway": "2001:db8:20::1"
+            })).unwrap()
+        }
+
+        fn interface(device: &Device, pool: Option<&Pool>) -> Value {
+            let config = serde_json::to_value(NetworkConfig::for_device(device, pool)).unwrap();
+            assert_eq!(config["version"], 2);
+            config["ethernets"][INTERFACE].to_owned()
+        }
+
+        #[test]
+        fn configures_the_address_gateway_and_dns_of_the_pool() {
+            assert_eq!(interface(&device(), Some(&pool(&["10.0.20.1", "2001:db8:20::53"]))), json!({
+                "match": { "macaddress": "00:00:5e:00:53:01" },
+                "dhcp4": false,
+                "dhcp6": false,
+                "addresses": ["10.0.20.11/24"],
+                "routes": [{ "to": "0.0.0.0/0", "via": "10.0.20.1" }],
+                "nameservers": { "addresses": ["10.0.20.1", "2001:db8:20::53"] }
+            }));
+            assert!(interface(&device(), Some(&pool(&[]))).get("nameservers").is_none());
+        }
+
+        #[test]
+        fn falls_back_to_dhcp_without_a_pool() {
+            assert_eq!(interface(&device(), None), json!({
+                "match": { "macaddress": "00:00:5e:00:53:01" },
+                "dhcp4": true,
+                "dhcp6": false
+            }));
+        }
+
+        #[test]
+        fn adds_static_ipv6_addresses_with_the_pool_gateway() {
+            let mut device = device();
+            device.set_ipv6_address(Some("2001:db8:20::11".parse().unwrap())).set_ipv6_mode(Some(Ipv6Mode::Static));
+            let interface = interface(&device, Some(&pool(&[])));
+            assert_eq!(interface["accept-ra"], false);
+            assert_eq!(interface["addresses"], json!(["10.0.20.11/24", "2001:db8:20::11/64"]));
+            assert_eq!(interface["routes"][1], json!({ "to": "::/0", "via": "2001:db8:20::1" }));
+        }
+
+        #[test]
+        fn leaves_slaac_addresses_to_router_advertisements() {
+            let mut device = device();
+            device.set_ipv6_address(Some("2001:db8:20:0:200:5eff:fe00:5301".parse().unwrap())).set_ipv6_mode(Some(Ipv6Mode::Slaac));
+            let interface = interface(&device, Some(&pool(&[])));
+            assert_eq!(interface["accept-ra"], true);
+            assert_eq!(interface["addresses"], json!(["10.0.20.11/24"]));
+            assert_eq!(interface["routes"].as_array().unwrap().len(), 1);
+        }
+    }
+}
+
+pub mod context {
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use getset::{Getters};
+
+    use crate::{settings::Settings, store::Tables, v1::{devices::models::device::Device, clusters::models::cluster::Cluster, ipam::models::{pool::Pool, allocation}}};
+
+    /// Everything known about a device that provisioning artifacts are generated from.
+    /// `nodes` holds every device of the same cluster, including the device itself.
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct ProvisioningContext {
+        device: Device,
+        cluster: Option<Cluster>,
+        pool: Option<Pool>,
+        nodes: Vec<Device>
+    }
+
+    impl ProvisioningContext {
+        pub fn for_device(settings: &Settings, tables: &Tables, device: Device) -> ProvisioningContext {
+            let cluster = device.get_cluster().as_ref().and_then(|name| tables.clusters.get(name).cloned());
+            let nodes = match &cluster {
+                Some(cluster) => tables.devices.values()
+                    .filter(|node| node.get_cluster().as_deref() == Some(cluster.get_name().as_str()))
+                    .cloned()
+                    .collect(),
+                None => Vec::new()
+            };
+            let pool = allocation::pool_for_device(settings, tables, &device);
+            ProvisioningContext { device, cluster, pool, nodes }
+        }
+
+        pub fn without_secrets(mut self) -> ProvisioningContext {
+            self.cluster = self.cluster.map(Cluster::without_secrets);
+            self
+        }
+    }
+}
+
+pub mod kubeadm {
+    use std::collections::BTreeMap;
+
+    use serde_derive::Serialize;
+
+    use crate::{errors::Errors, v1::{clusters::models::cluster::{Cluster, NodeRole}, devices::models::device::Device, tokens::models::{token, service::NodeTokens}}};
+    use super::context::ProvisioningContext;
+
+    const API_VERSION: &str = "kubeadm.k8s.io/v1beta3";
+    const API_SERVER_PORT: u16 = 6443;
+    const ETCD_CLIENT_PORT: u16 = 2379;
+    const ETCD_PEER_PORT: u16 = 2380;
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct NodeRegistration {
+        name: String,
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        kubelet_extra_args: BTreeMap<String, String>
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ApiEndpoint {
+        advertise_address: String,
+        bind_port: u16
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct BootstrapToken {
+        token: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+        ttl: String,
+        groups: Vec<String>,
+        usages: Vec<String>
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InitConfiguration {
+        api_version: &'static str,
+        kind: &'static str,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        bootstrap_tokens: Vec<BootstrapToken>,
+        node_registration: NodeRegistration,
+        #[serde(rename = "localAPIEndpoint")]
+        local_api_endpoint: ApiEndpoint,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        certificate_key: Option<String>
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Networking {
+        pod_subnet: String,
+        service_subnet: String
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct LocalEtcd {
+        #[serde(rename = "serverCertSANs")]
+        server_cert_sans: Vec<String>,
+        #[serde(rename = "peerCertSANs")]
+        peer_cert_sans: Vec<String>,
+        extra_args: BTreeMap<String, String>
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ExternalEtcd {
+        endpoints: Vec<String>,
+        ca_file: &'static str,
+        cert_file: &'static str,
+        key_file: &'static str
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub enum Etcd {
+        Local(LocalEtcd),
+        External(ExternalEtcd)
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ClusterConfiguration {
+        api_version: &'static str,
+        kind: &'static str,
+        cluster_name: String,
+        kubernetes_version: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        control_plane_endpoint: Option<String>,
+        networking: Networking,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        etcd: Option<Etcd>
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct BootstrapTokenDiscovery {
+        api_server_endpoint: String,
+        token: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        ca_cert_hashes: Vec<String>,
+        #[serde(rename = "unsafeSkipCAVerification", skip_serializing_if = "std::ops::Not::not")]
+        unsafe_skip_ca_verification: bool
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Discovery {
+        bootstrap_token: BootstrapTokenDiscovery
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct JoinControlPlane {
+        #[serde(rename = "localAPIEndpoint")]
+        local_api_endpoint: ApiEndpoint,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        certificate_key: Option<String>
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct JoinConfiguration {
+        api_version: &'static str,
+        kind: &'static str,
+        node_registration: NodeRegistration,
+        discovery: Discovery,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        control_plane: Option<JoinControlPlane>
+    }
+
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    pub enum Document {
+        Init(InitConfiguration),
+        Cluster(ClusterConfiguration),
+        Join(JoinConfiguration)
+    }
+
+    fn conflict(message: &str) -> Errors {
+        Errors::ConflictError { message: message.to_owned() }
+    }
+
+    fn normalize_mac(mac_address: &str) -> String {
+        mac_address.to_lowercase().replace('-', ":")
+    }
+
+    fn members(context: &ProvisioningContext, role: NodeRole) -> Vec<&Device> {
+        context.get_nodes().iter().filter(|node| node.get_role() == &Some(role)).collect()
+    }
+
+    fn node_registration(device: &Device) -> NodeRegistration {
+        let mut kubelet_extra_args = BTreeMap::new();
+        let node_ip = match device.get_ipv6_address() {
+            Some(ipv6_address) => format!("{},{}", device.get_ip_address(), ipv6_address),
+            None => device.get_ip_address().to_string()
+        };
+        kubelet_extra_args.insert("node-ip".to_owned(), node_ip);
+        NodeRegistration { name: device.get_hostname().to_owned(), kubelet_extra_args }
+    }
+
+    fn api_endpoint(device: &Device) -> ApiEndpoint {
+        ApiEndpoint { advertise_address: device.get_ip_address().to_string(), bind_port: API_SERVER_PORT }
+    }
+
+    fn cluster_configuration(cluster: &Cluster, control_plane_endpoint: Option<String>, etcd: Option<Etcd>) -> ClusterConfiguration {
+        ClusterConfiguration {
+            api_version: API_VERSION,
+            kind: "ClusterConfiguration",
+            cluster_name: cluster.get_name().to_owned(),
+            kubernetes_version: cluster.get_kubernetes_version().to_owned(),
+            control_plane_endpoint,
+            networking: Networking {
+                pod_subnet: cluster.get_pod_cidr().to_owned(),
+                service_subnet: cluster.get_service_cidr().to_owned()
+            },
+            etcd
+        }
+    }
+
+    /// Control plane nodes use the dedicated etcd nodes of the cluster when there are any,
+    /// otherwise kubeadm runs stacked etcd on the control plane itself.
+    fn external_etcd(context: &ProvisioningContext) -> Option<Etcd> {
+        let etcd_nodes = members(context, NodeRole::Etcd);
+        if etcd_nodes.is_empty() {
+            return None
+        }
+        Some(Etcd::External(ExternalEtcd {
+            endpoints: etcd_nodes.iter().map(|node| format!("https://{}:{}", node.get_ip_address(), ETCD_CLIENT_PORT)).collect(),
+            ca_file: "/etc/kubernetes/pki/etcd/ca.crt",
+            cert_file: "/etc/kubernetes/pki/apiserver-etcd-client.crt",
+            key_file: "/etc/kubernetes/pki/apiserver-etcd-client.key"
+        }))
+    }
+
+    /// Follows the kubeadm guide for an external etcd cluster: every member gets a local etcd
+    /// configured with the full initial cluster.
+    fn local_etcd(context: &ProvisioningContext, device: &Device) -> Etcd {
+        let initial_cluster = members(context, NodeRole::Etcd).iter()
+            .map(|node| format!("{}=https://{}:{}", node.get_hostname(), node.get_ip_address(), ETCD_PEER_PORT))
+            .collect::<Vec<String>>()
+            .join(",");
+        let address = device.get_ip_address().to_string();
+
+        let mut extra_args = BTreeMap::new();
+        extra_args.insert("name".to_owned(), device.get_hostname().to_owned());
+        extra_args.insert("initial-cluster".to_owned(), initial_cluster);
+        extra_args.insert("initial-cluster-state".to_owned(), "new".to_owned());
+        extra_args.insert("listen-peer-urls".to_owned(), format!("https://{}:{}", address, ETCD_PEER_PORT));
+        extra_args.insert("listen-client-urls".to_owned(), format!("https://{}:{}", address, ETCD_CLIENT_PORT));
+        extra_args.insert("advertise-client-urls".to_owned(), format!("https://{}:{}", address, ETCD_CLIENT_PORT));
+        extra_args.insert("initial-advertise-peer-urls".to_owned(), format!("https://{}:{}", address, ETCD_PEER_PORT));
+
+        Etcd::Local(LocalEtcd {
+            server_cert_sans: vec![address.clone()],
+            peer_cert_sans: vec![address],
+            extra_args
+        })
+    }
+
+    pub fn is_init_node(context: &ProvisioningContext, cluster: &Cluster, device: &Device) -> bool {
+        match cluster.get_init_node() {
+            Some(init_node) => normalize_mac(init_node) == *device.get_mac_address(),
+            None => members(context, NodeRole::ControlPlane).iter()
+                .map(|node| node.get_mac_address())
+                .min()
+                .is_some_and(|mac_address| mac_address == device.get_mac_address())
+        }
+    }
+
+    fn join_configuration(cluster: &Cluster, device: &Device, token: String, control_plane: Option<JoinControlPlane>) -> Result<JoinConfiguration, Errors> {
+        let skip_ca_verification = cluster.get_ca_cert_hashes().is_empty();
+        if skip_ca_verification && !cluster.get_unsafe_skip_ca_verification() {
+            return Err(conflict("Cluster has no caCertHashes"))
+        }
+        Ok(JoinConfiguration {
+            api_version: API_VERSION,
+            kind: "JoinConfiguration",
+            node_registration: node_registration(device),
+            discovery: Discovery {
+                bootstrap_token: BootstrapTokenDiscovery {
+                    api_server_endpoint: cluster.get_api_endpoint().to_owned(),
+                    token,
+                    ca_cert_hashes: cluster.get_ca_cert_hashes().to_owned(),
+                    unsafe_skip_ca_verification: skip_ca_verification
+                }
+            },
+            control_plane
+        })
+    }
+
+    /// Builds the kubeadm documents for the device in `context`: `InitConfiguration` plus
+    /// `ClusterConfiguration` for the init node and etcd members, `JoinConfiguration` for everyone else.
+    pub fn documents(context: &ProvisioningContext, tokens: &NodeTokens) -> Result<Vec<Document>, Errors> {
+        let device = context.get_device();
+        let cluster = context.get_cluster().as_ref().ok_or_else(|| conflict("Device is not assigned to a cluster"))?;
+        let role = device.get_role().ok_or_else(|| conflict("Device has no cluster role"))?;
+        let now = token::now();
+        let token = tokens.join.as_ref().map(|token| token.token());
+
+        match role {
+            NodeRole::Etcd => Ok(vec![
+                Document::Init(InitConfiguration {
+                    api_version: API_VERSION,
+                    kind: "InitConfiguration",
+                    bootstrap_tokens: Vec::new(),
+                    node_registration: node_registration(device),
+                    local_api_endpoint: api_endpoint(device),
+                    certificate_key: None
+                }),
+                Document::Cluster(cluster_configuration(cluster, None, Some(local_etcd(context, device))))
+            ]),
+            NodeRole::ControlPlane if is_init_node(context, cluster, device) => Ok(vec![
+                Document::Init(InitConfiguration {
+                    api_version: API_VERSION,
+                    kind: "InitConfiguration",
+                    bootstrap_tokens: tokens.cluster.iter().map(|token| BootstrapToken {
+                        token: token.token(),
+                        description: token.get_description().to_owned(),
+                        ttl: format!("{}s", token.remaining_seconds(now)),
+                        groups: vec!["system:bootstrappers:kubeadm:default-node-token".to_owned()],
+                        usages: vec!["signing".to_owned(), "authentication".to_owned()]
+                    }).collect(),
+                    node_registration: node_registration(device),
+                    local_api_endpoint: api_endpoint(device),
+                    certificate_key: cluster.get_certificate_key().to_owned()
+                }),
+                Document::Cluster(cluster_configuration(cluster, Some(cluster.get_api_endpoint().to_owned()), external_etcd(context)))
+            ]),
+            NodeRole::ControlPlane => {
+                let token = token.ok_or_else(|| conflict("No bootstrap token available to join with"))?;
+                let control_plane = JoinControlPlane {
+                    local_api_endpoint: api_endpoint(device),
+                    certificate_key: cluster.get_certificate_key().to_owned()
+                };
+                Ok(vec![Document::Join(join_configuration(cluster, device, token, Some(control_plane))?)])
+            }
+            NodeRole::Worker => {
+                let token = token.ok_or_else(|| conflict("No bootstrap token available to join with"))?;
+                Ok(vec![Document::Join(join_configuration(cluster, device, token, None)?)])
+            }
+        }
+    }
+    #[cfg(test)]
+    mod tests {
+        use eui48::MacAddress;
+        use serde_json::{json, Value};
+
+        use crate::{settings::Settings, store::Tables, v1::tokens::models::{service::NodeTokens, token::BootstrapToken}};
+        use super::*;
+
+        const CERTIFICATE_KEY: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        const CA_CERT_HASH: &str = "sha256:fedcba9876543210fedcba9876543210fedcba9876543210fedcba9876543210";
+
+        fn cluster(ca_cert_hashes: &[&str], unsafe_skip_ca_verification: bool) -> Cluster {
+            serde_json::from_value(json!({
+                "name": "prod",
+                "kubernetesVersion": "1.27.3",
+                "apiEndpoint": "10.0.0.10:6443",
+                "podCidr": "10.244.0.0/16",
+                "serviceCidr": "10.96.0.0/12",
+                "cni": "calico",
+                "certificateKey": CERTIFICATE_KEY,
+                "caCertHashes": ca_cert_hashes,
+                "unsafeSkipCaVerification": unsafe_skip_ca_verification
+            })).unwrap()
+        }
+
+        fn node(hostname: &str, mac_address: &str, ip_address: &str, role: NodeRole) -> Device {
+            let mut device = Device::new(hostname, MacAddress::parse_str(mac_address).unwrap(), ip_address.parse().unwrap(), None);
+            device.set_cluster(Some("prod".to_owned()));
+            device.set_role(Some(role));
+            device
+        }
+
+        fn nodes() -> Vec<Device> {
+            vec![
+                node("cp-1", "00:00:5e:00:53:01", "10.0.0.11", NodeRole::ControlPlane),
+                node("cp-2", "00:00:5e:00:53:02", "10.0.0.12", NodeRole::ControlPlane),
+                node("worker-1", "00:00:5e:00:53:03", "10.0.0.21", NodeRole::Worker)
+            ]
+        }
+
+        fn context(cluster: Cluster, hostname: &str) -> ProvisioningContext {
+            let settings: Settings = serde_json::from_value(json!({
+                "server": { "address": "127.0.0.1", "port": 8080 },
+                "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" }
+            })).unwrap();
+            let mut tables = Tables::default();
+            tables.clusters.insert(cluster.get_name().to_owned(), cluster);
+            for node in nodes() {
+                tables.devices.insert(node.get_mac_address().to_owned(), node);
+            }
+            let device = nodes().into_iter().find(|node| node.get_hostname() == hostname).unwrap();
+            ProvisioningContext::for_device(&settings, &tables, device)
+        }
+
+        fn join_token() -> BootstrapToken {
+            BootstrapToken::generate("prod", Some("00:00:5e:00:53:02".to_owned()), None, 3600)
+        }
+
+        fn rendered(result: Result<Vec<Document>, Errors>) -> Vec<Value> {
+            match result {
+                Ok(documents) => documents.iter().map(|document| serde_json::to_value(document).unwrap()).collect(),
+                Err(error) => panic!("no documents: {}", error)
+            }
+        }
+
+        #[test]
+        fn initializes_the_cluster_on_the_lowest_control_plane() {
+            let issued = BootstrapToken::generate("prod", Some("00:00:5e:00:53:03".to_owned()), None, 3600);
+            let tokens = NodeTokens { join: None, cluster: vec![issued.clone()], publish: None, withdraw: None };
+            let documents = rendered(documents(&context(cluster(&[CA_CERT_HASH], false), "cp-1"), &tokens));
+
+            assert_eq!(documents.len(), 2);
+            assert_eq!(documents[0]["kind"], "InitConfiguration");
+            assert_eq!(documents[0]["certificateKey"], CERTIFICATE_KEY);
+            assert_eq!(documents[0]["localAPIEndpoint"]["advertiseAddress"], "10.0.0.11");
+            assert_eq!(documents[0]["bootstrapTokens"][0]["token"], issued.token());
+            assert_eq!(documents[1]["kind"], "ClusterConfiguration");
+            assert_eq!(documents[1]["controlPlaneEndpoint"], "10.0.0.10:6443");
+        }
+
+        #[test]
+        fn joins_further_control_planes_with_the_certificate_key() {
+            let token = join_token();
+            let tokens = NodeTokens { join: Some(token.clone()), cluster: Vec::new(), publish: None, withdraw: None };
+            let documents = rendered(documents(&context(cluster(&[CA_CERT_HASH], false), "cp-2"), &tokens));
+
+            assert_eq!(documents.len(), 1);
+            assert_eq!(documents[0]["kind"], "JoinConfiguration");
+            assert_eq!(documents[0]["controlPlane"]["certificateKey"], CERTIFICATE_KEY);
+            assert_eq!(documents[0]["controlPlane"]["localAPIEndpoint"]["advertiseAddress"], "10.0.0.12");
+            assert_eq!(documents[0]["discovery"]["bootstrapToken"]["token"], token.token());
+        }
+
+        #[test]
+        fn joins_workers_without_a_control_plane_section() {
+            let tokens = NodeTokens { join: Some(join_token()), cluster: Vec::new(), publish: None, withdraw: None };
+            let documents = rendered(documents(&context(cluster(&[CA_CERT_HASH], false), "worker-1"), &tokens));
+
+            assert_eq!(documents[0]["kind"], "JoinConfiguration");
+            assert!(documents[0].get("controlPlane").is_none());
+        }
+
+        #[test]
+        fn pins_the_ca_when_hashes_are_known() {
+            let tokens = NodeTokens { join: Some(join_token()), cluster: Vec::new(), publish: None, withdraw: None };
+            let documents = rendered(documents(&context(cluster(&[CA_CERT_HASH], false), "worker-1"), &tokens));
+
+            let discovery = &documents[0]["discovery"]["bootstrapToken"];
+            assert_eq!(discovery["caCertHashes"], json!([CA_CERT_HASH]));
+            assert!(discovery.get("unsafeSkipCAVerification").is_none());
+        }
+
+        #[test]
+        fn skips_ca_verification_only_when_allowed() {
+            let tokens = NodeTokens { join: Some(join_token()), cluster: Vec::new(), publish: None, withdraw: None };
+            let documents = rendered(documents(&context(cluster(&[], true), "worker-1"), &tokens));
+            let discovery = &documents[0]["discovery"]["bootstrapToken"];
+            assert_eq!(discovery["unsafeSkipCAVerification"], true);
+            assert!(discovery.get("caCertHashes").is_none());
+
+            let tokens = NodeTokens { join: Some(join_token()), cluster: Vec::new(), publish: None, withdraw: None };
+            let result = super::documents(&context(cluster(&[], false), "worker-1"), &tokens);
+            assert!(matches!(result, Err(Errors::ConflictError { .. })));
+        }
+
+        #[test]
+        fn needs_a_token_to_join() {
+            let tokens = NodeTokens { join: None, cluster: Vec::new(), publish: None, withdraw: None };
+            let result = documents(&context(cluster(&[CA_CERT_HASH], false), "worker-1"), &tokens);
+            assert!(matches!(result, Err(Errors::ConflictError { .. })));
+        }
+    }
+}
+
+pub mod talos {
+    use serde_derive::Serialize;
+
+    use crate::{errors::Errors, v1::clusters::models::{cluster::{Cni, NodeRole}, talos::{CertAndKey, TalosSecrets}}};
+    use super::context::ProvisioningContext;
+
+    const DEFAULT_INSTALL_DISK: &str = "/dev/sda";
+
+    #[derive(Serialize)]
+    pub struct PemPair {
+        crt: String,
+        key: String
+    }
+
+    impl PemPair {
+        fn with_key(pair: &CertAndKey) -> PemPair {
+            PemPair { crt: pair.get_crt().to_owned(), key: pair.get_key().to_owned() }
+        }
+
+        // Workers only get to verify against the CA
+        fn without_key(pair: &CertAndKey) -> PemPair {
+            PemPair { crt: pair.get_crt().to_owned(), key: String::new() }
+        }
+    }
+
+    #[derive(Serialize)]
+    pub struct KeyOnly {
+        key: String
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DeviceSelector {
+        hardware_addr: String
+    }
+
+    #[derive(Serialize)]
+    pub struct Route {
+        network: String,
+        gateway: String
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Interface {
+        device_selector: DeviceSelector,
+        dhcp: bool,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        addresses: Vec<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        routes: Vec<Route>
+    }
+
+    #[derive(Serialize)]
+    pub struct Network {
+        hostname: String,
+        interfaces: Vec<Interface>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        nameservers: Vec<String>
+    }
+
+    #[derive(Serialize)]
+    pub struct Install {
+        disk: String,
+        image: String,
+        wipe: bool
+    }
+
+    #[derive(Serialize)]
+    pub struct Kubelet {
+        image: String
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Machine {
+        #[serde(rename = "type")]
+        machine_type: &'static str,
+        token: String,
+        ca: PemPair,
+        #[serde(rename = "certSANs")]
+        cert_sans: Vec<String>,
+        kubelet: Kubelet,
+        network: Network,
+        install: Install
+    }
+
+    #[derive(Serialize)]
+    pub struct ControlPlane {
+        endpoint: String
+    }
+
+    #[derive(Serialize)]
+    pub struct CniConfig {
+        name: &'static str
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ClusterNetwork {
+        dns_domain: &'static str,
+        pod_subnets: Vec<String>,
+        service_subnets: Vec<String>,
+        cni: CniConfig
+    }
+
+    #[derive(Serialize)]
+    pub struct Etcd {
+        ca: PemPair
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ClusterSection {
+        id: String,
+        secret: String,
+        control_plane: ControlPlane,
+        cluster_name: String,
+        network: ClusterNetwork,
+        token: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        secretbox_encryption_secret: Option<String>,
+        ca: PemPair,
+        #[serde(rename = "aggregatorCA", skip_serializing_if = "Option::is_none")]
+        aggregator_ca: Option<PemPair>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        service_account: Option<KeyOnly>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        etcd: Option<Etcd>
+    }
+
+    /// Talos `v1alpha1` machine configuration, equivalent to what `talosctl gen config` writes
+    /// for `controlplane.yaml` and `worker.yaml`.
+    #[derive(Serialize)]
+    pub struct MachineConfig {
+        version: &'static str,
+        debug: bool,
+        persist: bool,
+        machine: Machine,
+        cluster: ClusterSection
+    }
+
+    fn conflict(message: &str) -> Errors {
+        Errors::ConflictError { message: message.to_owned() }
+    }
+
+    fn with_version_prefix(version: &str) -> String {
+        if version.starts_with('v') { version.to_owned() } else { format!("v{}", version) }
+    }
+
+    fn primary_interface(context: &ProvisioningContext) -> (Interface, Vec<String>) {
+        let device = context.get_device();
+        let mut interface = Interface {
+            device_selector: DeviceSelector { hardware_addr: device.get_mac_address().to_owned() },
+            dhcp: true,
+            addresses: Vec::new(),
+            routes: Vec::new()
+        };
+        let mut nameservers = Vec::new();
+
+        if let Some((pool, network)) = context.get_pool().as_ref().and_then(|pool| Some((pool, pool.network().ok()?))) {
+            interface.dhcp = false;
+            interface.addresses.push(format!("{}/{}", device.get_ip_address(), network.prefix_len()));
+            interface.routes.push(Route { network: "0.0.0.0/0".to_owned(), gateway: pool.get_gateway().to_string() });
+            nameservers = pool.get_dns_servers().iter().map(|server| server.to_string()).collect();
+
+            if let Some(address) = device.get_ipv6_address() {
+                let prefix_len = pool.ipv6_network().map_or(64, |network| network.prefix_len());
+                interface.addresses.push(format!("{}/{}", address, prefix_len));
+                if let Some(gateway) = pool.get_ipv6_gateway() {
+                    interface.routes.push(Route { network: "::/0".to_owned(), gateway: gateway.to_string() });
+                }
+            }
+        }
+
+        (interface, nameservers)
+    }
+
+    pub fn machine_config(context: &ProvisioningContext, secrets: &TalosSecrets) -> Result<MachineConfig, Errors> {
+        let device = context.get_device();
+        let cluster = context.get_cluster().as_ref().ok_or_else(|| conflict("Device is not assigned to a cluster"))?;
+        let talos_version = cluster.get_talos_version().as_ref().ok_or_else(|| conflict("Cluster has no Talos version configured"))?;
+        let control_plane = match device.get_role() {
+            Some(NodeRole::ControlPlane) => true,
+            Some(NodeRole::Worker) => false,
+            Some(NodeRole::Etcd) => return Err(conflict("Talos runs etcd on its control plane nodes, dedicated etcd nodes are not supported")),
+            None => return Err(conflict("Device has no cluster role"))
+        };
+
+        let install_disk = device.get_inventory().as_ref()
+            .and_then(|inventory| inventory.install_disk())
+            .map_or(DEFAULT_INSTALL_DISK.to_owned(), |disk| disk.get_name().to_owned());
+        let endpoint = if cluster.get_api_endpoint().starts_with("https://") {
+            cluster.get_api_endpoint().to_owned()
+        } else {
+            format!("https://{}", cluster.get_api_endpoint())
+        };
+        let cni = match cluster.get_cni() {
+            Cni::Flannel => "flannel",
+            // Calico and Cilium are installed after bootstrap, Talos must not deploy its own CNI
+            Cni::Calico | Cni::Cilium | Cni::None => "none"
+        };
+        let certs = secrets.get_certs();
+        let (interface, nameservers) = primary_interface(context);
+
+        Ok(MachineConfig {
+            version: "v1alpha1",
+            debug: false,
+            persist: true,
+            machine: Machine {
+                machine_type: if control_plane { "controlplane" } else { "worker" },
+                token: secrets.get_trustdinfo().get_token().to_owned(),
+                ca: if control_plane { PemPair::with_key(certs.get_os()) } else { PemPair::without_key(certs.get_os()) },
+                cert_sans: Vec::new(),
+                kubelet: Kubelet { image: format!("ghcr.io/siderolabs/kubelet:{}", with_version_prefix(cluster.get_kubernetes_version())) },
+                network: Network {
+                    hostname: device.get_hostname().to_owned(),
+                    interfaces: vec![interface],
+                    nameservers
+                },
+                install: Install {
+                    disk: install_disk,
+                    image: format!("ghcr.io/siderolabs/installer:{}", with_version_prefix(talos_version)),
+                    wipe: false
+                }
+            },
+            cluster: ClusterSection {
+                id: secrets.get_cluster().get_id().to_owned(),
+                secret: secrets.get_cluster().get_secret().to_owned(),
+                control_plane: ControlPlane { endpoint },
+                cluster_name: cluster.get_name().to_owned(),
+                network: ClusterNetwork {
+                    dns_domain: "cluster.local",
+                    pod_subnets: vec![cluster.get_pod_cidr().to_owned()],
+                    service_subnets: vec![cluster.get_service_cidr().to_owned()],
+                    cni: CniConfig { name: cni }
+                },
+                token: secrets.get_secrets().get_bootstraptoken().to_owned(),
+                secretbox_encryption_secret: control_plane.then(|| secrets.get_secrets().get_secretboxencryptionsecret().to_owned()),
+                ca: if control_plane { PemPair::with_key(certs.get_k8s()) } else { PemPair::without_key(certs.get_k8s()) },
+                aggregator_ca: control_plane.then(|| PemPair::with_key(certs.get_k8saggregator())),
+                service_account: control_plane.then(|| KeyOnly { key: certs.get_k8sserviceaccount().get_key().to_owned() }),
+                etcd: control_plane.then(|| Etcd { ca: PemPair::with_key(certs.get_etcd()) })
+            }
+        })
+    }
+    #[cfg(test)]
+    mod tests {
+        use eui48::MacAddress;
+        use serde_json::{json, Value};
+
+        use crate::{settings::Settings, store::Tables, v1::{clusters::models::cluster::Cluster, devices::models::device::Device}};
+        use super::*;
+
+        fn pair(name: &str) -> Value {
+            json!({ "crt": format!("{} certificate", name), "key": format!("{} key", name) })
+        }
+
+        fn secrets() -> TalosSecrets {
+            serde_json::from_value(json!({
+                "cluster": { "id": "cluster id", "secret": "cluster secret" },
+                "secrets": { "bootstraptoken": "abcdef.0123456789abcdef", "secretboxencryptionsecret": "secretbox" },
+                "trustdinfo": { "token": "trustd token" },
+                "certs": { "etcd": pair("etcd"), "k8s": pair("k8s"), "k8saggregator": pair("aggregator"), "k8sserviceaccount": { "key": "service account key" }, "os": pair("os") }
+            })).unwrap()
+        }
+
+        fn context(role: NodeRole, talos_version: Option<&str>, inventory: Option<Value>) -> ProvisioningContext {
+            let settings: Settings = serde_json::from_value(json!({
+                "server": { "address": "127.0.0.1", "port": 8080 },
+                "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" }
+            })).unwrap();
+            let cluster: Cluster = serde_json::from_value(json!({
+                "name": "prod",
+                "kubernetesVersion": "1.27.3",
+                "apiEndpoint": "10.0.0.10:6443",
+                "podCidr": "10.244.0.0/16",
+                "serviceCidr": "10.96.0.0/12",
+                "cni": "flannel",
+                "talosVersion": talos_version
+            })).unwrap();
+            let mut device = Device::new("node-1", MacAddress::parse_str("00:00:5e:00:53:01").unwrap(), "10.0.0.11".parse().unwrap(), None);
+            device.set_cluster(Some("prod".to_owned()));
+            device.set_role(Some(role));
+            device.set_inventory(inventory.map(|inventory| serde_json::from_value(inventory).unwrap()));
+
+            let mut tables = Tables::default();
+            tables.clusters.insert("prod".to_owned(), cluster);
+            tables.devices.insert(device.get_mac_address().to_owned(), device.clone());
+            ProvisioningContext::for_device(&settings, &tables, device)
+        }
+
+        fn rendered(context: &ProvisioningContext) -> Value {
+            match machine_config(context, &secrets()) {
+                Ok(config) => serde_json::to_value(config).unwrap(),
+                Err(error) => panic!("no machine config: {}", error)
+            }
+        }
+
+        #[test]
+        fn gives_control_planes_every_key() {
+            let config = rendered(&context(NodeRole::ControlPlane, Some("1.5.2"), None));
+            assert_eq!(config["machine"]["type"], "controlplane");
+            assert_eq!(config["machine"]["ca"]["key"], "os key");
+            assert_eq!(config["cluster"]["ca"]["key"], "k8s key");
+            assert_eq!(config["cluster"]["aggregatorCA"]["key"], "aggregator key");
+            assert_eq!(config["cluster"]["serviceAccount"]["key"], "service account key");
+            assert_eq!(config["cluster"]["etcd"]["ca"]["key"], "etcd key");
+            assert_eq!(config["cluster"]["secretboxEncryptionSecret"], "secretbox");
+            assert_eq!(config["cluster"]["controlPlane"]["endpoint"], "https://10.0.0.10:6443");
+            assert_eq!(config["cluster"]["network"]["cni"]["name"], "flannel");
+        }
+
+        #[test]
+        fn gives_workers_only_the_certificates() {
+            let config = rendered(&context(NodeRole::Worker, Some("1.5.2"), None));
+            assert_eq!(config["machine"]["type"], "worker");
+            assert_eq!(config["machine"]["ca"], json!({ "crt": "os certificate", "key": "" }));
+            assert_eq!(config["cluster"]["ca"], json!({ "crt": "k8s certificate", "key": "" }));
+            for section in ["aggregatorCA", "serviceAccount", "etcd", "secretboxEncryptionSecret"] {
+                assert!(config["cluster"].get(section).is_none(), "worker got {}", section);
+            }
+        }
+
+        #[test]
+        fn picks_images_from_the_versions() {
+            let config = rendered(&context(NodeRole::Worker, Some("1.5.2"), None));
+            assert_eq!(config["machine"]["install"]["image"], "ghcr.io/siderolabs/installer:v1.5.2");
+            assert_eq!(config["machine"]["kubelet"]["image"], "ghcr.io/siderolabs/kubelet:v1.27.3");
+
+            let config = rendered(&context(NodeRole::Worker, Some("v1.6.0"), None));
+            assert_eq!(config["machine"]["install"]["image"], "ghcr.io/siderolabs/installer:v1.6.0");
+        }
+
+        #[test]
+        fn installs_onto_the_reported_disk() {
+            assert_eq!(rendered(&context(NodeRole::Worker, Some("1.5.2"), None))["machine"]["install"]["disk"], DEFAULT_INSTALL_DISK);
+
+            let inventory = json!({ "disks": [
+                { "name": "/dev/sda", "sizeBytes": 4000000000000u64, "rotational": true },
+                { "name": "/dev/nvme0n1", "sizeBytes": 500000000000u64, "rotational": false }
+            ] });
+            assert_eq!(rendered(&context(NodeRole::Worker, Some("1.5.2"), Some(inventory)))["machine"]["install"]["disk"], "/dev/nvme0n1");
+        }
+
+        #[test]
+        fn needs_a_talos_cluster_and_a_supported_role() {
+            assert!(matches!(machine_config(&context(NodeRole::Worker, None, None), &secrets()), Err(Errors::ConflictError { .. })));
+            assert!(matches!(machine_config(&context(NodeRole::Etcd, Some("1.5.2"), None), &secrets()), Err(Errors::ConflictError { .. })));
+        }
+    }
+}
+
+pub mod requests {
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use getset::{Getters};
+
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+    #[serde(rename_all = "lowercase")]
+    pub enum PhoneHomeStatus {
+        Success,
+        Failure
+    }
+
+    /// Sent by the installed node at the end of provisioning.
+    #[derive(Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct PhoneHome {
+        status: PhoneHomeStatus,
+        message: Option<String>
+    }
+}
\ No newline at end of file
@@ -0,0 +1,205 @@
+use crate::{errors::{Errors, parse_mac_address}, v1::{Response, devices::models::device::{Device, ProvisioningState}, provision::models::{network::NetworkConfig, context::ProvisioningContext, kubeadm, talos, requests::{PhoneHome, PhoneHomeStatus}}, tokens::models::service}, settings::Settings, store::Store, templates::{TemplateContext, TemplateEngine, request_base_url}};
+
+use actix_web::{web::{Path, Data}, Result, HttpRequest, HttpResponse};
+use log::{info, warn, error};
+use paperclip::actix::{web::Json, api_v2_operation, get, post};
+use serde::Serialize;
+
+
+fn find_device(store: &Store, mac_address: &str) -> Result<Device, Errors> {
+    let mac = parse_mac_address(mac_address)?;
+    store.read(|tables| tables.devices.get(&mac.to_hex_string()).cloned()).ok_or(Errors::NotFoundError)
+}
+
+fn find_context(settings: &Settings, store: &Store, mac_address: &str) -> Result<ProvisioningContext, Errors> {
+    let device = find_device(store, mac_address)?;
+    Ok(store.read(|tables| ProvisioningContext::for_device(settings, tables, device)))
+}
+
+/// Renders every document into one YAML stream, separated by `---`, and serves it unless the
+/// template directory holds an override called `name` for this device.
+fn yaml_response<T: Serialize>(name: &str, documents: &[T], settings: &Settings, request: &HttpRequest, context: &ProvisioningContext) -> Result<HttpResponse, Errors> {
+    let rendered: Result<Vec<String>, serde_yaml::Error> = documents.iter().map(serde_yaml::to_string).collect();
+    let generated = rendered.map_err(|error| {
+        error!("Unable to render YAML: {}", error);
+        Errors::InternalServerError
+    })?.join("---\n");
+
+    let template_context = TemplateContext::new(settings, context, &request_base_url(request), true);
+    let body = TemplateEngine::new(settings.get_templates()).render_generated(name, template_context, generated)?;
+    Ok(HttpResponse::Ok().content_type("text/yaml").body(body))
+}
+
+#[api_v2_operation]
+#[get("/{mac_address}")]
+pub async fn get_provisioning_context(path: Path<String>, settings: Data<Settings>, store: Data<Store>) -> Result<Json<Response<ProvisioningContext>>, Errors> {
+    let context = find_context(&settings, &store, &path.into_inner())?;
+    Ok(Json(Response { data: context }))
+}
+
+#[api_v2_operation]
+#[get("/{mac_address}/network-config")]
+pub async fn get_network_config(path: Path<String>, request: HttpRequest, settings: Data<Settings>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let context = find_context(&settings, &store, &path.into_inner())?;
+    let config = NetworkConfig::for_device(context.get_device(), context.get_pool().as_ref());
+
+    info!("Rendering network config for {}", context.get_device().get_mac_address());
+    yaml_response("network-config", &[config], &settings, &request, &context)
+}
+
+#[api_v2_operation]
+#[get("/{mac_address}/kubeadm.yaml")]
+pub async fn get_kubeadm_config(path: Path<String>, request: HttpRequest, settings: Data<Settings>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let mac = parse_mac_address(&path.into_inner())?;
+
+    // Join tokens are issued while rendering, so this runs as a single transaction
+    let (context, tokens) = store.transaction(|tables| {
+        let device = tables.devices.get(&mac.to_hex_string()).cloned().ok_or(Errors::NotFoundError)?;
+        let context = ProvisioningContext::for_device(&settings, tables, device);
+        let tokens = service::tokens_for_node(tables, settings.get_tokens(), &context)?;
+        Ok::<_, Errors>((context, tokens))
+    })?;
+    // A join token is useless to the node until the API server knows it
+    if let Some((access, token)) = &tokens.publish {
+        if let Err(error) = service::publish(access, token).await {
+            store.transaction(|tables| {
+                tables.tokens.remove(token.get_id());
+                Ok::<_, Errors>(())
+            })?;
+            return Err(error)
+        }
+    }
+    if let Some((access, token)) = &tokens.withdraw {
+        if service::withdraw(access, token).await.is_err() {
+            warn!("Bootstrap token {} stays valid in cluster {} until it expires", token.get_id(), token.get_cluster());
+        }
+    }
+    let documents = kubeadm::documents(&context, &tokens)?;
+
+    info!("Rendering kubeadm config for {} ({:?})", context.get_device().get_hostname(), context.get_device().get_role());
+    yaml_response("kubeadm.yaml", &documents, &settings, &request, &context)
+}
+
+#[api_v2_operation]
+#[get("/{mac_address}/talos.yaml")]
+pub async fn get_talos_config(path: Path<String>, request: HttpRequest, settings: Data<Settings>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let context = find_context(&settings, &store, &path.into_inner())?;
+    let cluster = context.get_cluster().as_ref().map(|cluster| cluster.get_name().to_owned()).unwrap_or_default();
+    let secrets = store.read(|tables| tables.talos_secrets.get(&cluster).cloned())
+        .ok_or_else(|| Errors::ConflictError { message: format!("No Talos secrets stored for cluster {}", cluster) })?;
+    let config = talos::machine_config(&context, &secrets)?;
+
+    info!("Rendering Talos machine config for {} ({:?})", context.get_device().get_hostname(), context.get_device().get_role());
+    yaml_response("talos.yaml", &[config], &settings, &request, &context)
+}
+
+#[api_v2_operation]
+#[get("/{mac_address}/templates/{name}")]
+pub async fn get_template(path: Path<(String, String)>, request: HttpRequest, settings: Data<Settings>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let (mac_address, name) = path.into_inner();
+    let context = find_context(&settings, &store, &mac_address)?;
+    let template_context = TemplateContext::new(&settings, &context, &request_base_url(&request), true);
+    let (output, _) = TemplateEngine::new(settings.get_templates()).render(&name, &template_context)?;
+
+    Ok(HttpResponse::Ok().content_type("text/plain").body(output))
+}
+
+#[api_v2_operation]
+#[post("/{mac_address}/phone-home")]
+pub async fn phone_home(path: Path<String>, body: Json<PhoneHome>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let mac = parse_mac_address(&path.into_inner())?;
+    let report = body.into_inner();
+
+    let revoked = store.transaction(|tables| {
+        let owner = mac.to_hex_string();
+        let device = tables.devices.get_mut(&owner).ok_or(Errors::NotFoundError)?;
+        match report.get_status() {
+            PhoneHomeStatus::Success => {
+                device.set_state(ProvisioningState::Provisioned);
+                let revoked = service::revoke_device_tokens(tables, &owner);
+                Ok(revoked.into_iter().map(|token| {
+                    let access = service::delivery(tables, token.get_cluster()).ok().flatten();
+                    (token, access)
+                }).collect())
+            }
+            PhoneHomeStatus::Failure => {
+                device.set_state(ProvisioningState::Failed);
+                Ok::<_, Errors>(Vec::new())
+            }
+        }
+    })?;
+
+    match report.get_status() {
+        PhoneHomeStatus::Success => info!("Device {} finished provisioning, revoked {} bootstrap token(s)", mac, revoked.len()),
+        PhoneHomeStatus::Failure => warn!("Device {} failed provisioning: {}", mac, report.get_message().as_deref().unwrap_or("no details"))
+    }
+    // The device is done either way, tokens that can't be withdrawn stay valid until they expire
+    for (token, access) in revoked {
+        match access {
+            Some(access) => if service::withdraw(&access, &token).await.is_err() {
+                warn!("Bootstrap token {} stays valid in cluster {} until it expires", token.get_id(), token.get_cluster());
+            },
+            None => if store.read(|tables| tables.initialized_clusters.contains_key(token.get_cluster())) {
+                warn!("Bootstrap token {} stays valid in cluster {} until it expires, KMS has no access to its API", token.get_id(), token.get_cluster());
+            }
+        }
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::{self, TestRequest}, App};
+    use eui48::MacAddress;
+    use paperclip::actix::OpenApiExt;
+    use serde_json::json;
+
+    use crate::{server, settings::StorageSettings, v1::clusters::models::cluster::{Cluster, NodeRole}};
+    use super::*;
+
+    fn settings() -> Settings {
+        serde_json::from_value(json!({
+            "server": { "address": "127.0.0.1", "port": 8080 },
+            "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" },
+            "provisioning": { "require_authentication": false }
+        })).unwrap()
+    }
+
+    fn store() -> Store {
+        let cluster: Cluster = serde_json::from_value(json!({
+            "name": "prod",
+            "kubernetesVersion": "1.27.3",
+            "apiEndpoint": "10.0.0.10:6443",
+            "podCidr": "10.244.0.0/16",
+            "serviceCidr": "10.96.0.0/12",
+            "cni": "flannel",
+            "talosVersion": "1.5.2"
+        })).unwrap();
+        let mut device = Device::new("node-1", MacAddress::parse_str("00:00:5e:00:53:01").unwrap(), "10.0.0.11".parse().unwrap(), None);
+        device.set_cluster(Some("prod".to_owned()));
+        device.set_role(Some(NodeRole::ControlPlane));
+
+        let store = Store::open(&StorageSettings::default()).unwrap();
+        store.transaction(|tables| {
+            tables.clusters.insert("prod".to_owned(), cluster);
+            tables.devices.insert(device.get_mac_address().to_owned(), device);
+            Ok::<_, Errors>(())
+        }).unwrap();
+        store
+    }
+
+    #[actix_web::test]
+    async fn refuses_talos_configs_without_secrets() {
+        let app = test::init_service(App::new()
+            .wrap_api()
+            .app_data(Data::new(settings()))
+            .app_data(Data::new(store()))
+            .configure(server::routes)
+            .build()).await;
+
+        let response = test::call_service(&app, TestRequest::get().uri("/v1/provision/00:00:5e:00:53:01/talos.yaml").to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let problem: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(problem["detail"], "No Talos secrets stored for cluster prod");
+    }
+}
\ No newline at end of file
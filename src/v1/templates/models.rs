@@ -0,0 +1,33 @@
+pub mod requests {
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use validator::{Validate};
+    use getset::{Getters};
+
+    use crate::{templates::TEMPLATE_NAME_RE, v1::devices::models::device::MAC_ADDRESS_RE};
+
+    #[derive(Serialize, Deserialize, Validate, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct RenderTemplate {
+        #[validate(regex = "MAC_ADDRESS_RE")]
+        mac_address: String,
+        // File name of the template, e.g. kickstart.ks or boot.ipxe
+        #[validate(regex = "TEMPLATE_NAME_RE")]
+        template: String
+    }
+}
+
+pub mod responses {
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+
+    #[derive(Serialize, Deserialize, Apiv2Schema)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RenderedTemplate {
+        pub template: String,
+        // Path of the file the template was loaded from, or `builtin`
+        pub source: String,
+        pub output: String
+    }
+}
@@ -0,0 +1,32 @@
+use crate::{auth::{Authenticated, Role, policy::Scope}, errors::{Errors, parse_validation_errors, parse_mac_address}, v1::{Response, templates::models::{requests::RenderTemplate, responses::RenderedTemplate}, provision::models::context::ProvisioningContext}, settings::Settings, store::Store, templates::{TemplateContext, TemplateEngine, request_base_url}};
+
+use actix_web::{web::Data, Result, HttpRequest};
+use paperclip::actix::{web::Json, api_v2_operation, post};
+use validator::Validate;
+
+
+/// Renders a template for a registered device without changing anything, so site templates can be
+/// checked before a machine boots from them.
+#[api_v2_operation]
+#[post("/render")]
+pub async fn render_template(auth: Authenticated, body: Json<RenderTemplate>, request: HttpRequest, settings: Data<Settings>, store: Data<Store>) -> Result<Json<Response<RenderedTemplate>>, Errors> {
+    let preview = body.into_inner();
+
+    if let Err(e) = preview.validate() {
+        return Err(Errors::ValidationError { field_errors: parse_validation_errors(e) })
+    }
+
+    let mac = parse_mac_address(preview.get_mac_address())?;
+    let (context, admin) = store.read(|tables| {
+        let device = tables.devices.get(&mac.to_hex_string()).cloned().ok_or(Errors::NotFoundError)?;
+        auth.0.authorize(Role::Operator, "render templates", Scope::device(&device))?;
+        let admin = auth.0.can(Role::Admin, Scope::device(&device));
+        Ok::<_, Errors>((ProvisioningContext::for_device(&settings, tables, device), admin))
+    })?;
+    // Signed URLs and the certificate key are join credentials, operators preview without them
+    let context = if admin { context } else { context.without_secrets() };
+    let template_context = TemplateContext::new(&settings, &context, &request_base_url(&request), admin);
+    let (output, source) = TemplateEngine::new(settings.get_templates()).render(preview.get_template(), &template_context)?;
+
+    Ok(Json(Response { data: RenderedTemplate { template: preview.get_template().to_owned(), source: source.to_string(), output } }))
+}
@@ -0,0 +1,387 @@
+pub mod token {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use paperclip::actix::Apiv2Schema;
+    use rand::{Rng, thread_rng};
+    use serde_derive::{Serialize, Deserialize};
+    use getset::{Getters};
+
+    const TOKEN_CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+    pub fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+    }
+
+    fn random_string(length: usize) -> String {
+        let mut rng = thread_rng();
+        (0..length).map(|_| TOKEN_CHARSET[rng.gen_range(0..TOKEN_CHARSET.len())] as char).collect()
+    }
+
+    /// A kubeadm bootstrap token (`[a-z0-9]{6}.[a-z0-9]{16}`). Tokens bound to a device are handed out
+    /// with that device's kubeadm configuration and revoked once the device phones home. The API
+    /// server only knows tokens that were created in it, see [`super::service::delivery`].
+    #[derive(Clone, Serialize, Deserialize, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct BootstrapToken {
+        id: String,
+        secret: String,
+        cluster: String,
+        device: Option<String>,
+        description: Option<String>,
+        created_at: u64,
+        expires_at: u64,
+        // How often KMS handed the token out in a kubeadm configuration
+        uses: u32
+    }
+
+    impl BootstrapToken {
+        pub fn generate(cluster: &str, device: Option<String>, description: Option<String>, ttl_seconds: u64) -> BootstrapToken {
+            let created_at = now();
+            BootstrapToken {
+                id: random_string(6),
+                secret: random_string(16),
+                cluster: cluster.to_owned(),
+                device,
+                description,
+                created_at,
+                expires_at: created_at + ttl_seconds,
+                uses: 0
+            }
+        }
+
+        pub fn token(&self) -> String {
+            format!("{}.{}", self.id, self.secret)
+        }
+
+        pub fn is_expired(&self, now: u64) -> bool {
+            now >= self.expires_at
+        }
+
+        pub fn remaining_seconds(&self, now: u64) -> u64 {
+            self.expires_at.saturating_sub(now)
+        }
+
+        pub fn record_use(&mut self) {
+            self.uses += 1;
+        }
+
+        /// Issues a replacement with a fresh ID and secret, valid for as long as the original was
+        /// when it was issued.
+        pub fn rotate(&self) -> BootstrapToken {
+            BootstrapToken::generate(&self.cluster, self.device.clone(), self.description.clone(), self.expires_at - self.created_at)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use regex::Regex;
+
+        use super::*;
+
+        #[test]
+        fn generates_kubeadm_tokens() {
+            let token = BootstrapToken::generate("prod", None, None, 3600);
+            assert!(Regex::new("^[a-z0-9]{6}\\.[a-z0-9]{16}$").unwrap().is_match(&token.token()));
+            assert_eq!(token.token(), format!("{}.{}", token.get_id(), token.get_secret()));
+            assert_eq!(token.get_expires_at() - token.get_created_at(), 3600);
+        }
+
+        #[test]
+        fn expires_at_its_expiry() {
+            let token = BootstrapToken::generate("prod", None, None, 60);
+            let expires_at = *token.get_expires_at();
+            assert!(!token.is_expired(expires_at - 1));
+            assert!(token.is_expired(expires_at));
+            assert_eq!(token.remaining_seconds(expires_at - 10), 10);
+            assert_eq!(token.remaining_seconds(expires_at + 10), 0);
+        }
+
+        #[test]
+        fn rotates_into_a_new_token_with_the_same_ttl() {
+            let token = BootstrapToken::generate("prod", Some("00:11:22:33:44:55".to_owned()), Some("node".to_owned()), 7200);
+            let rotated = token.rotate();
+            assert_ne!(rotated.token(), token.token());
+            assert_eq!(rotated.get_expires_at() - rotated.get_created_at(), 7200);
+            assert_eq!(rotated.get_cluster(), token.get_cluster());
+            assert_eq!(rotated.get_device(), token.get_device());
+            assert_eq!(rotated.get_description(), token.get_description());
+        }
+    }
+}
+
+pub mod requests {
+    use paperclip::actix::Apiv2Schema;
+    use serde_derive::{Serialize, Deserialize};
+    use validator::{Validate};
+    use getset::{Getters};
+
+    #[derive(Serialize, Deserialize, Validate, Getters, Apiv2Schema)]
+    #[get = "pub with_prefix"]
+    #[serde(rename_all = "camelCase")]
+    pub struct CreateToken {
+        #[validate(length(min = 1))]
+        cluster: String,
+        // Falls back to the configured token TTL
+        #[validate(range(min = 60))]
+        ttl_seconds: Option<u64>,
+        description: Option<String>
+    }
+}
+
+pub mod service {
+    use log::{error, info};
+
+    use crate::{clients::kubernetes::KubernetesClient, errors::Errors, settings::TokenSettings, store::Tables, v1::{clusters::models::{access::KubernetesAccess, cluster::NodeRole}, provision::models::{context::ProvisioningContext, kubeadm}}};
+    use super::token::{BootstrapToken, now};
+
+    pub struct NodeTokens {
+        pub join: Option<BootstrapToken>,
+        pub cluster: Vec<BootstrapToken>,
+        // A join token issued for a cluster that is running already, it has to be published
+        pub publish: Option<(KubernetesAccess, BootstrapToken)>,
+        // The used up join token it replaces, to be deleted from the running cluster
+        pub withdraw: Option<(KubernetesAccess, BootstrapToken)>
+    }
+
+    pub fn purge_expired(tables: &mut Tables) {
+        let now = now();
+        tables.tokens.retain(|_, token| !token.is_expired(now));
+    }
+
+    /// Generates tokens until one has an ID that isn't taken, kubeadm tells tokens apart by ID alone.
+    pub fn unique(tables: &Tables, generate: impl Fn() -> BootstrapToken) -> BootstrapToken {
+        loop {
+            let token = generate();
+            if !tables.tokens.contains_key(token.get_id()) {
+                return token
+            }
+        }
+    }
+
+    /// Where new or revoked tokens of `cluster` have to go besides the store. Until the init node
+    /// fetched its configuration, its `InitConfiguration` creates them; afterwards only the cluster
+    /// API can, and without access to it changing the cluster's tokens is refused.
+    pub fn delivery(tables: &Tables, cluster: &str) -> Result<Option<KubernetesAccess>, Errors> {
+        if !tables.initialized_clusters.contains_key(cluster) {
+            return Ok(None)
+        }
+        match tables.kubernetes_access.get(cluster) {
+            Some(access) => Ok(Some(access.clone())),
+            None => Err(Errors::ConflictError {
+                message: format!("Cluster {} is initialized and has no Kubernetes API access, its bootstrap tokens can only be changed with kubeadm", cluster)
+            })
+        }
+    }
+
+    fn client(access: &KubernetesAccess) -> Result<KubernetesClient, Errors> {
+        KubernetesClient::from_access(access).map_err(|error| {
+            error!("Invalid Kubernetes API access: {}", error);
+            Errors::InternalServerError
+        })
+    }
+
+    /// Creates the token's Secret in the cluster, which is what makes the API server accept it.
+    pub async fn publish(access: &KubernetesAccess, token: &BootstrapToken) -> Result<(), Errors> {
+        client(access)?.apply_bootstrap_token(token).await.map_err(|error| {
+            error!("Unable to create bootstrap token {} in cluster {}: {}", token.get_id(), token.get_cluster(), error);
+            Errors::InternalServerError
+        })
+    }
+
+    pub async fn withdraw(access: &KubernetesAccess, token: &BootstrapToken) -> Result<(), Errors> {
+        client(access)?.delete_bootstrap_token(token.get_id()).await.map_err(|error| {
+            error!("Unable to delete bootstrap token {} from cluster {}: {}", token.get_id(), token.get_cluster(), error);
+            Errors::InternalServerError
+        })
+    }
+
+    fn device_token(tables: &Tables, cluster: &str, mac_address: &str) -> Option<BootstrapToken> {
+        let now = now();
+        tables.tokens.values()
+            .find(|token| token.get_device().as_deref() == Some(mac_address) && token.get_cluster() == cluster && !token.is_expired(now))
+            .cloned()
+    }
+
+    fn issue_device_token(tables: &mut Tables, settings: &TokenSettings, cluster: &str, mac_address: &str) -> BootstrapToken {
+        let token = unique(tables, || BootstrapToken::generate(cluster, Some(mac_address.to_owned()), Some(format!("Join token for {}", mac_address)), *settings.get_ttl_seconds()));
+        info!("Issued bootstrap token {} for {} in cluster {}", token.get_id(), mac_address, cluster);
+        tables.tokens.insert(token.get_id().to_owned(), token.clone());
+        token
+    }
+
+    /// Collects the tokens a node's kubeadm configuration embeds. Joining nodes get their own
+    /// token, replaced by a fresh one once it was handed out `max_uses` times. The init node pre-issues tokens for every other node of the cluster and
+    /// creates all of them through `InitConfiguration.bootstrapTokens`. Tokens of nodes that
+    /// join later are published through the cluster API, see [`delivery`].
+    pub fn tokens_for_node(tables: &mut Tables, settings: &TokenSettings, context: &ProvisioningContext) -> Result<NodeTokens, Errors> {
+        purge_expired(tables);
+
+        let device = context.get_device();
+        let (cluster, role) = match (context.get_cluster(), device.get_role()) {
+            (Some(cluster), Some(role)) if *role != NodeRole::Etcd => (cluster, role),
+            _ => return Ok(NodeTokens { join: None, cluster: Vec::new(), publish: None, withdraw: None })
+        };
+
+        if *role == NodeRole::ControlPlane && kubeadm::is_init_node(context, cluster, device) {
+            for node in context.get_nodes().iter().filter(|node| node.get_mac_address() != device.get_mac_address() && node.get_role() != &Some(NodeRole::Etcd)) {
+                if device_token(tables, cluster.get_name(), node.get_mac_address()).is_none() {
+                    issue_device_token(tables, settings, cluster.get_name(), node.get_mac_address());
+                }
+            }
+            let now = now();
+            let cluster_tokens = tables.tokens.values()
+                .filter(|token| token.get_cluster() == cluster.get_name() && !token.is_expired(now))
+                .cloned()
+                .collect();
+            tables.initialized_clusters.insert(cluster.get_name().to_owned(), now);
+            return Ok(NodeTokens { join: None, cluster: cluster_tokens, publish: None, withdraw: None })
+        }
+
+        let (mut token, publish, withdraw) = match device_token(tables, cluster.get_name(), device.get_mac_address()) {
+            Some(token) if token.get_uses() < settings.get_max_uses() => (token, None, None),
+            used_up => {
+                let access = delivery(tables, cluster.get_name())?;
+                let withdraw = used_up.and_then(|token| {
+                    info!("Bootstrap token {} of {} was handed out {} time(s), replacing it", token.get_id(), device.get_mac_address(), token.get_uses());
+                    tables.tokens.remove(token.get_id());
+                    access.clone().map(|access| (access, token))
+                });
+                let token = issue_device_token(tables, settings, cluster.get_name(), device.get_mac_address());
+                let publish = access.map(|access| (access, token.clone()));
+                (token, publish, withdraw)
+            }
+        };
+        token.record_use();
+        tables.tokens.insert(token.get_id().to_owned(), token.clone());
+        Ok(NodeTokens { join: Some(token), cluster: Vec::new(), publish, withdraw })
+    }
+
+    /// Removes the tokens bound to `mac_address` from the store and returns them.
+    pub fn revoke_device_tokens(tables: &mut Tables, mac_address: &str) -> Vec<BootstrapToken> {
+        let (revoked, kept) = std::mem::take(&mut tables.tokens).into_iter()
+            .partition(|(_, token)| token.get_device().as_deref() == Some(mac_address));
+        tables.tokens = kept;
+        revoked.into_values().collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::cell::Cell;
+
+        use eui48::MacAddress;
+        use serde_json::json;
+
+        use crate::{settings::Settings, v1::{clusters::models::cluster::Cluster, devices::models::device::Device}};
+        use super::*;
+
+        const WORKER: &str = "00:00:5e:00:53:02";
+
+        fn node(hostname: &str, mac_address: &str, role: NodeRole) -> Device {
+            let mut device = Device::new(hostname, MacAddress::parse_str(mac_address).unwrap(), "10.0.0.11".parse().unwrap(), None);
+            device.set_cluster(Some("prod".to_owned()));
+            device.set_role(Some(role));
+            device
+        }
+
+        fn tables() -> Tables {
+            let cluster: Cluster = serde_json::from_value(json!({
+                "name": "prod",
+                "kubernetesVersion": "1.27.3",
+                "apiEndpoint": "10.0.0.10:6443",
+                "podCidr": "10.244.0.0/16",
+                "serviceCidr": "10.96.0.0/12",
+                "cni": "calico",
+                "unsafeSkipCaVerification": true
+            })).unwrap();
+            let mut tables = Tables::default();
+            tables.clusters.insert("prod".to_owned(), cluster);
+            for node in [node("cp-1", "00:00:5e:00:53:01", NodeRole::ControlPlane), node("worker-1", WORKER, NodeRole::Worker)] {
+                tables.devices.insert(node.get_mac_address().to_owned(), node);
+            }
+            tables
+        }
+
+        fn worker(tables: &Tables) -> ProvisioningContext {
+            let settings: Settings = serde_json::from_value(json!({
+                "server": { "address": "127.0.0.1", "port": 8080 },
+                "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" }
+            })).unwrap();
+            ProvisioningContext::for_device(&settings, tables, tables.devices[WORKER].clone())
+        }
+
+        fn max_uses(max_uses: u32) -> TokenSettings {
+            serde_json::from_value(json!({ "max_uses": max_uses })).unwrap()
+        }
+
+        fn join_token(tables: &mut Tables, settings: &TokenSettings) -> NodeTokens {
+            let context = worker(tables);
+            match tokens_for_node(tables, settings, &context) {
+                Ok(tokens) => tokens,
+                Err(error) => panic!("no tokens: {}", error)
+            }
+        }
+
+        #[test]
+        fn replaces_join_tokens_once_used_up() {
+            let mut tables = tables();
+            let settings = TokenSettings::default();
+            let first = join_token(&mut tables, &settings).join.unwrap();
+            assert_eq!(*first.get_uses(), 1);
+
+            let second = join_token(&mut tables, &settings).join.unwrap();
+            assert_ne!(second.token(), first.token());
+            assert_eq!(*second.get_uses(), 1);
+            assert!(!tables.tokens.contains_key(first.get_id()));
+            assert_eq!(tables.tokens.len(), 1);
+        }
+
+        #[test]
+        fn hands_out_join_tokens_up_to_max_uses() {
+            let mut tables = tables();
+            let settings = max_uses(2);
+            let first = join_token(&mut tables, &settings).join.unwrap();
+            let second = join_token(&mut tables, &settings).join.unwrap();
+            assert_eq!(second.token(), first.token());
+            assert_eq!(*second.get_uses(), 2);
+
+            let third = join_token(&mut tables, &settings).join.unwrap();
+            assert_ne!(third.token(), first.token());
+        }
+
+        #[test]
+        fn withdraws_used_up_tokens_from_running_clusters() {
+            let mut tables = tables();
+            let settings = TokenSettings::default();
+            let first = join_token(&mut tables, &settings).join.unwrap();
+
+            tables.initialized_clusters.insert("prod".to_owned(), now());
+            let context = worker(&tables);
+            assert!(matches!(tokens_for_node(&mut tables, &settings, &context), Err(Errors::ConflictError { .. })));
+            assert!(tables.tokens.contains_key(first.get_id()));
+
+            let access: KubernetesAccess = serde_json::from_value(json!({ "server": "https://10.0.0.10:6443", "caCertificate": "ca", "token": "token" })).unwrap();
+            tables.kubernetes_access.insert("prod".to_owned(), access);
+            let tokens = join_token(&mut tables, &settings);
+            let (_, withdrawn) = tokens.withdraw.unwrap();
+            let (_, published) = tokens.publish.unwrap();
+            assert_eq!(withdrawn.get_id(), first.get_id());
+            assert_eq!(published.get_id(), tokens.join.unwrap().get_id());
+        }
+
+        #[test]
+        fn issues_only_unused_ids() {
+            let mut tables = Tables::default();
+            let taken = BootstrapToken::generate("prod", None, None, 60);
+            tables.tokens.insert(taken.get_id().to_owned(), taken.clone());
+
+            let calls = Cell::new(0);
+            let token = unique(&tables, || {
+                calls.set(calls.get() + 1);
+                if calls.get() == 1 { taken.clone() } else { BootstrapToken::generate("prod", None, None, 60) }
+            });
+            assert_eq!(calls.get(), 2);
+            assert_ne!(token.get_id(), taken.get_id());
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,167 @@
+use crate::{auth::{Authenticated, Role, policy::Scope}, errors::{Errors, parse_validation_errors, validation_error}, v1::{Response, tokens::models::{token::BootstrapToken, requests::CreateToken, service}}, settings::Settings, store::Store};
+
+use actix_web::{web::{Path, Data}, Result, HttpResponse};
+use log::info;
+use paperclip::actix::{web::Json, api_v2_operation, post, delete};
+use validator::Validate;
+
+
+// Mounted in `server::routes`, with and without the trailing slash the route macros would force
+#[api_v2_operation]
+pub async fn list_tokens(auth: Authenticated, store: Data<Store>) -> Result<Json<Response<Vec<BootstrapToken>>>, Errors> {
+    let tokens = store.transaction(|tables| {
+        service::purge_expired(tables);
+        Ok::<_, Errors>(tables.tokens.values()
+            .filter(|token| auth.0.can(Role::Admin, Scope::cluster(token.get_cluster())))
+            .cloned()
+            .collect())
+    })?;
+    Ok(Json(Response { data: tokens }))
+}
+
+#[api_v2_operation]
+pub async fn create_token(auth: Authenticated, body: Json<CreateToken>, settings: Data<Settings>, store: Data<Store>) -> Result<Json<Response<BootstrapToken>>, Errors> {
+    let request = body.into_inner();
+
+    if let Err(e) = request.validate() {
+        return Err(Errors::ValidationError { field_errors: parse_validation_errors(e) })
+    }
+    auth.0.authorize(Role::Admin, "issue bootstrap tokens", Scope::cluster(request.get_cluster()))?;
+
+    let ttl_seconds = request.get_ttl_seconds().unwrap_or(*settings.get_tokens().get_ttl_seconds());
+    let (token, access) = store.read(|tables| {
+        if !tables.clusters.contains_key(request.get_cluster()) {
+            return Err(validation_error("cluster", "unknown"))
+        }
+        let access = service::delivery(tables, request.get_cluster())?;
+        let token = service::unique(tables, || BootstrapToken::generate(request.get_cluster(), None, request.get_description().to_owned(), ttl_seconds));
+        Ok((token, access))
+    })?;
+
+    // The cluster has to accept the token before it's handed out
+    if let Some(access) = &access {
+        service::publish(access, &token).await?;
+    }
+    store.transaction(|tables| {
+        if tables.tokens.contains_key(token.get_id()) {
+            return Err(Errors::ConflictError { message: format!("Bootstrap token {} was issued concurrently", token.get_id()) })
+        }
+        tables.tokens.insert(token.get_id().to_owned(), token.clone());
+        Ok(())
+    })?;
+
+    info!("Issued bootstrap token {} for cluster {}", token.get_id(), token.get_cluster());
+    Ok(Json(Response { data: token }))
+}
+
+#[api_v2_operation]
+#[post("/{id}/rotate")]
+pub async fn rotate_token(auth: Authenticated, path: Path<String>, store: Data<Store>) -> Result<Json<Response<BootstrapToken>>, Errors> {
+    let id = path.into_inner();
+
+    let (previous, token, access) = store.read(|tables| {
+        let previous = tables.tokens.get(&id).cloned().ok_or(Errors::NotFoundError)?;
+        auth.0.authorize(Role::Admin, "rotate bootstrap tokens", Scope::cluster(previous.get_cluster()))?;
+        let access = service::delivery(tables, previous.get_cluster())?;
+        let token = service::unique(tables, || previous.rotate());
+        Ok::<_, Errors>((previous, token, access))
+    })?;
+
+    if let Some(access) = &access {
+        service::publish(access, &token).await?;
+        service::withdraw(access, &previous).await?;
+    }
+    store.transaction(|tables| {
+        tables.tokens.remove(&id);
+        tables.tokens.insert(token.get_id().to_owned(), token.clone());
+        Ok::<_, Errors>(())
+    })?;
+
+    info!("Rotated bootstrap token {} into {}", id, token.get_id());
+    Ok(Json(Response { data: token }))
+}
+
+#[api_v2_operation]
+#[delete("/{id}")]
+pub async fn revoke_token(auth: Authenticated, path: Path<String>, store: Data<Store>) -> Result<HttpResponse, Errors> {
+    let id = path.into_inner();
+
+    let (token, access) = store.read(|tables| {
+        let token = tables.tokens.get(&id).cloned().ok_or(Errors::NotFoundError)?;
+        auth.0.authorize(Role::Admin, "revoke bootstrap tokens", Scope::cluster(token.get_cluster()))?;
+        let access = service::delivery(tables, token.get_cluster())?;
+        Ok::<_, Errors>((token, access))
+    })?;
+
+    if let Some(access) = &access {
+        service::withdraw(access, &token).await?;
+    }
+    store.transaction(|tables| tables.tokens.remove(&id).map(|_| ()).ok_or(Errors::NotFoundError))?;
+
+    info!("Revoked bootstrap token {}", id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::{self, TestRequest}, App};
+    use paperclip::actix::OpenApiExt;
+    use serde_json::{json, Value};
+
+    use crate::{auth, server, settings::StorageSettings, v1::clusters::models::cluster::Cluster};
+    use super::*;
+
+    fn settings() -> Settings {
+        serde_json::from_value(json!({
+            "server": { "address": "127.0.0.1", "port": 8080 },
+            "unifi": { "base_url": "https://unifi.local", "username": "kms", "password": "secret" },
+            "auth": { "enabled": false }
+        })).unwrap()
+    }
+
+    fn store() -> Store {
+        let cluster: Cluster = serde_json::from_value(json!({
+            "name": "prod",
+            "kubernetesVersion": "1.27.3",
+            "apiEndpoint": "10.0.0.10:6443",
+            "podCidr": "10.244.0.0/16",
+            "serviceCidr": "10.96.0.0/12",
+            "cni": "calico"
+        })).unwrap();
+        let store = Store::open(&StorageSettings::default()).unwrap();
+        store.transaction(|tables| {
+            tables.clusters.insert("prod".to_owned(), cluster);
+            Ok::<_, Errors>(())
+        }).unwrap();
+        store
+    }
+
+    #[actix_web::test]
+    async fn issues_and_revokes_tokens() {
+        let app = test::init_service(App::new()
+            .wrap(auth::Authentication)
+            .wrap_api()
+            .app_data(Data::new(settings()))
+            .app_data(Data::new(store()))
+            .configure(server::routes)
+            .build()).await;
+
+        let response = test::call_service(&app, TestRequest::post().uri("/v1/tokens").set_json(json!({ "cluster": "staging" })).to_request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body: Value = test::call_and_read_body_json(&app, TestRequest::post().uri("/v1/tokens").set_json(json!({ "cluster": "prod", "ttlSeconds": 600 })).to_request()).await;
+        let id = body["data"]["id"].as_str().unwrap().to_owned();
+        assert_eq!(body["data"]["cluster"], "prod");
+
+        // The OpenAPI spec lists the collection with a trailing slash
+        for uri in ["/v1/tokens", "/v1/tokens/"] {
+            let body: Value = test::call_and_read_body_json(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(body["data"][0]["id"], id.as_str());
+        }
+
+        let response = test::call_service(&app, TestRequest::delete().uri(&format!("/v1/tokens/{}", id)).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let body: Value = test::call_and_read_body_json(&app, TestRequest::get().uri("/v1/tokens").to_request()).await;
+        assert!(body["data"].as_array().unwrap().is_empty());
+    }
+}
\ No newline at end of file